@@ -259,6 +259,15 @@ fn criterion_benchmark(c: &mut Criterion) {
                 .use_mmap(false)
                 .clone(),
         ),
+        (
+            "no_mmap,compress=snappy,flush=8192,cache=unbounded,slot_cache",
+            make_write_opts(Compression::Snappy, 8192),
+            ReadOptions::default()
+                .cache(Some(ReadCache::Unbounded))
+                .use_mmap(false)
+                .slot_cache(true)
+                .clone(),
+        ),
         // ("mmap,compress=zlib,flush=65536,cache=32", make_write_opts(Compression::Snappy, 8192), ReadOptions{cache: Some(ReadCache::Blocks(32)), use_mmap: true}),
         // ("no_mmap,compress=zlib,flush=65536,cache=32", make_write_opts(Compression::Snappy, 8192), ReadOptions{cache: Some(ReadCache::Blocks(32)), use_mmap: false}),
         // ("no_mmap,compress=zlib,flush=65536,cache=unbounded", make_write_opts(Compression::Snappy, 8192), ReadOptions{cache: Some(ReadCache::Blocks(32)), use_mmap: false}),
@@ -288,7 +297,7 @@ fn criterion_benchmark(c: &mut Criterion) {
                             let key = key as &[u8];
                             let value = reader.get(key).unwrap();
                             if *is_present {
-                                assert_eq!(value, Some(key));
+                                assert_eq!(value.as_deref(), Some(key));
                             } else {
                                 assert_eq!(value, None);
                             }
@@ -354,7 +363,7 @@ fn criterion_benchmark(c: &mut Criterion) {
                                 let key = key as &[u8];
                                 let value = reader.get(key).unwrap();
                                 if *is_present {
-                                    assert_eq!(value, Some(key));
+                                    assert_eq!(value.as_deref(), Some(key));
                                 } else {
                                     assert_eq!(value, None);
                                 }
@@ -394,6 +403,107 @@ fn criterion_benchmark(c: &mut Criterion) {
     group.finish();
 }
 
+fn filter_types(c: &mut Criterion) {
+    let filename = "/tmp/sstable-filters";
+    let size = 100_000;
+    // Most lookups miss, so that it's mostly the filters being measured.
+    let state = TestState::new(32, size, 0.8);
+
+    let mut group = c.benchmark_group("method=get, filter types, 100 000 items");
+    for (name, filter_type, bloom_per_chunk) in [
+        ("bloom", FilterType::Bloom, false),
+        ("blocked-bloom", FilterType::BlockedBloom, false),
+        ("xor", FilterType::Xor, false),
+        ("bloom,per-chunk", FilterType::Bloom, true),
+        ("blocked-bloom,per-chunk", FilterType::BlockedBloom, true),
+        ("xor,per-chunk", FilterType::Xor, true),
+    ]
+    .iter()
+    {
+        state
+            .write_sstable(
+                filename,
+                WriteOptions::default()
+                    .filter_type(*filter_type)
+                    .bloom_per_chunk(*bloom_per_chunk),
+            )
+            .unwrap();
+
+        group.bench_function(*name, |b| {
+            b.iter_batched(
+                || MmapUncompressedSSTableReader::new(filename).unwrap(),
+                |reader| {
+                    for kv in state.get_shuffled_input() {
+                        let KV { key, is_present } = &kv;
+                        let key = key as &[u8];
+                        let value = reader.get(key).unwrap();
+                        if *is_present {
+                            assert_eq!(value.as_deref(), Some(key));
+                        } else {
+                            assert_eq!(value, None);
+                        }
+                    }
+                },
+                BatchSize::LargeInput,
+            );
+        });
+    }
+    group.finish();
+}
+
+fn cache_policies(c: &mut Criterion) {
+    let filename = "/tmp/sstable-cache-policies";
+    let size = 100_000;
+    let state = TestState::new(32, size, 0.);
+    state
+        .write_sstable(
+            filename,
+            WriteOptions::default().compression(Compression::Snappy),
+        )
+        .unwrap();
+    // The hot keys fit into the cache, the whole table doesn't.
+    let hot: Vec<&[u8]> = state.get_shuffled_input_ref()[..500]
+        .iter()
+        .map(|kv| &kv.key as &[u8])
+        .collect();
+
+    let mut group = c.benchmark_group("method=get and scans, cache policies, 100 000 items");
+    for (name, policy, fill_cache) in [
+        ("lru", CachePolicy::Lru, true),
+        ("tiny-lfu", CachePolicy::TinyLfu, true),
+        ("lru,scans-no-fill", CachePolicy::Lru, false),
+    ]
+    .iter()
+    {
+        let cache = BlockCache::with_policy(ReadCache::Bytes(4 * 1024 * 1024), *policy);
+        let mut opts = ReadOptions::default();
+        opts.block_cache(Some(cache.clone()));
+        let mut reader = SSTableReader::new_with_options(filename, &opts).unwrap();
+        let mut get_options = GetOptions::new();
+        get_options.fill_cache(*fill_cache);
+
+        group.bench_function(*name, |b| {
+            b.iter(|| {
+                for _ in 0..10 {
+                    for key in hot.iter() {
+                        assert_eq!(reader.get(key).unwrap(), Some(*key));
+                    }
+                }
+                let scan = reader
+                    .range_with_options(
+                        std::ops::Bound::Unbounded,
+                        std::ops::Bound::Unbounded,
+                        Some(get_options),
+                    )
+                    .unwrap();
+                assert_eq!(scan.count(), size);
+            });
+        });
+        eprintln!("{}: hit rate {:.3}", name, cache.stats().hit_rate());
+    }
+    group.finish();
+}
+
 fn default_criterion() -> Criterion {
     Criterion::default().sample_size(10)
 }
@@ -401,7 +511,7 @@ fn default_criterion() -> Criterion {
 criterion_group! {
     name = sstable;
     config = default_criterion();
-    targets = criterion_benchmark, compare_with_others
+    targets = criterion_benchmark, compare_with_others, filter_types, cache_policies
 }
 
 criterion_main!(sstable);
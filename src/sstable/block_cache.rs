@@ -0,0 +1,249 @@
+//! A cache of chunks shared between readers.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+
+use super::options::{CachePolicy, ReadCache};
+use super::sized_cache::{CacheSize, SizedCache};
+use super::Result;
+
+/// A chunk is identified by its table and its offset in it.
+type BlockKey = (u64, u64);
+
+/// A cached chunk, empty while the reader that missed it computes it.
+type Block = Arc<OnceCell<Bytes>>;
+
+impl CacheSize for Block {
+    fn cache_size(&self) -> usize {
+        self.get().map(|bytes| bytes.len()).unwrap_or(0)
+    }
+}
+
+struct Inner {
+    shards: Vec<Mutex<SizedCache<BlockKey, Block>>>,
+    next_table_id: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// How many chunks were found in a BlockCache, and how many were not.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct BlockCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl BlockCacheStats {
+    /// The share of the lookups that found the chunk, 0 if there were none.
+    pub fn hit_rate(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            return 0.0;
+        }
+        // a ratio does not need all the precision.
+        #[allow(clippy::cast_precision_loss)]
+        let hit_rate = self.hits as f64 / lookups as f64;
+        hit_rate
+    }
+}
+
+/// A cache of chunks that can be shared by any number of readers, so that the memory of
+/// all of them is bounded together.
+///
+/// Pass it to the readers with `ReadOptions::block_cache`. The chunks are cached once
+/// uncompressed and decrypted, and the readers don't cache them on their own.
+/// Cloning the cache makes another handle to the same cache.
+///
+/// Example:
+/// ```
+/// use sstb::sstable::{BlockCache, ReadCache, ReadOptions};
+///
+/// let cache = BlockCache::new(ReadCache::Bytes(64 * 1024 * 1024));
+/// let mut options = ReadOptions::default();
+/// options.block_cache(Some(cache));
+/// ```
+#[derive(Clone)]
+pub struct BlockCache {
+    inner: Arc<Inner>,
+}
+
+impl BlockCache {
+    /// Make an LRU cache bounded by "cache". The bound is split between a shard per CPU.
+    pub fn new(cache: ReadCache) -> Self {
+        Self::with_policy(cache, CachePolicy::Lru)
+    }
+
+    /// Make a cache bounded by "cache", that evicts chunks with "policy".
+    pub fn with_policy(cache: ReadCache, policy: CachePolicy) -> Self {
+        Self::with_shards(cache, policy, num_cpus::get())
+    }
+
+    /// Make a cache bounded by "cache", split between "shards" shards.
+    ///
+    /// A budget of fewer blocks than shards makes a shard per block, and the blocks left
+    /// over by an even split go to the first shards, so the cache holds no more blocks
+    /// than it's given.
+    pub fn with_shards(cache: ReadCache, policy: CachePolicy, shards: usize) -> Self {
+        let shards = match cache {
+            ReadCache::Blocks(blocks) => shards.min(blocks),
+            _ => shards,
+        }
+        .max(1);
+        let shard_cache = |shard: usize| match cache {
+            ReadCache::Blocks(blocks) => {
+                let extra = usize::from(shard < blocks % shards);
+                ReadCache::Blocks(blocks / shards + extra)
+            }
+            ReadCache::Bytes(bytes) => ReadCache::Bytes(bytes / shards),
+            ReadCache::Unbounded => ReadCache::Unbounded,
+        };
+        Self {
+            inner: Arc::new(Inner {
+                shards: (0..shards)
+                    .map(|shard| Mutex::new(SizedCache::new(shard_cache(shard), policy)))
+                    .collect(),
+                next_table_id: AtomicU64::new(0),
+                hits: AtomicU64::new(0),
+                misses: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Get an id for a newly opened table, that its chunks are cached under.
+    pub fn new_table_id(&self) -> u64 {
+        self.inner.next_table_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// How many chunks were found in the cache, and how many were not, so far.
+    pub fn stats(&self) -> BlockCacheStats {
+        BlockCacheStats {
+            hits: self.inner.hits.load(Ordering::Relaxed),
+            misses: self.inner.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The total size of the cached chunks in bytes.
+    pub fn memory_usage(&self) -> usize {
+        self.inner
+            .shards
+            .iter()
+            .map(|shard| shard.lock().memory_usage())
+            .sum()
+    }
+
+    fn shard(&self, key: &BlockKey) -> &Mutex<SizedCache<BlockKey, Block>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        // it's ok to truncate the hash.
+        #[allow(clippy::cast_possible_truncation)]
+        let hash = hasher.finish() as usize;
+        &self.inner.shards[hash % self.inner.shards.len()]
+    }
+
+    fn count(&self, hit: bool) {
+        let counter = if hit {
+            &self.inner.hits
+        } else {
+            &self.inner.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Get the chunk at "offset" of the table, or insert it computed with "func".
+    ///
+    /// The shard isn't locked during the computation. Concurrent misses on the same chunk
+    /// wait for the first one to compute it.
+    pub fn get_or_insert<F>(&self, table_id: u64, offset: u64, func: F) -> Result<Bytes>
+    where
+        F: FnOnce() -> Result<Bytes>,
+    {
+        let key = (table_id, offset);
+        let shard = self.shard(&key);
+        let block = {
+            let mut shard = shard.lock();
+            match shard.get(&key) {
+                Some(block) => block.clone(),
+                None => {
+                    let block = Block::default();
+                    shard.put(key, block.clone(), 0);
+                    block
+                }
+            }
+        };
+        let mut computed = false;
+        let bytes = block
+            .get_or_try_init(|| {
+                computed = true;
+                func()
+            })?
+            .clone();
+        self.count(!computed);
+        if computed {
+            let mut shard = shard.lock();
+            // The chunk might have been evicted while it was computed.
+            let cached = shard.peek(&key);
+            if cached.map(|cached| Arc::ptr_eq(cached, &block)) == Some(true) {
+                shard.add_size(&key, bytes.cache_size());
+            }
+        }
+        Ok(bytes)
+    }
+
+    /// Get the chunk at "offset" of the table if it's cached, or compute it with "func"
+    /// without caching it. The chunk isn't counted as used by the cache policy.
+    pub fn get_or_compute<F>(&self, table_id: u64, offset: u64, func: F) -> Result<Bytes>
+    where
+        F: FnOnce() -> Result<Bytes>,
+    {
+        let key = (table_id, offset);
+        let cached = self
+            .shard(&key)
+            .lock()
+            .peek(&key)
+            .and_then(|block| block.get().cloned());
+        self.count(cached.is_some());
+        match cached {
+            Some(bytes) => Ok(bytes),
+            None => func(),
+        }
+    }
+
+    /// Get the chunk like `get_or_insert`, and keep it in the cache until the table's
+    /// chunks are unpinned.
+    pub fn pin<F>(&self, table_id: u64, offset: u64, func: F) -> Result<()>
+    where
+        F: FnOnce() -> Result<Bytes>,
+    {
+        let bytes = self.get_or_insert(table_id, offset, func)?;
+        let key = (table_id, offset);
+        let mut shard = self.shard(&key).lock();
+        // It might have been evicted by other readers in the meantime.
+        if !shard.pin(&key) {
+            let size = bytes.cache_size();
+            shard.put(key, Arc::new(OnceCell::from(bytes)), size);
+            shard.pin(&key);
+        }
+        Ok(())
+    }
+
+    /// Drop the pinned chunks of the table. This is done when its reader is dropped.
+    pub fn unpin_table(&self, table_id: u64) {
+        for shard in self.inner.shards.iter() {
+            shard.lock().unpin_where(|(id, _)| *id == table_id);
+        }
+    }
+}
+
+impl std::fmt::Debug for BlockCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BlockCache")
+            .field("shards", &self.inner.shards.len())
+            .finish()
+    }
+}
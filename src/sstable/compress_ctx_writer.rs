@@ -4,6 +4,7 @@ use super::compression::*;
 use super::poswriter::PosWriter;
 use super::{Error, Result};
 use std::convert::TryFrom;
+use std::sync::Arc;
 
 const COMPRESSOR_MISSING: Error = Error::ProgrammingError("compressor missing");
 
@@ -115,3 +116,57 @@ where
         Ok(enc.into_inner()?.into_inner())
     }
 }
+
+/// A version of CompressionContextWriter for custom codecs.
+///
+/// Codecs compress whole chunks, so everything written is buffered until
+/// the compression context is reset.
+pub struct CodecWriter<W> {
+    writer: PosWriter<W>,
+    codec: Arc<dyn Codec>,
+    buf: Vec<u8>,
+    compressed: Vec<u8>,
+}
+
+impl<W: Write> CodecWriter<W> {
+    pub fn new(writer: W, codec: Arc<dyn Codec>) -> Self {
+        Self {
+            writer: PosWriter::new(writer, 0),
+            codec,
+            buf: Vec::new(),
+            compressed: Vec::new(),
+        }
+    }
+    fn flush_chunk(&mut self) -> Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        self.compressed.clear();
+        self.codec.compress(&self.buf, &mut self.compressed)?;
+        self.writer.write_all(&self.compressed)?;
+        self.buf.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for CodecWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buf.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        // The chunk can't be flushed until it's done, as it's compressed at once.
+        self.writer.flush()
+    }
+}
+
+impl<W: Write> CompressionContextWriter<W> for CodecWriter<W> {
+    fn reset_compression_context(&mut self) -> Result<usize> {
+        self.flush_chunk()?;
+        Ok(usize::try_from(self.writer.current_offset())?)
+    }
+    fn into_inner(mut self: Box<Self>) -> Result<W> {
+        self.flush_chunk()?;
+        Ok(self.writer.into_inner())
+    }
+}
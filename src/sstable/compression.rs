@@ -2,9 +2,57 @@
 
 use super::Result;
 
+use super::types::{CodecId, Compression};
 use super::Error;
 use snap;
+use std::collections::HashMap;
 use std::io::{Cursor, Read, Write};
+use std::sync::Arc;
+
+/// A custom compression codec.
+///
+/// Unlike the built-in compressions, which are streaming, codecs compress and uncompress
+/// whole chunks at once.
+///
+/// Register it in `Codecs` and set `Compression::Custom` with its id in `WriteOptions`
+/// to use it.
+pub trait Codec: Send + Sync {
+    /// Compress "buf", appending the result to "out".
+    fn compress(&self, buf: &[u8], out: &mut Vec<u8>) -> Result<()>;
+    /// Uncompress "buf" into "out", which is exactly the length of the uncompressed data.
+    fn uncompress_into(&self, buf: &[u8], out: &mut [u8]) -> Result<()>;
+}
+
+/// A registry of custom codecs by their ids.
+///
+/// The ids are stored in the sstable metadata, so they must not change between writing
+/// and reading the table. Opening an sstable that uses a codec missing in the
+/// registry fails with `Error::UnknownCodec`.
+#[derive(Clone, Default)]
+pub struct Codecs {
+    codecs: HashMap<CodecId, Arc<dyn Codec>>,
+}
+
+impl Codecs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Register the codec with the id, replacing the one registered before if any.
+    pub fn register(&mut self, id: CodecId, codec: Arc<dyn Codec>) -> &mut Self {
+        self.codecs.insert(id, codec);
+        self
+    }
+    /// Get the codec registered with the id.
+    pub fn get(&self, id: CodecId) -> Result<Arc<dyn Codec>> {
+        self.codecs.get(&id).cloned().ok_or(Error::UnknownCodec(id))
+    }
+}
+
+impl std::fmt::Debug for Codecs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.codecs.keys()).finish()
+    }
+}
 
 pub trait CompressorFactory<W: Write, C: Compressor<W>> {
     fn from_writer(&self, writer: W) -> C;
@@ -15,7 +63,32 @@ pub trait Compressor<W: Write>: Write {
 }
 
 pub trait Uncompress {
-    fn uncompress(&self, buf: &[u8]) -> Result<Vec<u8>>;
+    /// Uncompress "buf" into "out".
+    ///
+    /// "out" must be exactly the length of the uncompressed data, the caller
+    /// knows it from the index.
+    fn uncompress_into(&self, buf: &[u8], out: &mut [u8]) -> Result<()>;
+}
+
+impl Uncompress for Box<dyn Uncompress + Send + Sync> {
+    fn uncompress_into(&self, buf: &[u8], out: &mut [u8]) -> Result<()> {
+        self.as_ref().uncompress_into(buf, out)
+    }
+}
+
+/// Custom codecs
+pub struct CodecUncompress {
+    codec: Arc<dyn Codec>,
+}
+
+impl Uncompress for CodecUncompress {
+    fn uncompress_into(&self, buf: &[u8], out: &mut [u8]) -> Result<()> {
+        if out.is_empty() {
+            // Empty sections are not passed to the codec when writing.
+            return Ok(());
+        }
+        self.codec.uncompress_into(buf, out)
+    }
 }
 
 /// ZLIB
@@ -70,13 +143,12 @@ impl<W: Write> CompressorFactory<W, ZlibCompressor<W>> for ZlibCompressorFactory
 }
 
 impl Uncompress for ZlibUncompress {
-    fn uncompress(&self, buf: &[u8]) -> Result<Vec<u8>> {
-        let mut dec = flate2::read::ZlibDecoder::new(Cursor::new(buf));
-        // TODO: buf.len() here is a bad heuristic. Need the real number, this can be pulled during
-        // compression.
-        let mut buf = Vec::with_capacity(buf.len());
-        dec.read_to_end(&mut buf)?;
-        Ok(buf)
+    fn uncompress_into(&self, buf: &[u8], out: &mut [u8]) -> Result<()> {
+        // The input is fully in memory, so the "bufread" decoder is used to not
+        // allocate an intermediate buffer.
+        let mut dec = flate2::bufread::ZlibDecoder::new(buf);
+        dec.read_exact(out)?;
+        Ok(())
     }
 }
 
@@ -134,12 +206,50 @@ impl<W: Write> CompressorFactory<W, SnappyCompressor<W>> for SnappyCompressorFac
 }
 
 impl Uncompress for SnappyUncompress {
-    fn uncompress(&self, buf: &[u8]) -> Result<Vec<u8>> {
+    fn uncompress_into(&self, buf: &[u8], out: &mut [u8]) -> Result<()> {
         let mut dec = snap::Reader::new(Cursor::new(buf));
-        // TODO: buf.len() here is a bad heuristic. Need the real number, this can be pulled during
-        // compression.
-        let mut buf = Vec::with_capacity(buf.len());
-        dec.read_to_end(&mut buf)?;
-        Ok(buf)
+        dec.read_exact(out)?;
+        Ok(())
+    }
+}
+
+/// Compress "buf" in one go, appending the result to "out".
+pub fn compress_into(
+    compression: Compression,
+    codecs: &Codecs,
+    buf: &[u8],
+    out: &mut Vec<u8>,
+) -> Result<()> {
+    match compression {
+        Compression::None => out.extend_from_slice(buf),
+        Compression::Zlib => {
+            let mut compressor = ZlibCompressor::new(out, flate2::Compression::default());
+            compressor.write_all(buf)?;
+            compressor.into_inner()?;
+        }
+        Compression::Snappy => {
+            let mut compressor = SnappyCompressor::new(out);
+            compressor.write_all(buf)?;
+            compressor.into_inner()?;
+        }
+        Compression::Custom(id) => codecs.get(id)?.compress(buf, out)?,
     }
+    Ok(())
+}
+
+/// Get the uncompressor for the compression, or None if there's nothing to uncompress.
+///
+/// Fails if the compression is a custom codec missing in "codecs".
+pub fn uncompressor(
+    compression: Compression,
+    codecs: &Codecs,
+) -> Result<Option<Box<dyn Uncompress + Send + Sync>>> {
+    Ok(match compression {
+        Compression::None => None,
+        Compression::Zlib => Some(Box::new(ZlibUncompress {})),
+        Compression::Snappy => Some(Box::new(SnappyUncompress {})),
+        Compression::Custom(id) => Some(Box::new(CodecUncompress {
+            codec: codecs.get(id)?,
+        })),
+    })
 }
@@ -1,5 +1,6 @@
-use super::{ReadCache, Result};
-use bytes::Bytes;
+use super::sized_cache::{CacheSize, SizedCache};
+use super::{CachePolicy, ReadCache, Result};
+use bytes::{Bytes, BytesMut};
 use parking_lot::{Mutex, RwLock};
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
@@ -7,84 +8,226 @@ use std::sync::Arc;
 
 struct Inner {
     value: RwLock<Option<Bytes>>,
+    // An empty handle to the allocation of the value, to reclaim it once it's evicted.
+    spare: Mutex<BytesMut>,
 }
 
 impl Inner {
     fn new() -> Self {
         Self {
             value: RwLock::new(None),
+            spare: Mutex::new(BytesMut::new()),
         }
     }
-    fn get_or_insert<F>(&self, func: F) -> Result<Bytes>
+    fn get(&self) -> Option<Bytes> {
+        self.value.read().clone()
+    }
+
+    /// Returns the value, and whether it was computed by this call.
+    fn get_or_insert<F>(&self, func: F) -> Result<(Bytes, bool)>
     where
-        F: Fn() -> Result<Bytes>,
+        F: FnOnce() -> Result<(Bytes, BytesMut)>,
     {
         {
             let g = self.value.read();
             if let Some(bytes) = g.as_ref() {
-                return Ok(bytes.clone());
+                return Ok((bytes.clone(), false));
             }
         }
         let mut g = self.value.write();
         match g.as_mut() {
-            Some(bytes) => Ok(bytes.clone()),
+            Some(bytes) => Ok((bytes.clone(), false)),
             None => {
-                let value = func()?;
+                let (value, spare) = func()?;
+                *self.spare.lock() = spare;
                 g.replace(value.clone());
-                Ok(value)
+                Ok((value, true))
             }
         }
     }
 }
 
+/// Get a buffer of "length" zeroes to compute a value into.
+///
+/// If "evicted" is no longer used by anything, neither the cache nor the readers, its
+/// allocation is reused, so a cache at capacity does not hit the allocator on misses.
+fn take_buffer(evicted: Option<Arc<Inner>>, length: usize) -> BytesMut {
+    let mut buf = match evicted.map(Arc::try_unwrap) {
+        Some(Ok(inner)) => {
+            drop(inner.value);
+            inner.spare.into_inner()
+        }
+        _ => BytesMut::new(),
+    };
+    // This reclaims the allocation if the handle is the last one to it.
+    buf.reserve(length);
+    buf.resize(length, 0);
+    buf
+}
+
+/// Fill "buf" with "fill", and split it into the value and a handle to its allocation.
+fn fill_buffer<F>(mut buf: BytesMut, fill: F) -> Result<(Bytes, BytesMut)>
+where
+    F: FnOnce(&mut [u8]) -> Result<()>,
+{
+    fill(&mut buf)?;
+    let spare = buf.split_off(buf.len());
+    Ok((buf.freeze(), spare))
+}
+
+/// Compute a value of "length" bytes that won't be cached.
+fn compute<F>(length: usize, fill: F) -> Result<Bytes>
+where
+    F: FnOnce(&mut [u8]) -> Result<()>,
+{
+    let mut buf = vec![0; length];
+    fill(&mut buf)?;
+    Ok(Bytes::from(buf))
+}
+
+impl CacheSize for Arc<Inner> {
+    fn cache_size(&self) -> usize {
+        // This is called with the shard locked, so a value being filled is skipped rather
+        // than waited for. It's accounted for once it's filled.
+        self.value
+            .try_read()
+            .and_then(|value| value.as_ref().map(Bytes::len))
+            .unwrap_or(0)
+    }
+}
+
 /// An LRU cache of Bytes that can be used by multiple threads
 /// concurrently.
 ///
 /// Works by sharding the single-threaded LRUCache into multiple shards.
+///
+/// With ReadCache::Bytes each shard gets an equal part of the budget. Values are
+/// accounted for once they are computed.
+///
+/// The values are computed by filling a buffer of the length given by the caller. On a
+/// miss in a full shard, the least recently used value is evicted before the new one is
+/// computed, and its buffer is reused unless a reader still holds it.
 pub struct ConcurrentLRUCache {
-    caches: Option<Vec<Mutex<lru::LruCache<u64, Arc<Inner>>>>>,
+    caches: Option<Vec<Mutex<SizedCache<u64, Arc<Inner>>>>>,
 }
 
 impl ConcurrentLRUCache {
-    pub fn new(shards: usize, cache: Option<ReadCache>) -> Self {
+    pub fn new(shards: usize, cache: Option<ReadCache>, policy: CachePolicy) -> Self {
+        let cache = cache.map(|cache| match cache {
+            ReadCache::Bytes(bytes) => ReadCache::Bytes(bytes / shards.max(1)),
+            cache => cache,
+        });
         Self {
-            caches: cache.map(|cache| (0..shards).map(|_| Mutex::new(cache.lru())).collect()),
+            caches: cache.map(|cache| {
+                (0..shards)
+                    .map(|_| Mutex::new(SizedCache::new(cache, policy)))
+                    .collect()
+            }),
+        }
+    }
+
+    /// The total size of the cached values in bytes.
+    pub fn memory_usage(&self) -> usize {
+        match self.caches.as_ref() {
+            Some(caches) => caches.iter().map(|lru| lru.lock().memory_usage()).sum(),
+            None => 0,
+        }
+    }
+
+    /// Get the value from the cache if it's there, or compute it using the provided
+    /// callback without caching it. The callback fills a buffer of "length" bytes.
+    pub fn get_or_compute<F>(&self, offset: u64, length: usize, fill: F) -> Result<Bytes>
+    where
+        F: FnOnce(&mut [u8]) -> Result<()>,
+    {
+        let caches = match self.caches.as_ref() {
+            Some(caches) => caches,
+            None => return compute(length, fill),
+        };
+        let inner = shard(caches, offset).lock().peek(&offset).cloned();
+        match inner.and_then(|inner| inner.get()) {
+            Some(value) => Ok(value),
+            None => compute(length, fill),
         }
     }
 
     /// Get or insert the value into the cache. The inserted value is computed
-    /// using the provided callback.
+    /// using the provided callback, which fills a buffer of "length" bytes.
     ///
     /// The shard-level lock is NOT held during the computation.
     /// During the computation the chunk-level lock is held, so only threads contending
     /// on the specific chunk will get blocked.
-    pub fn get_or_insert<F>(&self, offset: u64, func: F) -> Result<Bytes>
+    pub fn get_or_insert<F>(&self, offset: u64, length: usize, fill: F) -> Result<Bytes>
     where
-        F: Fn() -> Result<Bytes>,
+        F: FnOnce(&mut [u8]) -> Result<()>,
     {
         let caches = match self.caches.as_ref() {
             Some(caches) => caches,
-            None => return func(),
+            None => return compute(length, fill),
         };
 
-        let mut hasher = DefaultHasher::new();
-        offset.hash(&mut hasher);
-        // it's ok to truncate the hash.
-        #[allow(clippy::cast_possible_truncation)]
-        let hash = hasher.finish() as usize;
-        let idx = hash % caches.len();
-
-        let inner = {
-            let mut lru = unsafe { caches.get_unchecked(idx) }.lock();
+        let shard = shard(caches, offset);
+        let (inner, evicted) = {
+            let mut lru = shard.lock();
             match lru.get(&offset) {
-                Some(inner) => inner.clone(),
+                Some(inner) => (inner.clone(), None),
                 None => {
+                    let evicted = if lru.is_full(length) {
+                        lru.pop_lru()
+                    } else {
+                        None
+                    };
                     let inner = Arc::new(Inner::new());
-                    lru.put(offset, inner.clone());
-                    inner
+                    lru.put(offset, inner.clone(), 0);
+                    (inner, evicted)
                 }
             }
         };
-        inner.get_or_insert(func)
+        let (value, computed) =
+            inner.get_or_insert(|| fill_buffer(take_buffer(evicted, length), fill))?;
+        if computed {
+            let mut lru = shard.lock();
+            // The value might have been evicted while it was computed.
+            let cached = lru.peek(&offset);
+            if cached.map(|cached| Arc::ptr_eq(cached, &inner)) == Some(true) {
+                lru.add_size(&offset, value.cache_size());
+            }
+        }
+        Ok(value)
     }
+
+    /// Get or insert the value like `get_or_insert`, and keep it in the cache for as
+    /// long as the cache lives.
+    pub fn pin<F>(&self, offset: u64, length: usize, fill: F) -> Result<()>
+    where
+        F: FnOnce(&mut [u8]) -> Result<()>,
+    {
+        let value = self.get_or_insert(offset, length, fill)?;
+        let caches = match self.caches.as_ref() {
+            Some(caches) => caches,
+            None => return Ok(()),
+        };
+        let mut lru = shard(caches, offset).lock();
+        // It might have been evicted by other threads in the meantime.
+        if !lru.pin(&offset) {
+            let size = value.cache_size();
+            let inner = Inner {
+                value: RwLock::new(Some(value)),
+                spare: Mutex::new(BytesMut::new()),
+            };
+            lru.put(offset, Arc::new(inner), size);
+            lru.pin(&offset);
+        }
+        Ok(())
+    }
+}
+
+fn shard<T>(caches: &[T], offset: u64) -> &T {
+    let mut hasher = DefaultHasher::new();
+    offset.hash(&mut hasher);
+    // it's ok to truncate the hash.
+    #[allow(clippy::cast_possible_truncation)]
+    let hash = hasher.finish() as usize;
+    let idx = hash % caches.len();
+    unsafe { caches.get_unchecked(idx) }
 }
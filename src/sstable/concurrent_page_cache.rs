@@ -1,90 +1,164 @@
+use super::block_cache::BlockCache;
 use super::compression::Uncompress;
 use super::concurrent_lru::ConcurrentLRUCache;
-use super::options::ReadCache;
-use super::{error, page_cache, Result};
+use super::encryption::TableCipher;
+use super::env::RandomAccessFile;
+use super::options::{CachePolicy, ReadCache};
+use super::page_cache::{self, ChunkHandle, MemoryUsage};
+use super::secondary_cache::SecondaryCache;
+use super::{error, Result};
 
 use bytes::Bytes;
-use nix::sys::uio::pread;
 use std::convert::TryFrom;
-use std::fs::File;
-use std::os::unix::io::AsRawFd;
-use std::os::unix::io::RawFd;
-
-fn pread_exact(fd: RawFd, mut offset: u64, length: u64) -> Result<Vec<u8>> {
-    // if this was mmaped, there will be no truncation.
-    #[allow(clippy::cast_possible_truncation)]
-    let mut buf = vec![0_u8; length as usize];
-    let mut remaining = length;
-    while remaining > 0 {
-        let size = pread(fd, &mut buf, i64::try_from(offset)?)? as u64;
-        if size == 0 {
-            return Err(error::INVALID_DATA);
-        }
-        remaining -= size;
-        offset += size;
-    }
-    Ok(buf)
-}
+use std::sync::Arc;
 
 pub trait ConcurrentPageCache {
-    fn get_chunk(&self, offset: u64, length: u64) -> Result<Bytes>;
+    fn get_chunk(&self, chunk: ChunkHandle) -> Result<Bytes>;
+
+    /// Get the chunk like `get_chunk`, but don't cache it if it's not cached already.
+    fn get_chunk_no_fill(&self, chunk: ChunkHandle) -> Result<Bytes> {
+        self.get_chunk(chunk)
+    }
+
+    /// Cache the chunk so that it's never evicted, see `PageCache::pin_chunk`.
+    fn pin_chunk(&self, chunk: ChunkHandle) -> Result<()> {
+        self.get_chunk(chunk).map(|_| ())
+    }
+
+    /// See `PageCache::memory_usage`.
+    fn memory_usage(&self, _usage: &mut MemoryUsage) {}
 }
 
 impl ConcurrentPageCache for page_cache::StaticBufCache {
-    fn get_chunk(&self, offset: u64, length: u64) -> Result<Bytes> {
+    fn get_chunk(&self, chunk: ChunkHandle) -> Result<Bytes> {
         // if this was mmaped, there will be no truncation.
         #[allow(clippy::cast_possible_truncation)]
         self.get_buf()
-            .get(offset as usize..(offset + length) as usize)
+            .get(chunk.offset as usize..(chunk.offset + chunk.length) as usize)
             .map(Bytes::from_static)
             .ok_or(error::INVALID_DATA)
     }
 }
 
 pub struct FileBackedPageCache {
-    file: File,
+    file: Arc<dyn RandomAccessFile>,
     caches: ConcurrentLRUCache,
 }
 
 impl FileBackedPageCache {
-    pub fn new(file: File, cache: Option<ReadCache>, count: usize) -> Self {
+    pub fn new(
+        file: Arc<dyn RandomAccessFile>,
+        cache: Option<ReadCache>,
+        policy: CachePolicy,
+        count: usize,
+    ) -> Self {
         Self {
             file,
-            caches: ConcurrentLRUCache::new(count, cache),
+            caches: ConcurrentLRUCache::new(count, cache, policy),
         }
     }
-    fn read_chunk(&self, offset: u64, length: u64) -> Result<Bytes> {
-        let buf = pread_exact(self.file.as_raw_fd(), offset, length)?;
-        Ok(Bytes::from(buf))
-    }
 }
 
 impl ConcurrentPageCache for FileBackedPageCache {
-    fn get_chunk(&self, offset: u64, length: u64) -> Result<Bytes> {
+    fn get_chunk(&self, chunk: ChunkHandle) -> Result<Bytes> {
         self.caches
-            .get_or_insert(offset, || self.read_chunk(offset, length))
+            .get_or_insert(chunk.offset, usize::try_from(chunk.length)?, |buf| {
+                Ok(self.file.read_exact_at(buf, chunk.offset)?)
+            })
+    }
+
+    fn get_chunk_no_fill(&self, chunk: ChunkHandle) -> Result<Bytes> {
+        self.caches
+            .get_or_compute(chunk.offset, usize::try_from(chunk.length)?, |buf| {
+                Ok(self.file.read_exact_at(buf, chunk.offset)?)
+            })
+    }
+
+    fn pin_chunk(&self, chunk: ChunkHandle) -> Result<()> {
+        self.caches
+            .pin(chunk.offset, usize::try_from(chunk.length)?, |buf| {
+                Ok(self.file.read_exact_at(buf, chunk.offset)?)
+            })
+    }
+
+    fn memory_usage(&self, usage: &mut MemoryUsage) {
+        usage.raw_cache += self.caches.memory_usage();
     }
 }
 
+/// See `page_cache::WrappedCache`.
 pub struct WrappedCache<PC, U> {
     inner: PC,
     caches: ConcurrentLRUCache,
     uncompress: U,
+    // The secondary cache, and the key of the table in it.
+    secondary: Option<(SecondaryCache, u64)>,
 }
 
 impl<PC, U> WrappedCache<PC, U> {
-    pub fn new(inner: PC, uncompress: U, cache: Option<ReadCache>, count: usize) -> Self {
+    pub fn new(
+        inner: PC,
+        uncompress: U,
+        cache: Option<ReadCache>,
+        policy: CachePolicy,
+        count: usize,
+    ) -> Self {
         Self {
             inner,
-            caches: ConcurrentLRUCache::new(count, cache),
+            caches: ConcurrentLRUCache::new(count, cache, policy),
             uncompress,
+            secondary: None,
         }
     }
+
+    /// Use "secondary", a secondary cache and the key of the table in it.
+    pub fn with_secondary_cache(mut self, secondary: Option<(SecondaryCache, u64)>) -> Self {
+        self.secondary = secondary;
+        self
+    }
+
+    /// Uncompress the chunk read with "read" into "buf", unless the secondary cache has it.
+    /// The buffer is of the uncompressed length, which is known from the index.
+    fn uncompress_chunk<F>(
+        &self,
+        chunk: ChunkHandle,
+        fill_cache: bool,
+        read: F,
+        buf: &mut [u8],
+    ) -> Result<()>
+    where
+        U: Uncompress,
+        F: FnOnce() -> Result<Bytes>,
+    {
+        // Errors of the secondary cache are misses, see `page_cache::uncompress_chunk`.
+        if let Some((secondary, table_key)) = self.secondary.as_ref() {
+            if let Ok(true) = secondary.get(*table_key, chunk.offset, buf) {
+                return Ok(());
+            }
+        }
+        self.uncompress.uncompress_into(&read()?, buf)?;
+        if let (Some((secondary, table_key)), true) = (self.secondary.as_ref(), fill_cache) {
+            let _ = secondary.put(*table_key, chunk.offset, buf);
+        }
+        Ok(())
+    }
 }
 
 impl ConcurrentPageCache for Box<dyn ConcurrentPageCache + Send + Sync> {
-    fn get_chunk(&self, offset: u64, length: u64) -> Result<Bytes> {
-        self.as_ref().get_chunk(offset, length)
+    fn get_chunk(&self, chunk: ChunkHandle) -> Result<Bytes> {
+        self.as_ref().get_chunk(chunk)
+    }
+
+    fn get_chunk_no_fill(&self, chunk: ChunkHandle) -> Result<Bytes> {
+        self.as_ref().get_chunk_no_fill(chunk)
+    }
+
+    fn pin_chunk(&self, chunk: ChunkHandle) -> Result<()> {
+        self.as_ref().pin_chunk(chunk)
+    }
+
+    fn memory_usage(&self, usage: &mut MemoryUsage) {
+        self.as_ref().memory_usage(usage)
     }
 }
 
@@ -93,12 +167,128 @@ where
     U: Uncompress,
     PC: ConcurrentPageCache,
 {
-    fn get_chunk(&self, offset: u64, length: u64) -> Result<Bytes> {
-        self.caches.get_or_insert(offset, || {
-            let uncompressed = self.inner.get_chunk(offset, length)?;
-            let buf = self.uncompress.uncompress(&uncompressed)?;
-            let bytes = Bytes::from(buf);
-            Ok(bytes)
+    fn get_chunk(&self, chunk: ChunkHandle) -> Result<Bytes> {
+        let length = usize::try_from(chunk.uncompressed_length)?;
+        self.caches.get_or_insert(chunk.offset, length, |buf| {
+            self.uncompress_chunk(chunk, true, || self.inner.get_chunk(chunk), buf)
+        })
+    }
+
+    fn get_chunk_no_fill(&self, chunk: ChunkHandle) -> Result<Bytes> {
+        let length = usize::try_from(chunk.uncompressed_length)?;
+        self.caches.get_or_compute(chunk.offset, length, |buf| {
+            self.uncompress_chunk(chunk, false, || self.inner.get_chunk_no_fill(chunk), buf)
         })
     }
+
+    fn pin_chunk(&self, chunk: ChunkHandle) -> Result<()> {
+        let length = usize::try_from(chunk.uncompressed_length)?;
+        self.caches.pin(chunk.offset, length, |buf| {
+            self.uncompress_chunk(chunk, true, || self.inner.get_chunk(chunk), buf)
+        })
+    }
+
+    fn memory_usage(&self, usage: &mut MemoryUsage) {
+        usage.uncompressed_cache += self.caches.memory_usage();
+        self.inner.memory_usage(usage);
+    }
+}
+
+pub struct DecryptingCache<PC> {
+    inner: PC,
+    caches: ConcurrentLRUCache,
+    cipher: TableCipher,
+}
+
+impl<PC> DecryptingCache<PC> {
+    pub fn new(
+        inner: PC,
+        cipher: TableCipher,
+        cache: Option<ReadCache>,
+        policy: CachePolicy,
+        count: usize,
+    ) -> Self {
+        Self {
+            inner,
+            caches: ConcurrentLRUCache::new(count, cache, policy),
+            cipher,
+        }
+    }
+}
+
+impl<PC: ConcurrentPageCache> ConcurrentPageCache for DecryptingCache<PC> {
+    fn get_chunk(&self, chunk: ChunkHandle) -> Result<Bytes> {
+        let length = usize::try_from(page_cache::decrypted_length(&chunk)?)?;
+        self.caches.get_or_insert(chunk.offset, length, |buf| {
+            self.cipher
+                .decrypt_into(chunk.offset, &self.inner.get_chunk(chunk)?, buf)
+        })
+    }
+
+    fn get_chunk_no_fill(&self, chunk: ChunkHandle) -> Result<Bytes> {
+        let length = usize::try_from(page_cache::decrypted_length(&chunk)?)?;
+        self.caches.get_or_compute(chunk.offset, length, |buf| {
+            self.cipher
+                .decrypt_into(chunk.offset, &self.inner.get_chunk_no_fill(chunk)?, buf)
+        })
+    }
+
+    fn pin_chunk(&self, chunk: ChunkHandle) -> Result<()> {
+        let length = usize::try_from(page_cache::decrypted_length(&chunk)?)?;
+        self.caches.pin(chunk.offset, length, |buf| {
+            self.cipher
+                .decrypt_into(chunk.offset, &self.inner.get_chunk(chunk)?, buf)
+        })
+    }
+
+    fn memory_usage(&self, usage: &mut MemoryUsage) {
+        usage.decrypted_cache += self.caches.memory_usage();
+        self.inner.memory_usage(usage);
+    }
+}
+
+/// A cache that wraps another one, and keeps its results in a BlockCache shared with
+/// other readers.
+pub struct SharedCache<PC> {
+    inner: PC,
+    cache: BlockCache,
+    table_id: u64,
+}
+
+impl<PC> SharedCache<PC> {
+    pub fn new(inner: PC, cache: BlockCache) -> Self {
+        Self {
+            inner,
+            table_id: cache.new_table_id(),
+            cache,
+        }
+    }
+}
+
+impl<PC: ConcurrentPageCache> ConcurrentPageCache for SharedCache<PC> {
+    fn get_chunk(&self, chunk: ChunkHandle) -> Result<Bytes> {
+        self.cache
+            .get_or_insert(self.table_id, chunk.offset, || self.inner.get_chunk(chunk))
+    }
+
+    fn get_chunk_no_fill(&self, chunk: ChunkHandle) -> Result<Bytes> {
+        self.cache.get_or_compute(self.table_id, chunk.offset, || {
+            self.inner.get_chunk_no_fill(chunk)
+        })
+    }
+
+    fn pin_chunk(&self, chunk: ChunkHandle) -> Result<()> {
+        self.cache
+            .pin(self.table_id, chunk.offset, || self.inner.get_chunk(chunk))
+    }
+
+    fn memory_usage(&self, usage: &mut MemoryUsage) {
+        self.inner.memory_usage(usage);
+    }
+}
+
+impl<PC> Drop for SharedCache<PC> {
+    fn drop(&mut self) {
+        self.cache.unpin_table(self.table_id);
+    }
 }
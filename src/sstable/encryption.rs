@@ -0,0 +1,207 @@
+//! Encryption at rest.
+//!
+//! Every chunk is encrypted after compression with XChaCha20-Poly1305. The 24-byte nonce
+//! of a chunk is made of a random per-table nonce (stored in the metadata) followed by
+//! the chunk's offset in the file, so nonces never repeat within or across tables.
+//!
+//! The index and the bloom filter are encrypted as one chunk each.
+
+use std::convert::TryFrom;
+use std::io::Write;
+use std::sync::Arc;
+
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{Tag, XChaCha20Poly1305, XNonce};
+
+use super::compress_ctx_writer::CompressionContextWriter;
+use super::ondisk_format::EncryptionV3_2;
+use super::poswriter::PosWriter;
+use super::{Error, Result};
+
+/// The length of the authentication tag appended to every encrypted chunk.
+pub const TAG_LENGTH: usize = 16;
+
+/// Identifies the key in the key provider. It's stored in the sstable unencrypted.
+pub type KeyId = u32;
+
+/// A 256-bit encryption key.
+pub type EncryptionKey = [u8; 32];
+
+/// Supplies the encryption keys.
+///
+/// The sstable only stores the id of the key it was encrypted with, so keys can be
+/// rotated by writing new tables with a new id, while keeping old keys to read old tables.
+pub trait KeyProvider: Send + Sync {
+    fn key(&self, id: KeyId) -> Result<EncryptionKey>;
+}
+
+impl std::fmt::Debug for dyn KeyProvider {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("KeyProvider")
+    }
+}
+
+/// Which key to encrypt new sstables with.
+#[derive(Clone, Debug)]
+pub struct Encryption {
+    pub key_id: KeyId,
+    pub keys: Arc<dyn KeyProvider>,
+}
+
+/// The cipher of one table.
+#[derive(Clone)]
+pub struct TableCipher {
+    aead: XChaCha20Poly1305,
+    table_nonce: [u8; 16],
+}
+
+impl TableCipher {
+    /// Make a cipher for a new table, with a fresh random table nonce.
+    pub fn generate(encryption: &Encryption) -> Result<(Self, EncryptionV3_2)> {
+        let mut meta = EncryptionV3_2 {
+            key_id: encryption.key_id,
+            table_nonce: [0; 16],
+        };
+        getrandom::getrandom(&mut meta.table_nonce).map_err(|e| Error::Io(e.into()))?;
+        let cipher = Self::new(&encryption.keys.key(meta.key_id)?, &meta);
+        Ok((cipher, meta))
+    }
+
+    /// Make a cipher for an existing table.
+    pub fn from_meta(keys: Option<&Arc<dyn KeyProvider>>, meta: &EncryptionV3_2) -> Result<Self> {
+        let keys = keys.ok_or(Error::EncryptionKeyRequired(meta.key_id))?;
+        Ok(Self::new(&keys.key(meta.key_id)?, meta))
+    }
+
+    fn new(key: &EncryptionKey, meta: &EncryptionV3_2) -> Self {
+        Self {
+            aead: XChaCha20Poly1305::new(key.into()),
+            table_nonce: meta.table_nonce,
+        }
+    }
+
+    fn nonce(&self, offset: u64) -> XNonce {
+        let mut nonce = XNonce::default();
+        nonce[..16].copy_from_slice(&self.table_nonce);
+        nonce[16..].copy_from_slice(&offset.to_le_bytes());
+        nonce
+    }
+
+    /// Encrypt the chunk that will be stored at "offset" in place, appending the tag.
+    pub fn encrypt(&self, offset: u64, buf: &mut Vec<u8>) -> Result<()> {
+        let tag = self
+            .aead
+            .encrypt_in_place_detached(&self.nonce(offset), b"", buf)
+            .map_err(|_| Error::ProgrammingError("chunk too large to encrypt"))?;
+        buf.extend_from_slice(&tag);
+        Ok(())
+    }
+
+    /// Decrypt the chunk stored at "offset" into "out", which must be exactly
+    /// TAG_LENGTH bytes shorter than "buf".
+    pub fn decrypt_into(&self, offset: u64, buf: &[u8], out: &mut [u8]) -> Result<()> {
+        if buf.len() != out.len() + TAG_LENGTH {
+            return Err(super::INVALID_DATA);
+        }
+        let (ciphertext, tag) = buf.split_at(out.len());
+        out.copy_from_slice(ciphertext);
+        self.aead
+            .decrypt_in_place_detached(&self.nonce(offset), b"", out, Tag::from_slice(tag))
+            .map_err(|_| Error::InvalidData("chunk authentication failed"))
+    }
+
+    /// Decrypt a whole section stored at "offset". An empty section stays empty.
+    pub fn decrypt_section(&self, offset: u64, buf: &[u8]) -> Result<Vec<u8>> {
+        if buf.is_empty() {
+            return Ok(Vec::new());
+        }
+        let plaintext_length = buf
+            .len()
+            .checked_sub(TAG_LENGTH)
+            .ok_or(super::INVALID_DATA)?;
+        let mut out = vec![0; plaintext_length];
+        self.decrypt_into(offset, buf, &mut out)?;
+        Ok(out)
+    }
+}
+
+/// Makes the compressing writer for every chunk of the EncryptingWriter.
+pub type ChunkWriterFactory =
+    Box<dyn Fn(Vec<u8>) -> Result<Box<dyn CompressionContextWriter<Vec<u8>>>>>;
+
+/// A CompressionContextWriter that encrypts every compressed chunk.
+///
+/// The chunk is compressed into memory by a writer made by "factory", and encrypted
+/// when the compression context is reset. As contexts are reset at chunk boundaries
+/// anyway, a fresh compressing writer is made for every chunk.
+pub struct EncryptingWriter<W> {
+    writer: PosWriter<W>,
+    // The offset of the writer in the file, for the nonces.
+    base_offset: u64,
+    cipher: TableCipher,
+    factory: ChunkWriterFactory,
+    chunk: Option<Box<dyn CompressionContextWriter<Vec<u8>>>>,
+}
+
+impl<W: Write> EncryptingWriter<W> {
+    pub fn new(
+        writer: W,
+        base_offset: u64,
+        cipher: TableCipher,
+        factory: ChunkWriterFactory,
+    ) -> Result<Self> {
+        let chunk = factory(Vec::new())?;
+        Ok(Self {
+            writer: PosWriter::new(writer, 0),
+            base_offset,
+            cipher,
+            factory,
+            chunk: Some(chunk),
+        })
+    }
+
+    fn chunk_mut(&mut self) -> std::io::Result<&mut Box<dyn CompressionContextWriter<Vec<u8>>>> {
+        self.chunk
+            .as_mut()
+            .ok_or_else(|| std::io::Error::other("chunk writer missing"))
+    }
+
+    /// Encrypt and write out the current chunk, and return the finished chunk's buffer.
+    fn flush_chunk(&mut self) -> Result<Vec<u8>> {
+        let chunk = self
+            .chunk
+            .take()
+            .ok_or(Error::ProgrammingError("chunk writer missing"))?;
+        let mut buf = chunk.into_inner()?;
+        if !buf.is_empty() {
+            let offset = self.base_offset + self.writer.current_offset();
+            self.cipher.encrypt(offset, &mut buf)?;
+            self.writer.write_all(&buf)?;
+        }
+        buf.clear();
+        Ok(buf)
+    }
+}
+
+impl<W: Write> Write for EncryptingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.chunk_mut()?.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        // The chunk can't be flushed until it's done, as it's encrypted at once.
+        self.writer.flush()
+    }
+}
+
+impl<W: Write> CompressionContextWriter<W> for EncryptingWriter<W> {
+    fn reset_compression_context(&mut self) -> Result<usize> {
+        let buf = self.flush_chunk()?;
+        self.chunk = Some((self.factory)(buf)?);
+        Ok(usize::try_from(self.writer.current_offset())?)
+    }
+    fn into_inner(mut self: Box<Self>) -> Result<W> {
+        self.flush_chunk()?;
+        Ok(self.writer.into_inner())
+    }
+}
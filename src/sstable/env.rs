@@ -0,0 +1,501 @@
+//! Where sstables are stored: files on disk, in memory, or any storage implementing `Env`.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::hash::Hasher;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::Deref;
+use std::os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use parking_lot::{Mutex, RwLock};
+use siphasher::sip::SipHasher13;
+
+/// A hint to the OS about how a range of a file is going to be read, see
+/// `ReadOptions::access_pattern` and `RandomAccessFile::advise`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Advice {
+    /// No particular pattern.
+    Normal,
+    /// Point lookups. The OS doesn't read ahead, as the pages around a chunk are
+    /// unlikely to be read next.
+    Random,
+    /// Scans. The OS reads ahead more than usual.
+    Sequential,
+    /// The range is going to be read soon, so the OS reads it in the background.
+    WillNeed,
+    /// The range is not going to be read again soon, so the OS may drop it from the
+    /// page cache.
+    DontNeed,
+}
+
+impl Advice {
+    fn madvise(self) -> libc::c_int {
+        match self {
+            Self::Normal => libc::MADV_NORMAL,
+            Self::Random => libc::MADV_RANDOM,
+            Self::Sequential => libc::MADV_SEQUENTIAL,
+            Self::WillNeed => libc::MADV_WILLNEED,
+            Self::DontNeed => libc::MADV_DONTNEED,
+        }
+    }
+
+    fn fadvise(self) -> libc::c_int {
+        match self {
+            Self::Normal => libc::POSIX_FADV_NORMAL,
+            Self::Random => libc::POSIX_FADV_RANDOM,
+            Self::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+            Self::WillNeed => libc::POSIX_FADV_WILLNEED,
+            Self::DontNeed => libc::POSIX_FADV_DONTNEED,
+        }
+    }
+}
+
+/// A file mapped into memory, see `RandomAccessFile::map`.
+///
+/// The bytes stay at the same address for as long as any clone of it is alive.
+#[derive(Clone)]
+pub struct MappedFile {
+    data: Arc<dyn AsRef<[u8]> + Send + Sync>,
+    // Set if "data" is an mmap, that takes hints with madvise.
+    mmapped: bool,
+}
+
+impl MappedFile {
+    pub fn new<T: AsRef<[u8]> + Send + Sync + 'static>(data: T) -> Self {
+        Self {
+            data: Arc::new(data),
+            mmapped: false,
+        }
+    }
+
+    fn from_mmap(mmap: memmap::Mmap) -> Self {
+        Self {
+            data: Arc::new(mmap),
+            mmapped: true,
+        }
+    }
+
+    /// Pass "advice" about "length" bytes at "offset" on to the OS with madvise.
+    /// Only files mapped with mmap take hints, for others this does nothing.
+    pub fn advise(&self, offset: u64, length: u64, advice: Advice) -> io::Result<()> {
+        if !self.mmapped {
+            return Ok(());
+        }
+        let len = self.len();
+        let start = usize::try_from(offset).map_or(len, |offset| offset.min(len));
+        let end = usize::try_from(length)
+            .ok()
+            .and_then(|length| start.checked_add(length))
+            .map_or(len, |end| end.min(len));
+        // madvise takes a page aligned address, and the mapping starts at one.
+        let start = start - start % page_size();
+        if start == end {
+            return Ok(());
+        }
+        let addr = self[start..].as_ptr() as *mut libc::c_void;
+        if unsafe { libc::madvise(addr, end - start, advice.madvise()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+fn page_size() -> usize {
+    let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    usize::try_from(size).unwrap_or(DIRECT_IO_ALIGNMENT)
+}
+
+impl Deref for MappedFile {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        (*self.data).as_ref()
+    }
+}
+
+impl std::fmt::Debug for MappedFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MappedFile")
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+/// A file the readers read sstables from. It's shared between threads, so reads take
+/// the offset to read at.
+pub trait RandomAccessFile: Send + Sync {
+    /// Read up to "buf.len()" bytes at "offset", and return how many were read.
+    /// 0 means the offset is at the end of the file.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+
+    /// Read exactly "buf.len()" bytes at "offset".
+    fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(buf, offset) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// The length of the file in bytes.
+    fn size(&self) -> io::Result<u64>;
+
+    /// Map the whole file into memory, so that readers with `ReadOptions::use_mmap`
+    /// can use it without copying. None if the file can't be mapped, then the readers
+    /// read it with `read_at`, and `MmapUncompressedSSTableReader` can't be used.
+    fn map(&self) -> io::Result<Option<MappedFile>> {
+        Ok(None)
+    }
+
+    /// A key that stays the same as long as the file is not replaced or modified,
+    /// including across restarts. `SecondaryCache` keeps chunks of files that have one.
+    fn unique_id(&self) -> io::Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Pass "advice" about "length" bytes at "offset" on to the OS, e.g. with
+    /// posix_fadvise. The readers ignore the errors, as hints are best effort. Storage
+    /// without a page cache does nothing, the default.
+    fn advise(&self, _offset: u64, _length: u64, _advice: Advice) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A file the writer writes an sstable to. The writer seeks back to the start once
+/// it's done, to fill in the metadata.
+pub trait WritableFile: Write + Seek + Send {}
+
+impl<T: Write + Seek + Send> WritableFile for T {}
+
+/// The storage the sstables are read from and written to, see `ReadOptions::env` and
+/// `WriteOptions::env`.
+///
+/// `PosixEnv` is the default, `MemEnv` keeps the sstables in memory.
+pub trait Env: Send + Sync {
+    /// Open the file at "path" for reading.
+    fn open(&self, path: &Path) -> io::Result<Arc<dyn RandomAccessFile>>;
+    /// Open the file at "path" for reading around the OS page cache, see
+    /// `ReadOptions::direct_io`. Storage without a page cache opens it as usual.
+    fn open_direct(&self, path: &Path) -> io::Result<Arc<dyn RandomAccessFile>> {
+        self.open(path)
+    }
+    /// Create the file at "path" for writing, truncating it if it exists.
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WritableFile>>;
+}
+
+impl std::fmt::Debug for dyn Env {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("Env")
+    }
+}
+
+/// Compute "base" + "delta" for seeking, failing if it's out of bounds.
+fn seek_offset(base: u64, delta: i64) -> io::Result<u64> {
+    let offset = match u64::try_from(delta) {
+        Ok(delta) => base.checked_add(delta),
+        Err(_) => base.checked_sub(delta.unsigned_abs()),
+    };
+    offset.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid seek"))
+}
+
+/// Reads a `RandomAccessFile` sequentially, starting at an offset.
+pub struct FileReader<'a> {
+    file: &'a dyn RandomAccessFile,
+    offset: u64,
+}
+
+impl<'a> FileReader<'a> {
+    pub fn new(file: &'a dyn RandomAccessFile, offset: u64) -> Self {
+        Self { file, offset }
+    }
+}
+
+impl<'a> Read for FileReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.file.read_at(buf, self.offset)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+impl<'a> Seek for FileReader<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.offset = match pos {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::Current(delta) => seek_offset(self.offset, delta)?,
+            SeekFrom::End(delta) => seek_offset(self.file.size()?, delta)?,
+        };
+        Ok(self.offset)
+    }
+}
+
+/// Files on local disk, read with pread and mmap, or with O_DIRECT.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct PosixEnv;
+
+/// The alignment of the offsets, lengths and buffers of O_DIRECT reads.
+const DIRECT_IO_ALIGNMENT: usize = 4096;
+
+/// How many extents a DirectFile keeps for reuse, and up to which length. Longer ones,
+/// like the extent of the index read when the table is opened, are dropped.
+const POOLED_EXTENTS: usize = 4;
+const MAX_POOLED_EXTENT: usize = 1 << 20;
+
+/// See `RandomAccessFile::unique_id`.
+fn file_unique_id(file: &File) -> io::Result<Option<u64>> {
+    let meta = file.metadata()?;
+    let mut hasher = SipHasher13::new_with_keys(0x7373_7462_7461_626c, 0x655f_6b65_7900_0000);
+    hasher.write_u64(meta.dev());
+    hasher.write_u64(meta.ino());
+    hasher.write_u64(meta.size());
+    hasher.write_i64(meta.mtime());
+    hasher.write_i64(meta.mtime_nsec());
+    Ok(Some(hasher.finish()))
+}
+
+struct PosixFile {
+    file: File,
+}
+
+impl RandomAccessFile for PosixFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.file.read_at(buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn map(&self) -> io::Result<Option<MappedFile>> {
+        let mmap = unsafe { memmap::Mmap::map(&self.file) }?;
+        Ok(Some(MappedFile::from_mmap(mmap)))
+    }
+
+    fn unique_id(&self) -> io::Result<Option<u64>> {
+        file_unique_id(&self.file)
+    }
+
+    fn advise(&self, offset: u64, length: u64, advice: Advice) -> io::Result<()> {
+        let invalid = |e| io::Error::new(io::ErrorKind::InvalidInput, e);
+        let offset = libc::off_t::try_from(offset).map_err(invalid)?;
+        let length = libc::off_t::try_from(length).map_err(invalid)?;
+        // posix_fadvise returns the error rather than setting errno.
+        match unsafe {
+            libc::posix_fadvise(self.file.as_raw_fd(), offset, length, advice.fadvise())
+        } {
+            0 => Ok(()),
+            errno => Err(io::Error::from_raw_os_error(errno)),
+        }
+    }
+}
+
+/// A file opened with O_DIRECT. Aligned reads go straight into the caller's buffer.
+/// Others cover the aligned extent around the requested range, and copy the range out of
+/// it. It can't be mapped, and hints are ignored, as it's not in the page cache.
+struct DirectFile {
+    file: File,
+    // Buffers for the extents, reused rather than allocated and zeroed on every read.
+    extents: Mutex<Vec<Vec<u8>>>,
+}
+
+impl RandomAccessFile for DirectFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        if offset.is_multiple_of(DIRECT_IO_ALIGNMENT as u64)
+            && buf.len().is_multiple_of(DIRECT_IO_ALIGNMENT)
+            && buf.as_ptr().align_offset(DIRECT_IO_ALIGNMENT) == 0
+        {
+            return self.file.read_at(buf, offset);
+        }
+        let start = offset - offset % DIRECT_IO_ALIGNMENT as u64;
+        // Less than the alignment.
+        #[allow(clippy::cast_possible_truncation)]
+        let skip = (offset - start) as usize;
+        let length = (skip + buf.len()).next_multiple_of(DIRECT_IO_ALIGNMENT);
+        let mut extent = self.extents.lock().pop().unwrap_or_default();
+        if extent.len() < length + DIRECT_IO_ALIGNMENT {
+            extent.resize(length + DIRECT_IO_ALIGNMENT, 0);
+        }
+        let pad = extent.as_ptr().align_offset(DIRECT_IO_ALIGNMENT);
+        let aligned = &mut extent[pad..pad + length];
+        let n = self.file.read_at(aligned, start)?;
+        let n = n.saturating_sub(skip).min(buf.len());
+        buf[..n].copy_from_slice(&aligned[skip..skip + n]);
+        if extent.len() <= MAX_POOLED_EXTENT {
+            let mut extents = self.extents.lock();
+            if extents.len() < POOLED_EXTENTS {
+                extents.push(extent);
+            }
+        }
+        Ok(n)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn unique_id(&self) -> io::Result<Option<u64>> {
+        file_unique_id(&self.file)
+    }
+}
+
+impl Env for PosixEnv {
+    fn open(&self, path: &Path) -> io::Result<Arc<dyn RandomAccessFile>> {
+        Ok(Arc::new(PosixFile {
+            file: File::open(path)?,
+        }))
+    }
+
+    fn open_direct(&self, path: &Path) -> io::Result<Arc<dyn RandomAccessFile>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .custom_flags(nix::fcntl::O_DIRECT.bits())
+            .open(path)?;
+        Ok(Arc::new(DirectFile {
+            file,
+            extents: Mutex::new(Vec::new()),
+        }))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        Ok(Box::new(File::create(path)?))
+    }
+}
+
+/// The contents of a file in `MemEnv`, shared with the writer of the file.
+pub type MemFileData = Arc<Mutex<Vec<u8>>>;
+
+/// Files kept in memory, e.g. for tests, or tables that are only used for a while.
+///
+/// Clones of the env share the files. Files opened for reading are a snapshot of the
+/// contents at the time, they don't see later writes.
+///
+/// Example:
+/// ```
+/// use std::sync::Arc;
+/// use sstb::sstable::*;
+///
+/// let env = Arc::new(MemEnv::new());
+/// let mut writer = SSTableWriterV2::new_with_options(
+///     "some-sstable",
+///     WriteOptions::default().env(env.clone()),
+/// ).unwrap();
+/// writer.set(b"foo", b"bar").unwrap();
+/// writer.finish().unwrap();
+///
+/// let mut reader = SSTableReader::new_with_options(
+///     "some-sstable",
+///     ReadOptions::default().env(env),
+/// ).unwrap();
+/// assert_eq!(reader.get(b"foo").unwrap(), Some(b"bar" as &[u8]));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct MemEnv {
+    files: Arc<RwLock<HashMap<PathBuf, MemFileData>>>,
+}
+
+impl MemEnv {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remove the file at "path". Returns false if there was none.
+    pub fn remove<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.files.write().remove(path.as_ref()).is_some()
+    }
+}
+
+impl Env for MemEnv {
+    fn open(&self, path: &Path) -> io::Result<Arc<dyn RandomAccessFile>> {
+        let data = match self.files.read().get(path) {
+            Some(data) => Bytes::copy_from_slice(&data.lock()),
+            None => return Err(io::ErrorKind::NotFound.into()),
+        };
+        Ok(Arc::new(MemFile::new(data)))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        let data = MemFileData::default();
+        self.files.write().insert(path.to_owned(), data.clone());
+        Ok(Box::new(MemWritableFile::new(data)))
+    }
+}
+
+/// A file in memory, that can be mapped without copying.
+pub struct MemFile {
+    data: Bytes,
+}
+
+impl MemFile {
+    pub fn new(data: Bytes) -> Self {
+        Self { data }
+    }
+}
+
+impl RandomAccessFile for MemFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let data = usize::try_from(offset)
+            .ok()
+            .and_then(|offset| self.data.get(offset..))
+            .unwrap_or_default();
+        let n = buf.len().min(data.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        Ok(n)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.data.len() as u64)
+    }
+
+    fn map(&self) -> io::Result<Option<MappedFile>> {
+        Ok(Some(MappedFile::new(self.data.clone())))
+    }
+}
+
+/// Writes into a buffer in memory.
+pub struct MemWritableFile {
+    data: MemFileData,
+    position: u64,
+}
+
+impl MemWritableFile {
+    pub fn new(data: MemFileData) -> Self {
+        Self { data, position: 0 }
+    }
+}
+
+impl Write for MemWritableFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut data = self.data.lock();
+        let mut cursor = Cursor::new(&mut *data);
+        cursor.set_position(self.position);
+        let n = cursor.write(buf)?;
+        self.position = cursor.position();
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemWritableFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = match pos {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::Current(delta) => seek_offset(self.position, delta)?,
+            SeekFrom::End(delta) => seek_offset(self.data.lock().len() as u64, delta)?,
+        };
+        Ok(self.position)
+    }
+}
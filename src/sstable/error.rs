@@ -1,4 +1,4 @@
-use super::Version;
+use super::{CodecId, KeyId, Version};
 
 pub const INVALID_DATA: Error = Error::InvalidData("corrupt SStable or bug");
 
@@ -6,9 +6,15 @@ pub const INVALID_DATA: Error = Error::InvalidData("corrupt SStable or bug");
 pub enum Error {
     Io(std::io::Error),
     CantUseCompressedFileWithMultiThreadedMmap,
+    CantUseEncryptedFileWithMultiThreadedMmap,
+    CantMapFile,
     ProgrammingError(&'static str),
+    InvalidOptions(&'static str),
     InvalidData(&'static str),
     UnsupportedVersion(Version),
+    UnknownCodec(CodecId),
+    EncryptionKeyRequired(KeyId),
+    NotEncrypted,
     Bincode(bincode::Error),
     Utf8Error(std::str::Utf8Error),
     KeyTooLong(usize),
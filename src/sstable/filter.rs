@@ -0,0 +1,397 @@
+//! Filters of chunks and tables.
+//!
+//! The items are key hashes (see `ondisk_format::bloom_key_hash`), and every probe
+//! is derived from the key hash, so a filter can be checked right in the page cache
+//! buffer, without copying or hashing the key again.
+//!
+//! There are 3 formats, see `FilterType`:
+//!
+//! - bloom: a bitmap followed by one byte with the number of probes. Probes are made by
+//!   double hashing. Tables with a table-wide filter of this type use the `bloomfilter`
+//!   crate instead, see `BloomV3_0`.
+//! - blocked bloom: same, but all the probes of a key fall into one 64-byte block, so a
+//!   lookup touches a single cache line.
+//! - xor: a static filter of fingerprints, see `build_xor`.
+//!
+//! A range filter is a filter of one of these types, with the prefixes of the keys at the
+//! lengths of `LADDER` in it, see `range_may_contain`.
+
+use std::borrow::Cow;
+use std::convert::TryFrom;
+
+use bloomfilter::Bloom;
+
+use std::ops::Bound;
+
+use super::ondisk_format::range_filter_hash;
+use super::options::BloomConfig;
+use super::types::FilterType;
+use super::Result;
+
+// k > 30 does not make the filter any better, but makes it slower.
+const MAX_PROBES: u8 = 30;
+// With MAX_PROBES, more bits than this don't make the filter any better, the false
+// positive rate is about 1e-13 already.
+const MAX_BITS_PER_KEY: f64 = 64.0;
+
+/// How many bits per key "config" asks for, at most MAX_BITS_PER_KEY, so that tiny
+/// false positive rates don't ask for infinite bits.
+///
+/// "config" is checked by `BloomConfig::validate` when the writer is made.
+pub fn bits_per_key(config: BloomConfig) -> f64 {
+    let bits_per_key = match config {
+        BloomConfig::BitsPerKey(bits_per_key) => bits_per_key,
+        BloomConfig::FalsePositiveRate(rate) => {
+            -rate.ln() / (std::f64::consts::LN_2 * std::f64::consts::LN_2)
+        }
+    };
+    bits_per_key.min(MAX_BITS_PER_KEY)
+}
+
+fn probes(key_hash: u64, bits: u64, count: u8) -> impl Iterator<Item = u64> {
+    let delta = key_hash.rotate_left(32) | 1;
+    (0..u64::from(count)).map(move |i| key_hash.wrapping_add(i.wrapping_mul(delta)) % bits)
+}
+
+/// Build a filter of "filter_type" with the keys hashed into "key_hashes".
+pub fn build(filter_type: FilterType, config: BloomConfig, key_hashes: &[u64]) -> Result<Vec<u8>> {
+    match filter_type {
+        FilterType::Bloom => Ok(build_chunk_filter(config, key_hashes)),
+        FilterType::BlockedBloom => Ok(build_blocked_bloom(config, key_hashes)),
+        FilterType::Xor => build_xor(config, key_hashes),
+    }
+}
+
+/// Returns false if the key hashed to "key_hash" is definitely not in "filter".
+pub fn may_contain(filter_type: FilterType, filter: &[u8], key_hash: u64) -> bool {
+    match filter_type {
+        FilterType::Bloom => chunk_filter_may_contain(filter, key_hash),
+        FilterType::BlockedBloom => blocked_bloom_may_contain(filter, key_hash),
+        FilterType::Xor => xor_may_contain(filter, key_hash),
+    }
+}
+
+/// The filter of a whole table.
+pub enum TableFilter {
+    /// The bloom filter of `BloomV3_0`.
+    Bloom(Bloom<u64>),
+    /// A filter built by `build`, possibly borrowed from the mmap'ed file.
+    Encoded(FilterType, Cow<'static, [u8]>),
+}
+
+impl TableFilter {
+    pub fn may_contain(&self, key_hash: u64) -> bool {
+        match self {
+            Self::Bloom(bloom) => bloom.check(&key_hash),
+            Self::Encoded(filter_type, filter) => may_contain(*filter_type, filter, key_hash),
+        }
+    }
+
+    /// The bytes held by the filter. Filters borrowed from the mmap'ed file hold none.
+    pub fn memory_usage(&self) -> usize {
+        match self {
+            Self::Bloom(bloom) => usize::try_from(bloom.number_of_bits() / 8).unwrap_or(usize::MAX),
+            Self::Encoded(_, Cow::Owned(filter)) => filter.capacity(),
+            Self::Encoded(_, Cow::Borrowed(_)) => 0,
+        }
+    }
+}
+
+/// The size of the bitmap in bytes and the number of probes for "keys" keys.
+fn bloom_size(config: BloomConfig, keys: usize) -> (usize, u8) {
+    let bits_per_key = bits_per_key(config).max(1.0);
+    // the sizes are approximate anyway.
+    #[allow(
+        clippy::cast_precision_loss,
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss
+    )]
+    (
+        ((keys as f64 * bits_per_key / 8.0).ceil() as usize).max(8),
+        ((bits_per_key * std::f64::consts::LN_2).round() as u8).clamp(1, MAX_PROBES),
+    )
+}
+
+/// Build the filter of a chunk with the keys hashed into "key_hashes".
+pub fn build_chunk_filter(config: BloomConfig, key_hashes: &[u64]) -> Vec<u8> {
+    let (bytes, count) = bloom_size(config, key_hashes.len());
+    let mut filter = vec![0; bytes + 1];
+    let bits = bytes as u64 * 8;
+    for &key_hash in key_hashes {
+        for bit in probes(key_hash, bits, count) {
+            filter[(bit / 8) as usize] |= 1 << (bit % 8);
+        }
+    }
+    filter[bytes] = count;
+    filter
+}
+
+/// Returns false if the key hashed to "key_hash" is definitely not in the chunk.
+pub fn chunk_filter_may_contain(filter: &[u8], key_hash: u64) -> bool {
+    let (count, bitmap) = match filter.split_last() {
+        Some((count, bitmap)) if !bitmap.is_empty() => (*count, bitmap),
+        // Broken filters can't rule anything out.
+        _ => return true,
+    };
+    let bits = bitmap.len() as u64 * 8;
+    probes(key_hash, bits, count).all(|bit| bitmap[(bit / 8) as usize] & (1 << (bit % 8)) != 0)
+}
+
+// The block of a blocked bloom filter is a cache line.
+const BLOCK_BYTES: usize = 64;
+const BLOCK_BITS: u32 = 512;
+
+/// The block of the key, chosen by the high half of the hash.
+fn block_start(key_hash: u64, blocks: usize) -> usize {
+    // the result is less than "blocks".
+    #[allow(clippy::cast_possible_truncation)]
+    let block = (((key_hash >> 32) * blocks as u64) >> 32) as usize;
+    block * BLOCK_BYTES
+}
+
+/// The probes inside the block, made from the low half of the hash.
+fn block_probes(key_hash: u64, count: u8) -> impl Iterator<Item = u32> {
+    // the high half already chose the block.
+    #[allow(clippy::cast_possible_truncation)]
+    let h = key_hash as u32;
+    let delta = h.rotate_right(17) | 1;
+    (0..u32::from(count)).map(move |i| h.wrapping_add(i.wrapping_mul(delta)) % BLOCK_BITS)
+}
+
+fn build_blocked_bloom(config: BloomConfig, key_hashes: &[u64]) -> Vec<u8> {
+    let (bytes, count) = bloom_size(config, key_hashes.len());
+    let blocks = std::cmp::max(1, (bytes + BLOCK_BYTES / 2) / BLOCK_BYTES);
+    let mut filter = vec![0; blocks * BLOCK_BYTES + 1];
+    for &key_hash in key_hashes {
+        let block = &mut filter[block_start(key_hash, blocks)..];
+        for bit in block_probes(key_hash, count) {
+            block[(bit / 8) as usize] |= 1 << (bit % 8);
+        }
+    }
+    filter[blocks * BLOCK_BYTES] = count;
+    filter
+}
+
+fn blocked_bloom_may_contain(filter: &[u8], key_hash: u64) -> bool {
+    let (count, bitmap) = match filter.split_last() {
+        Some((count, bitmap)) if bitmap.len() >= BLOCK_BYTES => (*count, bitmap),
+        // Broken filters can't rule anything out.
+        _ => return true,
+    };
+    let block = &bitmap[block_start(key_hash, bitmap.len() / BLOCK_BYTES)..];
+    block_probes(key_hash, count).all(|bit| block[(bit / 8) as usize] & (1 << (bit % 8)) != 0)
+}
+
+// The xor filter header: the seed, the segment length and the fingerprint width in bytes.
+const XOR_HEADER: usize = 13;
+const XOR_SEED: u64 = 0x726f_7866_696c_7472;
+
+/// The murmur3 finalizer.
+fn mix(mut h: u64) -> u64 {
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    h ^ (h >> 33)
+}
+
+/// The 3 slots of the hash, one in every segment.
+fn xor_slots(hash: u64, segment: u32) -> [usize; 3] {
+    let reduce = |h: u64, i: u32| {
+        // only the low half is reduced to the segment.
+        #[allow(clippy::cast_possible_truncation)]
+        let h = u64::from(h as u32);
+        // the result is less than 3 segments.
+        #[allow(clippy::cast_possible_truncation)]
+        let slot = (((h * u64::from(segment)) >> 32) + u64::from(i * segment)) as usize;
+        slot
+    };
+    [
+        reduce(hash, 0),
+        reduce(hash.rotate_left(21), 1),
+        reduce(hash.rotate_left(42), 2),
+    ]
+}
+
+fn fingerprint(hash: u64, width: u8) -> u16 {
+    let mask = if width == 1 { 0xff } else { 0xffff };
+    // the fingerprint is masked anyway.
+    #[allow(clippy::cast_possible_truncation)]
+    let fingerprint = (hash ^ (hash >> 32)) as u16;
+    fingerprint & mask
+}
+
+/// Build an xor filter (Graf and Lemire, 2019).
+///
+/// Every key is mapped to 3 slots, and the slots are filled so that the xor of the key's
+/// slots is its fingerprint. It takes 1.23 slots per key, and the fingerprints are 8 bits,
+/// or 16 if "config" asks for more than 12 bits per key. That is less space than a bloom
+/// filter needs for the same false positive rate.
+fn build_xor(config: BloomConfig, key_hashes: &[u64]) -> Result<Vec<u8>> {
+    let width: u8 = if bits_per_key(config) > 12.0 { 2 } else { 1 };
+    // Equal hashes would never get a slot of their own.
+    let mut hashes = key_hashes.to_vec();
+    hashes.sort_unstable();
+    hashes.dedup();
+
+    let segment = u32::try_from((32 + hashes.len() * 123 / 100) / 3)?;
+    let slots = 3 * segment as usize;
+    let mut seed = XOR_SEED;
+    let mut fingerprints = vec![0_u16; slots];
+    loop {
+        let mut xor_mask = vec![0_u64; slots];
+        let mut count = vec![0_u32; slots];
+        for &key_hash in &hashes {
+            let hash = mix(key_hash.wrapping_add(seed));
+            for &slot in &xor_slots(hash, segment) {
+                xor_mask[slot] ^= hash;
+                count[slot] += 1;
+            }
+        }
+        // Peel the keys that are alone in one of their slots, until none are left.
+        let mut queue: Vec<usize> = (0..slots).filter(|&slot| count[slot] == 1).collect();
+        let mut stack = Vec::with_capacity(hashes.len());
+        while let Some(slot) = queue.pop() {
+            if count[slot] != 1 {
+                continue;
+            }
+            let hash = xor_mask[slot];
+            stack.push((hash, slot));
+            for &other in &xor_slots(hash, segment) {
+                xor_mask[other] ^= hash;
+                count[other] -= 1;
+                if count[other] == 1 {
+                    queue.push(other);
+                }
+            }
+        }
+        if stack.len() == hashes.len() {
+            for &(hash, slot) in stack.iter().rev() {
+                let [a, b, c] = xor_slots(hash, segment);
+                fingerprints[slot] =
+                    fingerprint(hash, width) ^ fingerprints[a] ^ fingerprints[b] ^ fingerprints[c];
+            }
+            break;
+        }
+        seed = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    }
+
+    let mut filter = Vec::with_capacity(XOR_HEADER + slots * usize::from(width));
+    filter.extend_from_slice(&seed.to_le_bytes());
+    filter.extend_from_slice(&segment.to_le_bytes());
+    filter.push(width);
+    for fingerprint in fingerprints {
+        filter.extend_from_slice(&fingerprint.to_le_bytes()[..usize::from(width)]);
+    }
+    Ok(filter)
+}
+
+fn xor_may_contain(filter: &[u8], key_hash: u64) -> bool {
+    if filter.len() < XOR_HEADER {
+        return true;
+    }
+    let (header, fingerprints) = filter.split_at(XOR_HEADER);
+    let (mut seed, mut segment) = ([0; 8], [0; 4]);
+    seed.copy_from_slice(&header[..8]);
+    segment.copy_from_slice(&header[8..12]);
+    let (seed, segment, width) = (
+        u64::from_le_bytes(seed),
+        u32::from_le_bytes(segment),
+        header[12],
+    );
+    if (width != 1 && width != 2)
+        || fingerprints.len() as u64 != 3 * u64::from(segment) * u64::from(width)
+    {
+        // Broken filters can't rule anything out.
+        return true;
+    }
+    let get = |slot: usize| match width {
+        1 => u16::from(fingerprints[slot]),
+        _ => u16::from_le_bytes([fingerprints[2 * slot], fingerprints[2 * slot + 1]]),
+    };
+    let hash = mix(key_hash.wrapping_add(seed));
+    let [a, b, c] = xor_slots(hash, segment);
+    get(a) ^ get(b) ^ get(c) == fingerprint(hash, width)
+}
+
+/// The prefix lengths stored in range filters.
+///
+/// Longer prefixes rule out shorter ranges, but take more space. Consecutive keys share
+/// prefixes up to their common prefix, so only the longer ones are stored for every key.
+const LADDER: &[u8] = &[1, 2, 3, 4, 5, 6, 8, 12, 16];
+
+// How many prefixes one range check can probe.
+const MAX_RANGE_PROBES: u8 = 16;
+
+fn common_prefix_length(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+/// Push the range filter items of "key" to "out", given the key before it.
+pub fn push_range_filter_items(previous: &[u8], key: &[u8], out: &mut Vec<u64>) {
+    let common = common_prefix_length(previous, key);
+    for &level in LADDER {
+        let length = usize::from(level);
+        if length > key.len() {
+            break;
+        }
+        // The previous key pushed it already.
+        if length > common {
+            out.push(range_filter_hash(level, &key[..length]));
+        }
+    }
+    out.push(range_filter_hash(0, key));
+}
+
+/// Returns false if no key in the table with the range filter is in the range.
+///
+/// All the keys in the range share the common prefix of its ends, so the longest prefix
+/// of the ladder that fits in it is checked first. If the next prefix of the ladder is one
+/// byte longer, the few prefixes it can take in the range are checked too.
+pub fn range_may_contain(filter: &TableFilter, start: Bound<&[u8]>, end: Bound<&[u8]>) -> bool {
+    let (first, last) = match (start, end) {
+        (Bound::Included(first), Bound::Included(last)) if first == last => {
+            return filter.may_contain(range_filter_hash(0, first));
+        }
+        (Bound::Included(first), Bound::Included(last)) => (first, last),
+        (Bound::Included(first), Bound::Excluded(last))
+        | (Bound::Excluded(first), Bound::Included(last))
+        | (Bound::Excluded(first), Bound::Excluded(last)) => (first, last),
+        // Ranges open on either end share no prefix.
+        _ => return true,
+    };
+    if first >= last {
+        return false;
+    }
+    let common = common_prefix_length(first, last);
+    let prefix = &first[..common];
+    if let Some(&level) = LADDER.iter().rev().find(|&&l| usize::from(l) <= common) {
+        if !filter.may_contain(range_filter_hash(level, &prefix[..usize::from(level)])) {
+            return false;
+        }
+    }
+
+    // "last" is longer than the prefix, as it's past "first".
+    let next_level = match LADDER.iter().find(|&&l| usize::from(l) == common + 1) {
+        Some(&level) => level,
+        None => return true,
+    };
+    let low = first.get(common).copied().unwrap_or(0);
+    let high = last[common];
+    if high - low >= MAX_RANGE_PROBES {
+        return true;
+    }
+    // The prefix itself is in the range if it's the start.
+    if first.len() == common
+        && matches!(start, Bound::Included(_))
+        && filter.may_contain(range_filter_hash(0, prefix))
+    {
+        return true;
+    }
+    let mut probe = prefix.to_vec();
+    probe.push(0);
+    (low..=high).any(|byte| {
+        probe[common] = byte;
+        filter.may_contain(range_filter_hash(next_level, &probe))
+    })
+}
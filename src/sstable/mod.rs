@@ -18,17 +18,26 @@
 use std::collections::BTreeMap;
 use std::path::Path;
 
+mod block_cache;
 mod compress_ctx_writer;
 mod compression;
 mod concurrent_lru;
 mod concurrent_page_cache;
+mod encryption;
+mod env;
 mod error;
+mod filter;
 mod ondisk_format;
 mod options;
 mod page_cache;
 mod posreader;
 mod poswriter;
+mod range_read;
 mod result;
+mod secondary_cache;
+mod sized_cache;
+mod slot_cache;
+mod thread_pool;
 mod types;
 mod utils;
 
@@ -42,9 +51,16 @@ pub use reader::SSTableReader;
 pub use writer::RawSSTableWriter;
 pub use writer::SSTableWriterV2;
 
+pub use block_cache::{BlockCache, BlockCacheStats};
+pub use compression::{Codec, Codecs};
+pub use encryption::{Encryption, EncryptionKey, KeyId, KeyProvider};
+pub use env::{Advice, Env, MappedFile, MemEnv, PosixEnv, RandomAccessFile, WritableFile};
 pub use error::{Error, INVALID_DATA};
 pub use options::*;
+pub use page_cache::MemoryUsage;
+pub use range_read::{RangeRead, RangeReadFile};
 pub use result::Result;
+pub use secondary_cache::SecondaryCache;
 pub use types::*;
 
 /// A convenience function to write a btree map to a file.
@@ -91,6 +107,44 @@ mod tests {
         write_btree_map(&map, filename, Some(options)).unwrap();
     }
 
+    /// Write all the keys of three lowercase letters, each with itself as the value.
+    fn write_sorted_keys(filename: &str, options: &WriteOptions) {
+        let mut writer = SSTableWriterV2::new_with_options(filename, options).unwrap();
+        let mut iter = crate::utils::SortedBytesIterator::new(3, 0).unwrap();
+        while let Some(key) = iter.next() {
+            writer.set(key, key).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    /// Counts the reads of the sstable file it wraps.
+    struct CountedFile {
+        file: std::sync::Arc<dyn RandomAccessFile>,
+        reads: std::sync::atomic::AtomicUsize,
+    }
+
+    impl CountedFile {
+        fn open(filename: &str) -> std::sync::Arc<Self> {
+            std::sync::Arc::new(Self {
+                file: PosixEnv.open(Path::new(filename)).unwrap(),
+                reads: std::sync::atomic::AtomicUsize::new(0),
+            })
+        }
+    }
+
+    impl RandomAccessFile for CountedFile {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+            self.reads.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            self.file.read_at(buf, offset)
+        }
+        fn size(&self) -> std::io::Result<u64> {
+            self.file.size()
+        }
+        fn unique_id(&self) -> std::io::Result<Option<u64>> {
+            self.file.unique_id()
+        }
+    }
+
     fn test_basic_sanity(options: WriteOptions, filename: &str) {
         write_basic_map(filename, options);
         let mut reader =
@@ -143,8 +197,14 @@ mod tests {
         write_basic_map(filename, options);
 
         let reader = reader::MmapUncompressedSSTableReader::new(filename).unwrap();
-        assert_eq!(reader.get(b"foo").unwrap(), Some(b"some foo" as &[u8]));
-        assert_eq!(reader.get(b"bar").unwrap(), Some(b"some bar" as &[u8]));
+        assert_eq!(
+            reader.get(b"foo").unwrap().as_deref(),
+            Some(b"some foo" as &[u8])
+        );
+        assert_eq!(
+            reader.get(b"bar").unwrap().as_deref(),
+            Some(b"some bar" as &[u8])
+        );
         assert_eq!(reader.get(b"foobar").unwrap(), None);
     }
 
@@ -180,10 +240,16 @@ mod tests {
 
         crossbeam::scope(|s| {
             s.spawn(|_| {
-                assert_eq!(reader.get(b"foo").unwrap(), Some(b"some foo" as &[u8]));
+                assert_eq!(
+                    reader.get(b"foo").unwrap().as_deref(),
+                    Some(b"some foo" as &[u8])
+                );
             });
             s.spawn(|_| {
-                assert_eq!(reader.get(b"bar").unwrap(), Some(b"some bar" as &[u8]));
+                assert_eq!(
+                    reader.get(b"bar").unwrap().as_deref(),
+                    Some(b"some bar" as &[u8])
+                );
             });
             s.spawn(|_| {
                 assert_eq!(reader.get(b"foobar").unwrap(), None);
@@ -205,4 +271,1512 @@ mod tests {
         options.compression = Compression::Snappy;
         test_basic_sanity_threads(options, "/tmp/sstable_snappy_threads");
     }
+
+    #[test]
+    fn test_per_section_compression() {
+        let mut options = WriteOptions::default();
+        options.compression = Compression::Snappy;
+        options.index_compression = Some(Compression::None);
+        options.bloom_compression = Some(Compression::None);
+        test_basic_sanity(options.clone(), "/tmp/sstable_snappy_uncompressed_index");
+        test_basic_sanity_threads(options, "/tmp/sstable_snappy_uncompressed_index_threads");
+
+        let filename = "/tmp/sstable_mmap_zlib_index";
+        let mut options = WriteOptions::default();
+        options.index_compression = Some(Compression::Zlib);
+        options.bloom_compression = Some(Compression::Snappy);
+        write_basic_map(filename, options);
+
+        let reader = reader::MmapUncompressedSSTableReader::new(filename).unwrap();
+        assert_eq!(
+            reader.get(b"foo").unwrap().as_deref(),
+            Some(b"some foo" as &[u8])
+        );
+        assert_eq!(
+            reader.get(b"bar").unwrap().as_deref(),
+            Some(b"some bar" as &[u8])
+        );
+        assert_eq!(reader.get(b"foobar").unwrap(), None);
+    }
+
+    #[test]
+    fn test_value_compression() {
+        let filename = "/tmp/sstable_value_compression";
+        let large_value = b"large value ".repeat(100);
+        let mut map: BTreeMap<&[u8], &[u8]> = BTreeMap::new();
+        map.insert(b"large", &large_value);
+        map.insert(b"small", b"small value");
+
+        let uncompressed_filename = "/tmp/sstable_value_compression_none";
+        write_btree_map(&map, uncompressed_filename, None).unwrap();
+
+        let mut options = WriteOptions::default();
+        options.value_compression = Some(ValueCompression {
+            compression: Compression::Zlib,
+            min_length: 64,
+        });
+        write_btree_map(&map, filename, Some(options)).unwrap();
+        let file_len = |filename| std::fs::metadata(filename).unwrap().len();
+        assert!(file_len(filename) < file_len(uncompressed_filename));
+
+        let reader = reader::MmapUncompressedSSTableReader::new(filename).unwrap();
+        assert_eq!(
+            reader.get(b"large").unwrap().as_deref(),
+            Some(&large_value[..])
+        );
+        assert_eq!(
+            reader.get(b"small").unwrap().as_deref(),
+            Some(b"small value" as &[u8])
+        );
+
+        let mut reader = SSTableReader::new(filename).unwrap();
+        assert_eq!(reader.get(b"large").unwrap(), Some(&large_value[..]));
+        assert_eq!(reader.get(b"small").unwrap(), Some(b"small value" as &[u8]));
+
+        let reader = ConcurrentSSTableReader::new(filename).unwrap();
+        assert_eq!(
+            reader.get(b"large").unwrap().as_deref(),
+            Some(&large_value[..])
+        );
+        assert_eq!(
+            reader.get(b"small").unwrap().as_deref(),
+            Some(b"small value" as &[u8])
+        );
+
+        // A corrupt uncompressed length is not allocated.
+        let mut data = std::fs::read(filename).unwrap();
+        let mut header = b"large".to_vec();
+        header.extend_from_slice(&1200_u32.to_le_bytes());
+        let at = data
+            .windows(header.len())
+            .position(|w| w == &header[..])
+            .unwrap()
+            + 5;
+        data[at..at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let reader = reader::MmapUncompressedSSTableReader::from_bytes(data.into()).unwrap();
+        match reader.get(b"large") {
+            Err(Error::InvalidData(_)) => {}
+            other => panic!("expected InvalidData, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_corrupt_chunk_length() {
+        let filename = "/tmp/sstable_corrupt_chunk_length";
+        let mut options = WriteOptions::default();
+        options
+            .compression(Compression::Snappy)
+            .index_compression(Some(Compression::None));
+        let mut writer = SSTableWriterV2::new_with_options(filename, &options).unwrap();
+        writer.set(b"the only key", b"value").unwrap();
+        writer.finish().unwrap();
+
+        // The index entry is followed by the filter offset and length, 0 without per-chunk
+        // filters, and its key.
+        let mut data = std::fs::read(filename).unwrap();
+        let mut entry_end = vec![0; 16];
+        entry_end.extend_from_slice(b"the only key");
+        let at = data
+            .windows(entry_end.len())
+            .position(|w| w == &entry_end[..])
+            .unwrap();
+        data[at - 8..at].copy_from_slice(&u64::MAX.to_le_bytes());
+        std::fs::write(filename, &data).unwrap();
+
+        // A corrupt uncompressed length is not allocated.
+        match SSTableReader::new(filename) {
+            Err(Error::InvalidData(_)) => {}
+            other => panic!("expected InvalidData, got {:?}", other.err()),
+        }
+        match ConcurrentSSTableReader::new(filename) {
+            Err(Error::InvalidData(_)) => {}
+            other => panic!("expected InvalidData, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn test_compressed_many_chunks_small_cache() {
+        let filename = "/tmp/sstable_zlib_many_chunks";
+        let mut options = WriteOptions::default();
+        options.compression = Compression::Zlib;
+        options.flush_every = 256;
+
+        write_sorted_keys(filename, &options);
+        let mut iter = crate::utils::SortedBytesIterator::new(3, 0).unwrap();
+
+        // Bytes(1) is smaller than any chunk.
+        for &(cache, raw_cache) in &[
+            (Some(ReadCache::Blocks(2)), Some(ReadCache::Blocks(2))),
+            (Some(ReadCache::Bytes(1024)), Some(ReadCache::Bytes(1024))),
+            (Some(ReadCache::Bytes(1)), Some(ReadCache::Bytes(1))),
+            (Some(ReadCache::Bytes(1024)), None),
+            (None, Some(ReadCache::Unbounded)),
+        ] {
+            let mut read_options = ReadOptions::default();
+            read_options.cache = cache;
+            read_options.raw_cache = raw_cache;
+            read_options.use_mmap = false;
+
+            let mut reader = SSTableReader::new_with_options(filename, &read_options).unwrap();
+            let concurrent_reader =
+                ConcurrentSSTableReader::new_with_options(filename, &read_options).unwrap();
+            iter.reset();
+            while let Some(key) = iter.next() {
+                assert_eq!(reader.get(key).unwrap(), Some(key));
+                assert_eq!(concurrent_reader.get(key).unwrap().as_deref(), Some(key));
+            }
+            assert_eq!(reader.get(b"zzzz").unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn test_shared_block_cache() {
+        let mut options = WriteOptions::default();
+        options.compression(Compression::Snappy).flush_every(256);
+        let filenames = ["/tmp/sstable_block_cache_a", "/tmp/sstable_block_cache_b"];
+        for (i, filename) in (0_u8..).zip(&filenames) {
+            let mut writer = SSTableWriterV2::new_with_options(filename, &options).unwrap();
+            let mut iter = crate::utils::SortedBytesIterator::new(3, 0).unwrap();
+            while let Some(key) = iter.next() {
+                // The tables have different values at the same offsets.
+                writer.set(key, &[key, &[i]].concat()).unwrap();
+            }
+            writer.finish().unwrap();
+        }
+
+        for cache in &[ReadCache::Bytes(4096), ReadCache::Unbounded] {
+            let mut read_options = ReadOptions::default();
+            read_options
+                .block_cache(Some(BlockCache::with_shards(*cache, CachePolicy::Lru, 2)))
+                .use_mmap(false);
+            let mut readers: Vec<SSTableReader> = filenames
+                .iter()
+                .map(|filename| SSTableReader::new_with_options(filename, &read_options).unwrap())
+                .collect();
+            let concurrent_readers: Vec<ConcurrentSSTableReader> = filenames
+                .iter()
+                .map(|filename| {
+                    ConcurrentSSTableReader::new_with_options(filename, &read_options).unwrap()
+                })
+                .collect();
+            let mut iter = crate::utils::SortedBytesIterator::new(3, 0).unwrap();
+            while let Some(key) = iter.next() {
+                for (i, (reader, concurrent_reader)) in
+                    (0_u8..).zip(readers.iter_mut().zip(&concurrent_readers))
+                {
+                    let value = [key, &[i]].concat();
+                    assert_eq!(reader.get(key).unwrap(), Some(&value[..]));
+                    assert_eq!(
+                        concurrent_reader.get(key).unwrap().as_deref(),
+                        Some(&value[..])
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_warm_and_pin() {
+        use std::ops::Bound;
+
+        let filename = "/tmp/sstable_warm_and_pin";
+        let mut options = WriteOptions::default();
+        options.compression(Compression::Snappy).flush_every(256);
+        write_sorted_keys(filename, &options);
+
+        // Warming the whole table on open reads every chunk once.
+        let cache = BlockCache::with_shards(ReadCache::Unbounded, CachePolicy::Lru, 1);
+        let mut read_options = ReadOptions::default();
+        read_options
+            .block_cache(Some(cache.clone()))
+            .warm(vec![(Bound::Unbounded, Bound::Unbounded)]);
+        let mut reader = SSTableReader::new_with_options(filename, &read_options).unwrap();
+        let misses = cache.stats().misses;
+        assert!(misses > 1);
+        let mut iter = crate::utils::SortedBytesIterator::new(3, 0).unwrap();
+        while let Some(key) = iter.next() {
+            assert_eq!(reader.get(key).unwrap(), Some(key));
+        }
+        assert_eq!(cache.stats().misses, misses);
+
+        // Pinned chunks survive scans through a small cache.
+        let cache = BlockCache::with_shards(ReadCache::Blocks(2), CachePolicy::Lru, 1);
+        let mut read_options = ReadOptions::default();
+        read_options
+            .block_cache(Some(cache.clone()))
+            .pin(vec![(Bound::Unbounded, Bound::Included(b"aaa".to_vec()))]);
+        let mut reader = SSTableReader::new_with_options(filename, &read_options).unwrap();
+        let file = CountedFile::open(filename);
+        let mut read_options = ReadOptions::default();
+        read_options
+            .cache(Some(ReadCache::Blocks(2)))
+            .raw_cache(None)
+            .thread_buckets(Some(1));
+        let concurrent_reader =
+            ConcurrentSSTableReader::from_file(file.clone(), &read_options).unwrap();
+        concurrent_reader
+            .pin(Bound::Included(b"zzz"), Bound::Unbounded)
+            .unwrap();
+        assert_eq!(
+            reader
+                .range(Bound::Unbounded, Bound::Unbounded)
+                .unwrap()
+                .count(),
+            concurrent_reader
+                .range(Bound::Unbounded, Bound::Unbounded)
+                .unwrap()
+                .count()
+        );
+        let misses = cache.stats().misses;
+        assert_eq!(reader.get(b"aaa").unwrap(), Some(&b"aaa"[..]));
+        assert_eq!(cache.stats().misses, misses);
+
+        // The pinned chunk is served without reading the file, the others were evicted by
+        // the scan.
+        let reads = file.reads.load(std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(
+            concurrent_reader.get(b"zzz").unwrap().as_deref(),
+            Some(&b"zzz"[..])
+        );
+        assert_eq!(file.reads.load(std::sync::atomic::Ordering::SeqCst), reads);
+        assert_eq!(
+            concurrent_reader.get(b"aaa").unwrap().as_deref(),
+            Some(&b"aaa"[..])
+        );
+        assert!(file.reads.load(std::sync::atomic::Ordering::SeqCst) > reads);
+    }
+
+    #[test]
+    fn test_slot_cache() {
+        use std::sync::atomic::Ordering;
+
+        let filename = "/tmp/sstable_slot_cache";
+        let (codec, codecs) = CountingCodec::write_sorted_keys(filename);
+        let chunks = codec.compressed.load(Ordering::SeqCst);
+        assert!(chunks > 100);
+
+        for cache in &[
+            ReadCache::Blocks(4),
+            ReadCache::Bytes(4096),
+            ReadCache::Unbounded,
+        ] {
+            let mut read_options = ReadOptions::default();
+            read_options
+                .cache(Some(*cache))
+                .slot_cache(true)
+                .codecs(codecs.clone());
+            let reader =
+                ConcurrentSSTableReader::new_with_options(filename, &read_options).unwrap();
+            codec.uncompressed.store(0, Ordering::SeqCst);
+            crossbeam::scope(|s| {
+                for _ in 0..4 {
+                    s.spawn(|_| {
+                        let mut iter = crate::utils::SortedBytesIterator::new(3, 0).unwrap();
+                        while let Some(key) = iter.next() {
+                            assert_eq!(reader.get(key).unwrap().as_deref(), Some(key));
+                        }
+                    });
+                }
+            })
+            .unwrap();
+            // The threads look up the same chunks at about the same time, and the chunks
+            // that stay in the cache are only uncompressed by one of them.
+            let uncompressed = codec.uncompressed.load(Ordering::SeqCst);
+            if let ReadCache::Unbounded = cache {
+                assert_eq!(uncompressed, chunks);
+            } else {
+                assert!(uncompressed >= chunks);
+            }
+            assert_eq!(reader.get(b"aab!").unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn test_block_cache_threads() {
+        use std::sync::atomic::Ordering;
+
+        let filename = "/tmp/sstable_block_cache_threads";
+        let (codec, codecs) = CountingCodec::write_sorted_keys(filename);
+        let chunks = codec.compressed.load(Ordering::SeqCst);
+
+        let cache = BlockCache::with_shards(ReadCache::Unbounded, CachePolicy::Lru, 2);
+        let mut read_options = ReadOptions::default();
+        read_options.block_cache(Some(cache)).codecs(codecs);
+        let reader = ConcurrentSSTableReader::new_with_options(filename, &read_options).unwrap();
+        codec.uncompressed.store(0, Ordering::SeqCst);
+        crossbeam::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|_| {
+                    let mut iter = crate::utils::SortedBytesIterator::new(3, 0).unwrap();
+                    while let Some(key) = iter.next() {
+                        assert_eq!(reader.get(key).unwrap().as_deref(), Some(key));
+                    }
+                });
+            }
+        })
+        .unwrap();
+        // The threads miss the same chunks at about the same time, and wait for one of
+        // them to uncompress each.
+        assert_eq!(codec.uncompressed.load(Ordering::SeqCst), chunks);
+    }
+
+    #[test]
+    fn test_block_cache_budget() {
+        use bytes::Bytes;
+
+        for blocks in 1..=20 {
+            let cache = BlockCache::with_shards(ReadCache::Blocks(blocks), CachePolicy::Lru, 16);
+            for offset in 0..100 {
+                let chunk = cache
+                    .get_or_insert(0, offset, || Ok(Bytes::from(vec![0; 10])))
+                    .unwrap();
+                assert_eq!(chunk.len(), 10);
+            }
+            assert_eq!(cache.memory_usage(), blocks * 10);
+        }
+    }
+
+    #[test]
+    fn test_concurrent_lru_memory_usage() {
+        use concurrent_lru::ConcurrentLRUCache;
+
+        // The memory usage doesn't wait for the values being filled.
+        let cache = ConcurrentLRUCache::new(1, Some(ReadCache::Bytes(4096)), CachePolicy::Lru);
+        cache
+            .get_or_insert(0, 10, |buf| {
+                assert_eq!(cache.memory_usage(), 0);
+                buf.copy_from_slice(b"0123456789");
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(cache.memory_usage(), 10);
+    }
+
+    #[test]
+    fn test_secondary_cache() {
+        use std::sync::atomic::Ordering;
+
+        let filename = "/tmp/sstable_secondary_cache";
+        let dir = "/tmp/sstable_secondary_cache_dir";
+        let _ = std::fs::remove_dir_all(dir);
+        let mut options = WriteOptions::default();
+        options.compression(Compression::Zlib).flush_every(256);
+        write_sorted_keys(filename, &options);
+
+        // The memory caches keep a chunk, so the others come from the secondary cache the
+        // second time, or from the sstable if the secondary cache wrapped around.
+        let file = CountedFile::open(filename);
+        for capacity in &[1 << 20, 1024] {
+            let cache = SecondaryCache::open(dir, *capacity).unwrap();
+            let mut read_options = ReadOptions::default();
+            read_options
+                .cache(Some(ReadCache::Blocks(1)))
+                .raw_cache(Some(ReadCache::Blocks(1)))
+                .secondary_cache(Some(cache));
+            let mut reader = SSTableReader::from_file(file.clone(), &read_options).unwrap();
+            let concurrent_reader =
+                ConcurrentSSTableReader::from_file(file.clone(), &read_options).unwrap();
+            let mut read_all = || {
+                file.reads.store(0, Ordering::SeqCst);
+                let mut iter = crate::utils::SortedBytesIterator::new(3, 0).unwrap();
+                while let Some(key) = iter.next() {
+                    assert_eq!(reader.get(key).unwrap(), Some(key));
+                    assert_eq!(concurrent_reader.get(key).unwrap().as_deref(), Some(key));
+                }
+                file.reads.load(Ordering::SeqCst)
+            };
+            assert!(read_all() > 0);
+            let reads = read_all();
+            if *capacity == 1 << 20 {
+                assert_eq!(reads, 0);
+                // The secondary cache fails to read the chunks once its file is gone, and
+                // they come from the sstable again.
+                std::fs::OpenOptions::new()
+                    .write(true)
+                    .open(Path::new(dir).join("chunks"))
+                    .unwrap()
+                    .set_len(0)
+                    .unwrap();
+                assert!(read_all() > 0);
+            } else {
+                assert!(reads > 0);
+            }
+        }
+
+        // The chunks survive reopening the cache, unless they are corrupted.
+        let cache = SecondaryCache::open(dir, 1024).unwrap();
+        cache.put(1, 2, b"chunk").unwrap();
+        drop(cache);
+        let cache = SecondaryCache::open(dir, 1024).unwrap();
+        let mut buf = [0; 5];
+        assert!(cache.get(1, 2, &mut buf).unwrap());
+        assert_eq!(&buf, b"chunk");
+        assert!(!cache.get(1, 3, &mut buf).unwrap());
+        let chunks = std::fs::OpenOptions::new()
+            .write(true)
+            .open(std::path::Path::new(dir).join("chunks"))
+            .unwrap();
+        std::os::unix::fs::FileExt::write_all_at(&chunks, &[0; 1024], 0).unwrap();
+        assert!(!cache.get(1, 2, &mut buf).unwrap());
+    }
+
+    #[test]
+    fn test_memory_usage() {
+        let filename = "/tmp/sstable_memory_usage";
+        let mut options = WriteOptions::default();
+        options.compression(Compression::Snappy).flush_every(256);
+        write_sorted_keys(filename, &options);
+
+        let mut read_options = ReadOptions::default();
+        read_options
+            .cache(Some(ReadCache::Unbounded))
+            .use_mmap(false);
+        let mut reader = SSTableReader::new_with_options(filename, &read_options).unwrap();
+        let concurrent_reader =
+            ConcurrentSSTableReader::new_with_options(filename, &read_options).unwrap();
+        let usage = reader.memory_usage();
+        assert!(usage.index > 0);
+        assert_eq!(usage.filters, 0);
+        assert_eq!(usage.uncompressed_cache, 0);
+
+        let mut iter = crate::utils::SortedBytesIterator::new(3, 0).unwrap();
+        while let Some(key) = iter.next() {
+            assert_eq!(reader.get(key).unwrap(), Some(key));
+            assert_eq!(concurrent_reader.get(key).unwrap().as_deref(), Some(key));
+        }
+        for usage in &[reader.memory_usage(), concurrent_reader.memory_usage()] {
+            assert!(usage.filters > 0);
+            // The uncompressed chunks hold at least all the keys and values.
+            assert!(usage.uncompressed_cache > 26 * 26 * 26 * 6);
+            assert!(usage.raw_cache > 0);
+            assert!(usage.total() > usage.uncompressed_cache + usage.raw_cache);
+        }
+
+        // The chunks of a shared block cache are not counted by the readers.
+        let cache = BlockCache::new(ReadCache::Unbounded);
+        read_options.block_cache(Some(cache.clone()));
+        let mut reader = SSTableReader::new_with_options(filename, &read_options).unwrap();
+        let mut iter = crate::utils::SortedBytesIterator::new(3, 0).unwrap();
+        while let Some(key) = iter.next() {
+            assert_eq!(reader.get(key).unwrap(), Some(key));
+        }
+        assert!(cache.memory_usage() > 26 * 26 * 26 * 6);
+        assert!(reader.memory_usage().uncompressed_cache < cache.memory_usage());
+    }
+
+    #[test]
+    fn test_mem_env() {
+        // The path is only a name in the env, nothing is written to disk.
+        let filename = "/tmp/sstable_mem_env/does/not/exist";
+        let env = std::sync::Arc::new(MemEnv::new());
+        for compression in &[Compression::None, Compression::Snappy] {
+            let mut options = WriteOptions::default();
+            options
+                .compression(*compression)
+                .flush_every(256)
+                .env(env.clone());
+            write_sorted_keys(filename, &options);
+            assert!(!Path::new(filename).exists());
+
+            for use_mmap in &[true, false] {
+                let mut read_options = ReadOptions::default();
+                read_options.use_mmap(*use_mmap).env(env.clone());
+                let mut reader = SSTableReader::new_with_options(filename, &read_options).unwrap();
+                let concurrent_reader =
+                    ConcurrentSSTableReader::new_with_options(filename, &read_options).unwrap();
+                let mut iter = crate::utils::SortedBytesIterator::new(3, 0).unwrap();
+                while let Some(key) = iter.next() {
+                    assert_eq!(reader.get(key).unwrap(), Some(key));
+                    assert_eq!(concurrent_reader.get(key).unwrap().as_deref(), Some(key));
+                }
+                assert_eq!(reader.get(b"missing").unwrap(), None);
+            }
+        }
+        let mut read_options = ReadOptions::default();
+        read_options.env(env.clone());
+        let reader = MmapUncompressedSSTableReader::new_with_options(filename, &read_options);
+        assert!(matches!(
+            reader,
+            Err(Error::CantUseCompressedFileWithMultiThreadedMmap)
+        ));
+
+        assert!(env.remove(filename));
+        assert!(SSTableReader::new_with_options(filename, &read_options).is_err());
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        for compression in &[Compression::None, Compression::Snappy] {
+            let mut options = WriteOptions::default();
+            options.compression(*compression).flush_every(256);
+            let mut writer = SSTableWriterV2::new_in_memory(&options).unwrap();
+            let mut iter = crate::utils::SortedBytesIterator::new(3, 0).unwrap();
+            while let Some(key) = iter.next() {
+                writer.set(key, key).unwrap();
+            }
+            let data = bytes::Bytes::from(writer.finish_to_vec().unwrap());
+
+            let mut reader = SSTableReader::from_bytes(data.clone()).unwrap();
+            let concurrent_reader = ConcurrentSSTableReader::from_bytes(data.clone()).unwrap();
+            let mmap_reader = match compression {
+                Compression::None => {
+                    Some(MmapUncompressedSSTableReader::from_slice(&data).unwrap())
+                }
+                _ => None,
+            };
+            let mut iter = crate::utils::SortedBytesIterator::new(3, 0).unwrap();
+            while let Some(key) = iter.next() {
+                assert_eq!(reader.get(key).unwrap(), Some(key));
+                assert_eq!(concurrent_reader.get(key).unwrap().as_deref(), Some(key));
+                if let Some(mmap_reader) = mmap_reader.as_ref() {
+                    assert_eq!(mmap_reader.get(key).unwrap().as_deref(), Some(key));
+                }
+            }
+        }
+
+        let writer = SSTableWriterV2::new("/tmp/sstable_from_bytes").unwrap();
+        assert!(matches!(
+            writer.finish_to_vec(),
+            Err(Error::ProgrammingError(_))
+        ));
+    }
+
+    #[test]
+    fn test_range_read() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::sync::Arc;
+
+        // Counts the bytes fetched from the file it wraps.
+        struct Counted {
+            file: std::fs::File,
+            bytes: Arc<AtomicU64>,
+        }
+
+        impl RangeRead for Counted {
+            fn read_range(&self, offset: u64, length: u64) -> std::io::Result<Vec<u8>> {
+                self.bytes.fetch_add(length, Ordering::SeqCst);
+                self.file.read_range(offset, length)
+            }
+            fn size(&self) -> std::io::Result<u64> {
+                RangeRead::size(&self.file)
+            }
+        }
+
+        let filename = "/tmp/sstable_range_read";
+        let mut options = WriteOptions::default();
+        options.compression(Compression::Snappy).flush_every(256);
+        write_sorted_keys(filename, &options);
+
+        let bytes = Arc::new(AtomicU64::new(0));
+        let open = || {
+            let file = Counted {
+                file: std::fs::File::open(filename).unwrap(),
+                bytes: bytes.clone(),
+            };
+            Arc::new(RangeReadFile::new(file, 64 * 1024))
+        };
+        let file = open();
+        let mut reader = SSTableReader::from_file(file.clone(), &ReadOptions::default()).unwrap();
+        assert_eq!(reader.get(b"abc").unwrap(), Some(&b"abc"[..]));
+        // The header, the index and the chunk are only a few requests.
+        let requests = file.requests();
+        assert!(requests <= 3, "{} requests", requests);
+        assert_eq!(reader.get(b"abc").unwrap(), Some(&b"abc"[..]));
+        assert_eq!(file.requests(), requests);
+
+        // A point lookup elsewhere fetches just its chunk, not a whole range.
+        let fetched = bytes.load(Ordering::SeqCst);
+        assert_eq!(reader.get(b"mmm").unwrap(), Some(&b"mmm"[..]));
+        assert_eq!(file.requests(), requests + 1);
+        assert!(bytes.load(Ordering::SeqCst) - fetched < 1024);
+
+        // The hundreds of chunks of a scan are fetched a few at a time.
+        let file = open();
+        let reader =
+            ConcurrentSSTableReader::from_file(file.clone(), &ReadOptions::default()).unwrap();
+        let mut iter = crate::utils::SortedBytesIterator::new(3, 0).unwrap();
+        let range = reader.range(std::ops::Bound::Unbounded, std::ops::Bound::Unbounded);
+        for record in range.unwrap() {
+            let (key, value) = record.unwrap();
+            assert_eq!(Some(&key[..]), iter.next());
+            assert_eq!(key, value);
+        }
+        assert_eq!(iter.next(), None);
+        assert!(file.requests() < 20, "{} requests", file.requests());
+    }
+
+    #[test]
+    fn test_direct_io() {
+        let filename = "/tmp/sstable_direct_io";
+        for compression in &[Compression::None, Compression::Snappy] {
+            let mut options = WriteOptions::default();
+            options.compression(*compression).flush_every(1000);
+            write_sorted_keys(filename, &options);
+
+            let mut read_options = ReadOptions::default();
+            read_options.direct_io(true);
+            let mut reader = SSTableReader::new_with_options(filename, &read_options).unwrap();
+            let concurrent_reader =
+                ConcurrentSSTableReader::new_with_options(filename, &read_options).unwrap();
+            let mut iter = crate::utils::SortedBytesIterator::new(3, 0).unwrap();
+            while let Some(key) = iter.next() {
+                assert_eq!(reader.get(key).unwrap(), Some(key));
+                assert_eq!(concurrent_reader.get(key).unwrap().as_deref(), Some(key));
+            }
+            // The chunks are read into the caches, even if they are not compressed.
+            assert!(reader.memory_usage().raw_cache > 0);
+            assert!(concurrent_reader.memory_usage().raw_cache > 0);
+        }
+
+        // Aligned reads go straight into the buffer, others through an extent around them.
+        let data = std::fs::read(filename).unwrap();
+        let file = PosixEnv.open_direct(Path::new(filename)).unwrap();
+        let mut aligned = vec![0; 3 * 4096];
+        let pad = aligned.as_ptr().align_offset(4096);
+        for (offset, length) in &[(0, 4096), (4096, 8192), (1, 4096), (4095, 10), (8192, 100)] {
+            let buf = &mut aligned[pad..pad + length];
+            file.read_exact_at(buf, *offset as u64).unwrap();
+            assert_eq!(buf, &data[*offset..offset + length]);
+        }
+        let mut buf = vec![0; 100];
+        let offset = data.len() - 50;
+        assert_eq!(file.read_at(&mut buf, offset as u64).unwrap(), 50);
+        assert_eq!(&buf[..50], &data[offset..]);
+    }
+
+    #[test]
+    fn test_access_pattern() {
+        use parking_lot::Mutex;
+        use std::ops::Bound;
+        use std::sync::Arc;
+
+        // Records the hints, and reads from the file it wraps.
+        struct Advised {
+            file: Arc<dyn RandomAccessFile>,
+            advice: Mutex<Vec<Advice>>,
+        }
+
+        impl RandomAccessFile for Advised {
+            fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+                self.file.read_at(buf, offset)
+            }
+            fn size(&self) -> std::io::Result<u64> {
+                self.file.size()
+            }
+            fn advise(&self, _offset: u64, _length: u64, advice: Advice) -> std::io::Result<()> {
+                self.advice.lock().push(advice);
+                Ok(())
+            }
+        }
+
+        let filename = "/tmp/sstable_access_pattern";
+        let mut options = WriteOptions::default();
+        options.compression(Compression::Snappy).flush_every(1000);
+        write_sorted_keys(filename, &options);
+
+        // The OS takes the hints for files on disk and their mmaps.
+        let file = PosixEnv.open(Path::new(filename)).unwrap();
+        let mmap = file.map().unwrap().unwrap();
+        for advice in &[Advice::Random, Advice::WillNeed, Advice::DontNeed] {
+            file.advise(0, file.size().unwrap(), *advice).unwrap();
+            mmap.advise(100, 10000, *advice).unwrap();
+        }
+
+        let file = Arc::new(Advised {
+            file,
+            advice: Mutex::new(Vec::new()),
+        });
+        let mut read_options = ReadOptions::default();
+        read_options.access_pattern(Advice::Random);
+        let reader = ConcurrentSSTableReader::from_file(file.clone(), &read_options).unwrap();
+        assert_eq!(*file.advice.lock(), vec![Advice::Random]);
+
+        reader.warm(Bound::Unbounded, Bound::Unbounded).unwrap();
+        assert_eq!(*file.advice.lock(), vec![Advice::Random, Advice::WillNeed]);
+
+        // Every chunk of the scan is dropped once it's read.
+        file.advice.lock().clear();
+        let mut get_options = GetOptions::default();
+        get_options.fill_cache(false).dont_need(true);
+        let records = reader
+            .range_with_options(Bound::Unbounded, Bound::Unbounded, Some(get_options))
+            .unwrap()
+            .count();
+        assert_eq!(records, 26 * 26 * 26);
+        let advice = file.advice.lock();
+        assert!(advice.len() > 10);
+        assert!(advice.iter().all(|advice| *advice == Advice::DontNeed));
+    }
+
+    #[test]
+    fn test_prefetch() {
+        use std::ops::Bound;
+
+        let filename = "/tmp/sstable_prefetch";
+        for compression in &[Compression::None, Compression::Snappy] {
+            let mut options = WriteOptions::default();
+            options.compression(*compression).flush_every(1000);
+            write_sorted_keys(filename, &options);
+
+            for use_mmap in &[false, true] {
+                let mut read_options = ReadOptions::default();
+                read_options.use_mmap(*use_mmap);
+                let reader =
+                    ConcurrentSSTableReader::new_with_options(filename, &read_options).unwrap();
+                let mut get_options = GetOptions::default();
+                get_options.prefetch(4);
+                let mut scan = reader
+                    .range_with_options(
+                        Bound::Included(b"bcd"),
+                        Bound::Excluded(b"xyz"),
+                        Some(get_options),
+                    )
+                    .unwrap();
+                let mut iter = crate::utils::SortedBytesIterator::new(3, 0).unwrap();
+                while let Some(key) = iter.next() {
+                    if key < &b"bcd"[..] || key >= &b"xyz"[..] {
+                        continue;
+                    }
+                    let (k, v) = scan.next().unwrap().unwrap();
+                    assert_eq!(&k[..], key);
+                    assert_eq!(&v[..], key);
+                }
+                assert!(scan.next().is_none());
+                drop(scan);
+
+                // Scans dropped halfway leave the chunks they requested to the threads.
+                get_options.fill_cache(false);
+                let mut scan = reader
+                    .range_with_options(Bound::Unbounded, Bound::Unbounded, Some(get_options))
+                    .unwrap();
+                assert_eq!(&scan.next().unwrap().unwrap().0[..], b"aaa");
+                drop(scan);
+                drop(reader);
+            }
+        }
+    }
+
+    #[test]
+    fn test_sized_lru() {
+        let mut lru = sized_cache::SizedCache::new(ReadCache::Bytes(100), CachePolicy::Lru);
+        lru.put(1, vec![1_u8; 40], 40);
+        lru.put(2, vec![2_u8; 40], 40);
+        assert!(!lru.is_full(20));
+        assert!(lru.is_full(21));
+        lru.get(&1);
+        lru.put(3, vec![3_u8; 40], 40);
+        // 2 was the least recently used.
+        assert_eq!(lru.peek(&2), None);
+        assert!(lru.peek(&1).is_some());
+
+        // The most recently used value is kept, even over budget.
+        lru.put(4, vec![4_u8; 200], 200);
+        assert_eq!(lru.peek(&4).map(|v| v.len()), Some(200));
+        assert_eq!(lru.peek(&1), None);
+        assert_eq!(lru.peek(&3), None);
+
+        lru.add_size(&4, 10);
+        lru.put(5, vec![], 0);
+        assert_eq!(lru.peek(&4), None);
+        assert!(!lru.is_full(100));
+
+        let mut lru = sized_cache::SizedCache::new(ReadCache::Blocks(2), CachePolicy::Lru);
+        for i in 0..3 {
+            lru.put(i, i, 1000);
+        }
+        assert_eq!(lru.peek(&0), None);
+        assert!(lru.is_full(0));
+    }
+
+    #[test]
+    fn test_cache_policies() {
+        use std::ops::Bound;
+
+        // Hot keys are used over and over, while a scan goes through cold keys once.
+        let hot_hits = |policy| {
+            let mut cache = sized_cache::SizedCache::new(ReadCache::Blocks(100), policy);
+            let mut hits = 0;
+            for round in 0..100_u64 {
+                for key in 0..50 {
+                    match cache.get(&key) {
+                        Some(_) => hits += 1,
+                        None => cache.put(key, (), 1),
+                    }
+                }
+                for key in 0..200 {
+                    let key = 1000 + round * 200 + key;
+                    if cache.get(&key).is_none() {
+                        cache.put(key, (), 1);
+                    }
+                }
+            }
+            hits
+        };
+        let lru_hits = hot_hits(CachePolicy::Lru);
+        let tiny_lfu_hits = hot_hits(CachePolicy::TinyLfu);
+        assert_eq!(lru_hits, 0);
+        assert!(tiny_lfu_hits > 4500, "{} hits", tiny_lfu_hits);
+
+        let filename = "/tmp/sstable_cache_policies";
+        let mut options = WriteOptions::default();
+        options.compression(Compression::Snappy).flush_every(256);
+        write_sorted_keys(filename, &options);
+
+        // A scan that doesn't fill the cache doesn't evict the chunks of point lookups.
+        for &fill_cache in &[true, false] {
+            let cache = BlockCache::with_shards(ReadCache::Bytes(4096), CachePolicy::Lru, 1);
+            let mut read_options = ReadOptions::default();
+            read_options.block_cache(Some(cache.clone()));
+            let mut reader = SSTableReader::new_with_options(filename, &read_options).unwrap();
+            let mut get_options = GetOptions::default();
+            get_options.fill_cache(fill_cache);
+
+            assert!(reader.get(b"aaa").unwrap().is_some());
+            let scanned = reader
+                .range_with_options(Bound::Unbounded, Bound::Unbounded, Some(get_options))
+                .unwrap()
+                .count();
+            assert!(scanned > 1000);
+            let before = cache.stats();
+            assert!(reader.get(b"aaa").unwrap().is_some());
+            let hit = cache.stats().hits > before.hits;
+            assert_eq!(hit, !fill_cache);
+        }
+    }
+
+    // Stores the data as is, behind a magic prefix to check it went through the codec.
+    struct ToyCodec;
+
+    impl Codec for ToyCodec {
+        fn compress(&self, buf: &[u8], out: &mut Vec<u8>) -> Result<()> {
+            out.extend_from_slice(b"TOY");
+            out.extend_from_slice(buf);
+            Ok(())
+        }
+        fn uncompress_into(&self, buf: &[u8], out: &mut [u8]) -> Result<()> {
+            if !buf.starts_with(b"TOY") || buf.len() - 3 != out.len() {
+                return Err(INVALID_DATA);
+            }
+            out.copy_from_slice(&buf[3..]);
+            Ok(())
+        }
+    }
+
+    // Counts the chunks going through ToyCodec.
+    #[derive(Default)]
+    struct CountingCodec {
+        compressed: std::sync::atomic::AtomicUsize,
+        uncompressed: std::sync::atomic::AtomicUsize,
+    }
+
+    impl CountingCodec {
+        /// Write the sorted keys with only the data chunks going through a CountingCodec.
+        fn write_sorted_keys(filename: &str) -> (std::sync::Arc<Self>, Codecs) {
+            let codec = std::sync::Arc::new(Self::default());
+            let mut codecs = Codecs::new();
+            codecs.register(42, codec.clone());
+            let mut options = WriteOptions::default();
+            options
+                .compression(Compression::Custom(42))
+                .index_compression(Some(Compression::None))
+                .bloom_compression(Some(Compression::None))
+                .codecs(codecs.clone())
+                .flush_every(256);
+            write_sorted_keys(filename, &options);
+            (codec, codecs)
+        }
+    }
+
+    impl Codec for CountingCodec {
+        fn compress(&self, buf: &[u8], out: &mut Vec<u8>) -> Result<()> {
+            self.compressed
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            ToyCodec.compress(buf, out)
+        }
+        fn uncompress_into(&self, buf: &[u8], out: &mut [u8]) -> Result<()> {
+            self.uncompressed
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            ToyCodec.uncompress_into(buf, out)
+        }
+    }
+
+    #[test]
+    fn test_custom_codec() {
+        let filename = "/tmp/sstable_custom_codec";
+        let mut codecs = Codecs::new();
+        codecs.register(42, std::sync::Arc::new(ToyCodec));
+
+        let large_value = b"large value ".repeat(100);
+        let mut map: BTreeMap<&[u8], &[u8]> = BTreeMap::new();
+        map.insert(b"large", &large_value);
+        map.insert(b"small", b"small value");
+
+        let mut options = WriteOptions::default();
+        options
+            .compression(Compression::Custom(42))
+            .value_compression(Some(ValueCompression {
+                compression: Compression::Custom(42),
+                min_length: 64,
+            }))
+            .codecs(codecs.clone());
+        write_btree_map(&map, filename, Some(options)).unwrap();
+
+        let mut read_options = ReadOptions::default();
+        read_options.codecs(codecs);
+        for use_mmap in &[true, false] {
+            read_options.use_mmap(*use_mmap);
+            let mut reader = SSTableReader::new_with_options(filename, &read_options).unwrap();
+            assert_eq!(reader.get(b"large").unwrap(), Some(&large_value[..]));
+            assert_eq!(reader.get(b"small").unwrap(), Some(b"small value" as &[u8]));
+            assert_eq!(reader.get(b"missing").unwrap(), None);
+
+            let reader =
+                ConcurrentSSTableReader::new_with_options(filename, &read_options).unwrap();
+            assert_eq!(
+                reader.get(b"large").unwrap().as_deref(),
+                Some(&large_value[..])
+            );
+            assert_eq!(
+                reader.get(b"small").unwrap().as_deref(),
+                Some(b"small value" as &[u8])
+            );
+        }
+
+        match SSTableReader::new(filename) {
+            Err(Error::UnknownCodec(42)) => {}
+            other => panic!("expected UnknownCodec, got {:?}", other.err()),
+        }
+    }
+
+    struct TestKeys;
+
+    impl KeyProvider for TestKeys {
+        fn key(&self, _id: KeyId) -> Result<EncryptionKey> {
+            Ok([7; 32])
+        }
+    }
+
+    struct WrongKeys;
+
+    impl KeyProvider for WrongKeys {
+        fn key(&self, _id: KeyId) -> Result<EncryptionKey> {
+            Ok([0xff; 32])
+        }
+    }
+
+    #[test]
+    fn test_encryption() {
+        let keys: std::sync::Arc<dyn KeyProvider> = std::sync::Arc::new(TestKeys);
+        for compression in &[Compression::None, Compression::Zlib, Compression::Snappy] {
+            let filename = format!("/tmp/sstable_encrypted_{:?}", compression);
+            let mut options = WriteOptions::default();
+            options
+                .compression(*compression)
+                .flush_every(256)
+                .encryption(Some(Encryption {
+                    key_id: 1,
+                    keys: keys.clone(),
+                }));
+
+            let mut writer = SSTableWriterV2::new_with_options(&filename, &options).unwrap();
+            let mut iter = crate::utils::SortedBytesIterator::new(3, 0).unwrap();
+            while let Some(key) = iter.next() {
+                writer.set(key, b"secret value").unwrap();
+            }
+            writer.finish().unwrap();
+
+            let contents = std::fs::read(&filename).unwrap();
+            assert!(!contents.windows(12).any(|w| w == b"secret value"));
+
+            let mut read_options = ReadOptions::default();
+            read_options
+                .cache(Some(ReadCache::Blocks(2)))
+                .keys(Some(keys.clone()));
+            for use_mmap in &[true, false] {
+                read_options.use_mmap(*use_mmap);
+                let mut reader = SSTableReader::new_with_options(&filename, &read_options).unwrap();
+                let concurrent_reader =
+                    ConcurrentSSTableReader::new_with_options(&filename, &read_options).unwrap();
+                iter.reset();
+                while let Some(key) = iter.next() {
+                    assert_eq!(reader.get(key).unwrap(), Some(b"secret value" as &[u8]));
+                    assert_eq!(
+                        concurrent_reader.get(key).unwrap().as_deref(),
+                        Some(b"secret value" as &[u8])
+                    );
+                }
+                assert_eq!(reader.get(b"zzzz").unwrap(), None);
+            }
+
+            // The secondary cache would keep the chunks in plaintext, so it's not used.
+            let dir = format!("/tmp/sstable_encrypted_{:?}_secondary_cache", compression);
+            let _ = std::fs::remove_dir_all(&dir);
+            let mut read_options = read_options.clone();
+            read_options
+                .cache(Some(ReadCache::Blocks(1)))
+                .raw_cache(Some(ReadCache::Blocks(1)))
+                .use_mmap(false)
+                .secondary_cache(Some(SecondaryCache::open(&dir, 1 << 20).unwrap()));
+            let mut reader = SSTableReader::new_with_options(&filename, &read_options).unwrap();
+            let concurrent_reader =
+                ConcurrentSSTableReader::new_with_options(&filename, &read_options).unwrap();
+            for _ in 0..2 {
+                iter.reset();
+                while let Some(key) = iter.next() {
+                    assert_eq!(reader.get(key).unwrap(), Some(b"secret value" as &[u8]));
+                    assert!(concurrent_reader.get(key).unwrap().is_some());
+                }
+            }
+            let chunks = std::fs::read(Path::new(&dir).join("chunks")).unwrap();
+            assert!(!chunks.windows(12).any(|w| w == b"secret value"));
+
+            match SSTableReader::new(&filename) {
+                Err(Error::EncryptionKeyRequired(1)) => {}
+                other => panic!("expected EncryptionKeyRequired, got {:?}", other.err()),
+            }
+            match MmapUncompressedSSTableReader::new_with_options(&filename, &read_options) {
+                Err(Error::CantUseEncryptedFileWithMultiThreadedMmap)
+                | Err(Error::CantUseCompressedFileWithMultiThreadedMmap) => {}
+                other => panic!("expected an error, got {:?}", other.err()),
+            }
+
+            // A wrong key fails to authenticate the index.
+            read_options.keys(Some(std::sync::Arc::new(WrongKeys)));
+            match SSTableReader::new_with_options(&filename, &read_options) {
+                Err(Error::InvalidData(_)) => {}
+                other => panic!("expected InvalidData, got {:?}", other.err()),
+            }
+
+            // A plaintext table is not read as if it was encrypted.
+            options.encryption(None);
+            let mut writer = SSTableWriterV2::new_with_options(&filename, &options).unwrap();
+            writer.set(b"foo", b"plaintext value").unwrap();
+            writer.finish().unwrap();
+            read_options.keys(Some(keys.clone()));
+            match ConcurrentSSTableReader::new_with_options(&filename, &read_options) {
+                Err(Error::NotEncrypted) => {}
+                other => panic!("expected NotEncrypted, got {:?}", other.err()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_bloom_sized_from_keys() {
+        let file_len = |filename| std::fs::metadata(filename).unwrap().len();
+
+        let filename = "/tmp/sstable_bloom_small";
+        write_basic_map(filename, WriteOptions::default());
+        assert!(file_len(filename) < 1024);
+
+        let mut options = WriteOptions::default();
+        options.write_bloom(false);
+        write_sorted_keys("/tmp/sstable_bloom_none", &options);
+
+        let filename = "/tmp/sstable_bloom_fp_rate";
+        options
+            .write_bloom(true)
+            .bloom(BloomConfig::FalsePositiveRate(0.001));
+        write_sorted_keys(filename, &options);
+        let count = 26 * 26 * 26;
+
+        // The tables only differ in the bloom section, about 1.8 bytes per key for 0.1%
+        // false positives.
+        let bloom_len = file_len(filename) - file_len("/tmp/sstable_bloom_none");
+        assert!(bloom_len > count * 17 / 10, "{}", bloom_len);
+        assert!(bloom_len < count * 19 / 10, "{}", bloom_len);
+
+        let reader = ConcurrentSSTableReader::new(filename).unwrap();
+        let mut iter = crate::utils::SortedBytesIterator::new(3, 0).unwrap();
+        while let Some(key) = iter.next() {
+            assert_eq!(reader.get(key).unwrap().as_deref(), Some(key));
+        }
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let mut data = ondisk_format::MAGIC.to_vec();
+        data.extend_from_slice(&[3, 0, 1, 0]);
+        data.extend_from_slice(&[0; 256]);
+        match SSTableReader::from_bytes(data.into()) {
+            Err(Error::UnsupportedVersion(version)) => {
+                assert_eq!(format!("{:?}", version), "Version { major: 3, minor: 1 }")
+            }
+            other => panic!("expected UnsupportedVersion, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn test_invalid_bloom_config() {
+        let filename = "/tmp/sstable_invalid_bloom_config";
+        let configs = [
+            BloomConfig::FalsePositiveRate(0.0),
+            BloomConfig::FalsePositiveRate(1.0),
+            BloomConfig::FalsePositiveRate(1.5),
+            BloomConfig::FalsePositiveRate(f64::NAN),
+            BloomConfig::BitsPerKey(0.0),
+            BloomConfig::BitsPerKey(f64::INFINITY),
+        ];
+        for config in &configs {
+            let mut options = WriteOptions::default();
+            options.bloom(*config);
+            match SSTableWriterV2::new_with_options(filename, &options) {
+                Err(Error::InvalidOptions(_)) => {}
+                other => panic!("{:?} was accepted: {:?}", config, other.err()),
+            }
+            assert!(SSTableWriterV2::new_in_memory(&options).is_err());
+        }
+    }
+
+    #[test]
+    fn test_tiny_false_positive_rate() {
+        let hashes: Vec<u64> = (0..1000_u32)
+            .map(|i| ondisk_format::bloom_key_hash(&i.to_be_bytes()))
+            .collect();
+        // The bits per key are capped, rather than overflowing the size of the filter.
+        let config = BloomConfig::FalsePositiveRate(1e-300);
+        for filter_type in &[FilterType::Bloom, FilterType::BlockedBloom, FilterType::Xor] {
+            let filter = filter::build(*filter_type, config, &hashes).unwrap();
+            assert!(filter.len() < 1000 * 64 / 8 + 4096);
+        }
+
+        let mut options = WriteOptions::default();
+        options.bloom(config).bloom_per_chunk(true);
+        let mut writer = SSTableWriterV2::new_in_memory(&options).unwrap();
+        writer.set(b"foo", b"bar").unwrap();
+        let data = writer.finish_to_vec().unwrap();
+        let mut reader = SSTableReader::from_bytes(data.into()).unwrap();
+        assert_eq!(reader.get(b"foo").unwrap(), Some(b"bar" as &[u8]));
+    }
+
+    #[test]
+    fn test_chunk_filter_false_positives() {
+        let hashes: Vec<u64> = (0..1000_u32)
+            .map(|i| ondisk_format::bloom_key_hash(&i.to_be_bytes()))
+            .collect();
+        let filter = filter::build_chunk_filter(BloomConfig::BitsPerKey(10.0), &hashes);
+        assert!(hashes
+            .iter()
+            .all(|&hash| filter::chunk_filter_may_contain(&filter, hash)));
+        let false_positives = (1000..11000_u32)
+            .map(|i| ondisk_format::bloom_key_hash(&i.to_be_bytes()))
+            .filter(|&hash| filter::chunk_filter_may_contain(&filter, hash))
+            .count();
+        assert!(false_positives < 200, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn test_bloom_per_chunk() {
+        let keys: std::sync::Arc<dyn KeyProvider> = std::sync::Arc::new(TestKeys);
+        for encryption in &[None, Some(Encryption { key_id: 1, keys })] {
+            let filename = format!("/tmp/sstable_bloom_per_chunk_{}", encryption.is_some());
+            let mut options = WriteOptions::default();
+            options
+                .bloom_per_chunk(true)
+                .flush_every(256)
+                .encryption(encryption.clone());
+            write_sorted_keys(&filename, &options);
+            let mut iter = crate::utils::SortedBytesIterator::new(3, 0).unwrap();
+
+            let mut read_options = ReadOptions::default();
+            read_options
+                .cache(Some(ReadCache::Blocks(2)))
+                .keys(encryption.as_ref().map(|e| e.keys.clone()));
+            for use_mmap in &[true, false] {
+                read_options.use_mmap(*use_mmap);
+                let mut reader = SSTableReader::new_with_options(&filename, &read_options).unwrap();
+                let concurrent_reader =
+                    ConcurrentSSTableReader::new_with_options(&filename, &read_options).unwrap();
+                iter.reset();
+                while let Some(key) = iter.next() {
+                    assert_eq!(reader.get(key).unwrap(), Some(key));
+                    assert_eq!(concurrent_reader.get(key).unwrap().as_deref(), Some(key));
+                    let mut missing = key.to_vec();
+                    missing.push(0);
+                    assert_eq!(reader.get(&missing).unwrap(), None);
+                    assert_eq!(concurrent_reader.get(&missing).unwrap(), None);
+                }
+            }
+
+            // With a block cache, the filters are kept there, so that looking up missing
+            // keys again finds them.
+            let block_cache = BlockCache::new(ReadCache::Unbounded);
+            read_options
+                .use_mmap(false)
+                .block_cache(Some(block_cache.clone()));
+            let mut reader = SSTableReader::new_with_options(&filename, &read_options).unwrap();
+            let concurrent_reader =
+                ConcurrentSSTableReader::new_with_options(&filename, &read_options).unwrap();
+            let mut lookups = 0;
+            for _ in 0..2 {
+                iter.reset();
+                while let Some(key) = iter.next() {
+                    let mut missing = key.to_vec();
+                    missing.push(0);
+                    assert_eq!(reader.get(&missing).unwrap(), None);
+                    assert_eq!(concurrent_reader.get(&missing).unwrap(), None);
+                    lookups += 2;
+                }
+            }
+            assert!(block_cache.stats().hits >= lookups / 2);
+            assert!(block_cache.memory_usage() > 0);
+
+            if encryption.is_none() {
+                let reader = MmapUncompressedSSTableReader::new(&filename).unwrap();
+                iter.reset();
+                while let Some(key) = iter.next() {
+                    assert_eq!(reader.get(key).unwrap().as_deref(), Some(key));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_without_bloom() {
+        let file_len = |filename: &str| std::fs::metadata(filename).unwrap().len();
+        for bloom_per_chunk in &[false, true] {
+            let filename = format!("/tmp/sstable_without_bloom_{}", bloom_per_chunk);
+            let mut options = WriteOptions::default();
+            options
+                .write_bloom(false)
+                .bloom_per_chunk(*bloom_per_chunk)
+                .flush_every(256);
+            write_sorted_keys(&filename, &options);
+
+            let with_bloom = format!("/tmp/sstable_with_bloom_{}", bloom_per_chunk);
+            options.write_bloom(true);
+            write_sorted_keys(&with_bloom, &options);
+            assert!(file_len(&filename) < file_len(&with_bloom));
+
+            for use_bloom in &[true, false] {
+                let mut read_options = ReadOptions::default();
+                read_options.use_bloom(*use_bloom);
+                let mut reader = SSTableReader::new_with_options(&filename, &read_options).unwrap();
+                let mmap_reader = MmapUncompressedSSTableReader::new(&filename).unwrap();
+                assert!(reader.may_contain_prefix(b"anything").unwrap());
+                let mut iter = crate::utils::SortedBytesIterator::new(3, 0).unwrap();
+                while let Some(key) = iter.next() {
+                    assert_eq!(reader.get(key).unwrap(), Some(key));
+                    assert_eq!(mmap_reader.get(key).unwrap().as_deref(), Some(key));
+                    let mut missing = key.to_vec();
+                    missing.push(0);
+                    assert_eq!(reader.get(&missing).unwrap(), None);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_prefix_bloom_and_scans() {
+        use std::ops::Bound;
+
+        let groups = ["apple", "banana", "cherry"];
+        let keys: Vec<Vec<u8>> = groups
+            .iter()
+            .flat_map(|group| (0..200).map(move |i| format!("{}:{:04}", group, i)))
+            .map(String::into_bytes)
+            .collect();
+        let banana: Vec<&Vec<u8>> = keys.iter().filter(|k| k.starts_with(b"banana:")).collect();
+
+        for bloom_per_chunk in &[false, true] {
+            let filename = format!("/tmp/sstable_prefix_bloom_{}", bloom_per_chunk);
+            let mut options = WriteOptions::default();
+            options
+                .bloom(BloomConfig::BitsPerKey(20.0))
+                .bloom_per_chunk(*bloom_per_chunk)
+                .flush_every(256)
+                .prefix_extractor(Some(PrefixExtractor::Delimiter(b':')));
+            let mut writer = SSTableWriterV2::new_with_options(&filename, &options).unwrap();
+            for key in &keys {
+                writer.set(key, key).unwrap();
+            }
+            writer.finish().unwrap();
+
+            let mut reader = SSTableReader::new(&filename).unwrap();
+            assert!(reader.may_contain_prefix(b"banana:").unwrap());
+            assert!(!reader.may_contain_prefix(b"durian:").unwrap());
+            // Nothing can be extracted from these, so they can't be ruled out.
+            assert!(reader.may_contain_prefix(b"ban").unwrap());
+            assert!(reader.may_contain_prefix(b"dur").unwrap());
+
+            let found: Vec<(Vec<u8>, Vec<u8>)> = reader
+                .prefix(b"banana:")
+                .unwrap()
+                .map(|kv| kv.unwrap())
+                .collect();
+            assert_eq!(found.len(), banana.len());
+            for ((key, value), expected) in found.iter().zip(&banana) {
+                assert_eq!(key, *expected);
+                assert_eq!(value, *expected);
+            }
+            assert_eq!(reader.prefix(b"durian:").unwrap().count(), 0);
+            let range = reader.range(
+                Bound::Excluded(&b"apple:0099"[..]),
+                Bound::Included(&b"banana:0009"[..]),
+            );
+            let range = range.unwrap();
+            assert_eq!(range.count(), 110);
+            assert_eq!(
+                reader
+                    .range(Bound::Unbounded, Bound::Unbounded)
+                    .unwrap()
+                    .count(),
+                keys.len()
+            );
+
+            let reader = ConcurrentSSTableReader::new(&filename).unwrap();
+            assert!(!reader.may_contain_prefix(b"durian:").unwrap());
+            let found: Vec<bytes::Bytes> = reader
+                .prefix(b"banana:")
+                .unwrap()
+                .map(|kv| kv.unwrap().0)
+                .collect();
+            assert_eq!(found, banana);
+            assert_eq!(
+                reader
+                    .range(Bound::Included(&b"cherry:0150"[..]), Bound::Unbounded)
+                    .unwrap()
+                    .count(),
+                50
+            );
+
+            let reader = MmapUncompressedSSTableReader::new(&filename).unwrap();
+            assert!(!reader.may_contain_prefix(b"durian:").unwrap());
+            let found: Vec<&[u8]> = reader
+                .prefix(b"banana:")
+                .unwrap()
+                .map(|kv| kv.unwrap().0)
+                .collect();
+            assert_eq!(found, banana);
+            assert_eq!(
+                reader
+                    .range(Bound::Unbounded, Bound::Excluded(&b"apple:0010"[..]))
+                    .unwrap()
+                    .count(),
+                10
+            );
+        }
+    }
+
+    #[test]
+    fn test_filter_types() {
+        let hashes: Vec<u64> = (0..10_000_u32)
+            .map(|i| ondisk_format::bloom_key_hash(&i.to_be_bytes()))
+            .collect();
+        let missing: Vec<u64> = (10_000..110_000_u32)
+            .map(|i| ondisk_format::bloom_key_hash(&i.to_be_bytes()))
+            .collect();
+        // At most this many of the 100 000 missing keys may pass the filter.
+        for &(filter_type, bits_per_key, max_false_positives) in &[
+            (FilterType::Bloom, 10.0, 1500),
+            (FilterType::BlockedBloom, 10.0, 2000),
+            (FilterType::Xor, 10.0, 600),
+            (FilterType::Xor, 16.0, 50),
+        ] {
+            let config = BloomConfig::BitsPerKey(bits_per_key);
+            let filter = filter::build(filter_type, config, &hashes).unwrap();
+            assert!(hashes
+                .iter()
+                .all(|&hash| filter::may_contain(filter_type, &filter, hash)));
+            let false_positives = missing
+                .iter()
+                .filter(|&&hash| filter::may_contain(filter_type, &filter, hash))
+                .count();
+            assert!(
+                false_positives < max_false_positives,
+                "{:?}: {} false positives",
+                filter_type,
+                false_positives
+            );
+        }
+
+        for filter_type in &[FilterType::BlockedBloom, FilterType::Xor] {
+            for bloom_per_chunk in &[false, true] {
+                let filename = format!("/tmp/sstable_filter_{:?}_{}", filter_type, bloom_per_chunk);
+                let mut options = WriteOptions::default();
+                options
+                    .filter_type(*filter_type)
+                    .bloom_per_chunk(*bloom_per_chunk)
+                    .flush_every(256);
+                write_sorted_keys(&filename, &options);
+
+                let mut reader = SSTableReader::new(&filename).unwrap();
+                let mmap_reader = MmapUncompressedSSTableReader::new(&filename).unwrap();
+                let mut iter = crate::utils::SortedBytesIterator::new(3, 0).unwrap();
+                while let Some(key) = iter.next() {
+                    assert_eq!(reader.get(key).unwrap(), Some(key));
+                    assert_eq!(mmap_reader.get(key).unwrap().as_deref(), Some(key));
+                    let mut missing = key.to_vec();
+                    missing.push(0);
+                    assert_eq!(reader.get(&missing).unwrap(), None);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_range_filter() {
+        use std::ops::Bound;
+
+        let keys: Vec<Vec<u8>> = (0..5000)
+            .map(|i| format!("{:08}", i * 10).into_bytes())
+            .collect();
+        for filter_type in &[FilterType::Bloom, FilterType::Xor] {
+            let filename = format!("/tmp/sstable_range_filter_{:?}", filter_type);
+            let mut options = WriteOptions::default();
+            options.filter_type(*filter_type).range_filter(true);
+            let mut writer = SSTableWriterV2::new_with_options(&filename, &options).unwrap();
+            for key in &keys {
+                writer.set(key, key).unwrap();
+            }
+            writer.finish().unwrap();
+
+            let mut reader = SSTableReader::new(&filename).unwrap();
+            let mmap_reader = MmapUncompressedSSTableReader::new(&filename).unwrap();
+            let mut ruled_out = 0;
+            for i in 0..1000 {
+                let start = format!("{:08}", i * 10);
+                let end = format!("{:08}", i * 10 + 9);
+                let (start, end) = (start.as_bytes(), end.as_bytes());
+                // Ranges with keys are never ruled out.
+                assert!(reader
+                    .may_contain_range(Bound::Included(start), Bound::Excluded(end))
+                    .unwrap());
+                assert_eq!(
+                    reader
+                        .range(Bound::Included(start), Bound::Included(end))
+                        .unwrap()
+                        .count(),
+                    1
+                );
+                assert!(mmap_reader
+                    .may_contain_range(Bound::Included(start), Bound::Unbounded)
+                    .unwrap());
+
+                // Keys end with a 0, so there are none between.
+                let (empty_start, empty_end) = ([&start[..7], b"1"].concat(), end);
+                if !reader
+                    .may_contain_range(Bound::Included(&empty_start), Bound::Included(empty_end))
+                    .unwrap()
+                {
+                    ruled_out += 1;
+                }
+                assert_eq!(
+                    reader
+                        .range(Bound::Included(&empty_start), Bound::Included(empty_end))
+                        .unwrap()
+                        .count(),
+                    0
+                );
+            }
+            assert!(
+                ruled_out > 800,
+                "{:?}: {} ruled out",
+                filter_type,
+                ruled_out
+            );
+            assert!(!reader
+                .may_contain_range(Bound::Included(b"00000005"), Bound::Included(b"00000005"))
+                .unwrap());
+        }
+    }
 }
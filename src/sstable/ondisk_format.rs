@@ -13,11 +13,39 @@
 //!
 //! | KVLength | key: [u8] | value: [u8] |
 //!
+//! If the value is flagged as compressed in KVLength, it's stored as
+//!
+//! | uncompressed_length: ValueLength | compressed value: [u8] |
+//!
 //! V1 index data has the following layout
 //!
-//! | KVOffset | key: [u8] | offset: Offset |
+//! | KVOffset | key: [u8] |
+//!
+//! where KVOffset holds the offset of the chunk, its uncompressed length, and the location
+//! of its filter.
+//!
+//! V3.2 files are laid out as
+//!
+//! | MAGIC | Version | MetaV3_2 | DATA | FILTERS | INDEX_DATA | BLOOM | RANGE_FILTER |
+//!
+//! Either FILTERS or BLOOM is empty, depending on whether the table has filters per chunk
+//! (see the `filter` module) or a single bloom filter. Both are empty if the table was
+//! written without bloom filters. RANGE_FILTER is empty unless the
+//! table was written with a range filter.
+//!
+//! The bloom filter is BloomV3_0::bitmap_bytes long. With FilterType::Bloom it's the
+//! bitmap of the `bloomfilter` crate, other filter types are in the format of the `filter`
+//! module. The items in it are not the keys, but their hashes, see `bloom_key_hash`, and
+//! the hashes of their prefixes if the table has a prefix extractor, see `bloom_prefix_hash`.
+//!
+//! Version 3.1 was only written by development versions, with layouts that changed under
+//! the same number, so it's not read. Neither is 3.0, the records and the index entries
+//! of which had no room for what V3.2 stores in them.
+
+use std::hash::Hasher;
 
 use serde::{Deserialize, Serialize};
+use siphasher::sip::SipHasher13;
 
 /// The resulting sstable files MUST have this prefix.
 pub const MAGIC: &[u8] = b"\x80LSM";
@@ -27,31 +55,37 @@ pub type Offset = u64;
 
 use super::error::{Error, INVALID_DATA};
 use super::result::Result;
-use super::types::Compression;
+use super::types::{Compression, FilterType, PrefixExtractor};
 use super::utils::deserialize_from_eof_is_ok;
 use core::mem::size_of;
 use std::cmp::{Ord, Ordering};
 use std::convert::TryFrom;
 use std::io::{Read, Write};
+use std::ops::Range;
 
 pub use super::types::Version;
 
+/// Set in KVLength flags if the value was compressed on its own.
+pub const FLAG_VALUE_COMPRESSED: u8 = 1;
+
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct KVLength {
     pub key_length: KeyLength,
     pub value_length: ValueLength,
+    pub flags: u8,
 }
 
 impl KVLength {
-    pub fn new(k: usize, v: usize) -> Result<Self> {
+    pub fn new(k: usize, v: usize, flags: u8) -> Result<Self> {
         Ok(Self {
             key_length: KeyLength::try_from(k).map_err(|_| Error::KeyTooLong(k))?,
             value_length: ValueLength::try_from(v).map_err(|_| Error::ValueTooLong(v))?,
+            flags,
         })
     }
     pub const fn encoded_size() -> usize {
         // can't use sizeof Self as bincode has no padding while the struct might.
-        size_of::<KeyLength>() + size_of::<ValueLength>()
+        size_of::<KeyLength>() + size_of::<ValueLength>() + size_of::<u8>()
     }
     pub fn serialize_into<W: Write>(&self, w: W) -> Result<()> {
         Ok(bincode::serialize_into(w, self)?)
@@ -62,18 +96,28 @@ impl KVLength {
 pub struct KVOffset {
     pub key_length: KeyLength,
     pub offset: Offset,
+    // The length of the chunk starting at "offset" once uncompressed.
+    // Readers use this to allocate exactly-sized buffers for uncompression.
+    pub uncompressed_length: u64,
+    // The filter of the chunk in the filter section, if the table has per-chunk filters.
+    // Otherwise the length is 0.
+    pub filter_offset: Offset,
+    pub filter_length: u64,
 }
 
 impl KVOffset {
-    pub fn new(k: usize, offset: Offset) -> Result<Self> {
+    pub fn new(k: usize, offset: Offset, uncompressed_length: u64) -> Result<Self> {
         Ok(Self {
             key_length: KeyLength::try_from(k).map_err(|_| Error::KeyTooLong(k))?,
             offset,
+            uncompressed_length,
+            filter_offset: 0,
+            filter_length: 0,
         })
     }
     pub const fn encoded_size() -> usize {
         // can't use sizeof Self as bincode has no padding while the struct might.
-        size_of::<KeyLength>() + size_of::<Offset>()
+        size_of::<KeyLength>() + 2 * size_of::<Offset>() + 2 * size_of::<u64>()
     }
     pub fn deserialize_from_eof_is_ok<R: Read>(r: R) -> Result<Option<Self>> {
         Ok(deserialize_from_eof_is_ok(r)?)
@@ -83,65 +127,160 @@ impl KVOffset {
     }
 }
 
-#[derive(Serialize, Deserialize, Default, Debug)]
+/// The hash of a key, as it's stored in the bloom filter.
+///
+/// The writer only learns how many keys there are at the end, so it collects these
+/// hashes to size the filter. The hash keys are fixed, as they are not stored.
+pub fn bloom_key_hash(key: &[u8]) -> u64 {
+    let mut hasher = SipHasher13::new_with_keys(0x7373_7462_626c_6f6f, 0x6d5f_6b65_795f_6861);
+    hasher.write(key);
+    hasher.finish()
+}
+
+/// The hash of a key prefix, as it's stored in the bloom filter.
+///
+/// It's different from `bloom_key_hash`, so that prefixes don't make false positives
+/// for keys equal to them.
+pub fn bloom_prefix_hash(prefix: &[u8]) -> u64 {
+    let mut hasher = SipHasher13::new_with_keys(0x7373_7462_7072_6566, 0x6978_5f68_6173_6800);
+    hasher.write(prefix);
+    hasher.finish()
+}
+
+/// The hash of a key prefix of "level" bytes, as it's stored in the range filter.
+///
+/// Level 0 is the whole key.
+pub fn range_filter_hash(level: u8, prefix: &[u8]) -> u64 {
+    let mut hasher = SipHasher13::new_with_keys(0x7373_7462_7261_6e67, 0x655f_6c61_6464_6572);
+    hasher.write_u8(level);
+    hasher.write(prefix);
+    hasher.finish()
+}
+
+/// The parameters of the bloom filter, chosen by the writer from the number of keys.
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
 pub struct BloomV3_0 {
     pub bitmap_bytes: u32,
     pub k_num: u32,
     pub sip_keys: [(u64, u64); 2],
 }
 
-#[derive(Serialize, Deserialize, Default, Debug)]
-pub struct MetaV3_0 {
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct MetaV3_2 {
     pub data_len: u64,
+    // The per-chunk filters are between the data and the index.
+    pub filter_len: u64,
     pub index_len: u64,
+    // Custom codecs uncompress the index at once, so they need to know its length.
+    pub index_uncompressed_len: u64,
     pub bloom_len: u64,
+    pub range_filter_len: u64,
     pub items: u64,
+    // Compression of the data chunks.
     pub compression: Compression,
+    pub index_compression: Compression,
+    pub bloom_compression: Compression,
+    // Compression of the values flagged with FLAG_VALUE_COMPRESSED.
+    pub value_compression: Compression,
+    // The longest of the values flagged with FLAG_VALUE_COMPRESSED, once uncompressed.
+    // Readers don't uncompress values into larger buffers.
+    pub max_value_len: u64,
+    // The longest of the data chunks, once uncompressed. Readers don't uncompress chunks
+    // into larger buffers.
+    pub max_chunk_len: u64,
     // updating this field is done as the last step.
     // it's presence indicates that the file is good.
     pub finished: bool,
     pub checksum: u32,
     pub bloom: BloomV3_0,
+    // Set if the chunks, the index and the bloom filter are encrypted.
+    pub encryption: Option<EncryptionV3_2>,
+    // Set if the prefixes it extracts are in the bloom filters too.
+    pub prefix_extractor: Option<PrefixExtractor>,
+    // The format of the chunk filters, and of the bloom section. A bloom section of
+    // FilterType::Bloom is described by "bloom", others by their own header.
+    pub filter_type: FilterType,
+}
+
+/// Encryption parameters of a table. The key itself is never stored.
+#[derive(Serialize, Deserialize, Default, Debug, Copy, Clone)]
+pub struct EncryptionV3_2 {
+    pub key_id: u32,
+    pub table_nonce: [u8; 16],
+}
+
+/// Where the value was found in the chunk.
+#[derive(Debug, Copy, Clone)]
+pub struct ValueLocation {
+    pub start: usize,
+    pub end: usize,
+    pub flags: u8,
+}
+
+impl ValueLocation {
+    pub fn is_compressed(&self) -> bool {
+        self.flags & FLAG_VALUE_COMPRESSED != 0
+    }
+}
+
+/// Where a record was found in the chunk.
+#[derive(Debug, Clone)]
+pub struct RecordLocation {
+    pub key: Range<usize>,
+    pub value: ValueLocation,
+    // The offset of the next record.
+    pub next: usize,
+}
+
+/// Read the record starting at "offset" of the chunk.
+///
+/// This assumes the chunk was fetched from disk and has V1 ondisk format.
+pub fn read_record(buf: &[u8], offset: usize) -> Result<RecordLocation> {
+    let kvlength = bincode::deserialize::<KVLength>(buf.get(offset..).ok_or(INVALID_DATA)?)?;
+    let key_start = offset + KVLength::encoded_size();
+    let key_end = key_start + kvlength.key_length as usize;
+    let value_end = key_end + kvlength.value_length as usize;
+    if value_end > buf.len() {
+        return Err(INVALID_DATA);
+    }
+    Ok(RecordLocation {
+        key: key_start..key_end,
+        value: ValueLocation {
+            start: key_end,
+            end: value_end,
+            flags: kvlength.flags,
+        },
+        next: value_end,
+    })
 }
 
 /// Find the key in the chunk by scanning sequentially.
 ///
 /// This assumes the chunk was fetched from disk and has V1 ondisk format.
 ///
-/// Returns the start and end index of the value.
+/// Returns the location of the value.
 ///
 /// TODO: this probably belongs in "ondisk" for version V1.
-pub fn find_value_offset_v2(buf: &[u8], key: &[u8]) -> Result<Option<(usize, usize)>> {
-    macro_rules! buf_get {
-        ($x:expr) => {{
-            buf.get($x).ok_or(INVALID_DATA)?
-        }};
-    }
-
-    let kvlen_encoded_size = KVLength::encoded_size();
-
+pub fn find_value_offset_v2(buf: &[u8], key: &[u8]) -> Result<Option<ValueLocation>> {
     let mut offset = 0;
     while offset < buf.len() {
-        let kvlength = bincode::deserialize::<KVLength>(&buf[offset..])?;
-        let (start_key, cursor) = {
-            let key_start = offset + kvlen_encoded_size;
-            let key_end = key_start + kvlength.key_length as usize;
-            (buf_get!(key_start..key_end), key_end)
-        };
-
-        let (start, end) = {
-            let value_end = cursor + kvlength.value_length as usize;
-            (cursor, value_end)
-        };
-        offset = end;
-
-        match start_key.cmp(key) {
-            Ordering::Equal => {
-                return Ok(Some((start, end)));
-            }
+        let record = read_record(buf, offset)?;
+        offset = record.next;
+
+        match buf[record.key].cmp(key) {
+            Ordering::Equal => return Ok(Some(record.value)),
             Ordering::Greater => return Ok(None),
             Ordering::Less => continue,
         }
     }
     Ok(None)
 }
+
+/// Split a value flagged with FLAG_VALUE_COMPRESSED into its uncompressed length
+/// and the compressed bytes.
+pub fn split_compressed_value(buf: &[u8]) -> Result<(usize, &[u8])> {
+    let header_size = size_of::<ValueLength>();
+    let uncompressed_length =
+        bincode::deserialize::<ValueLength>(buf.get(..header_size).ok_or(INVALID_DATA)?)?;
+    Ok((uncompressed_length as usize, &buf[header_size..]))
+}
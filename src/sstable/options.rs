@@ -1,32 +1,112 @@
-use super::types::Compression;
+use super::block_cache::BlockCache;
+use super::compression::Codecs;
+use super::encryption::{Encryption, KeyProvider};
+use super::env::{Advice, Env, PosixEnv};
+use super::error::Error;
+use super::result::Result;
+use super::secondary_cache::SecondaryCache;
+use super::types::{Compression, FilterType, PrefixExtractor};
 
-use lru::LruCache;
+use std::ops::Bound;
+use std::sync::Arc;
 
-// The configuration for the bloom filter.
+/// The configuration for the bloom filter.
+///
+/// The filter is sized when the sstable is finished, from the number of keys in it.
 #[derive(Debug, Copy, Clone)]
-pub struct BloomConfig {
-    pub bitmap_size: u32,
-    pub items_count: usize,
+pub enum BloomConfig {
+    /// How many bits to spend per key. 10 bits per key give about 1% false positives.
+    BitsPerKey(f64),
+    /// The target rate of false positives, e.g. 0.01.
+    FalsePositiveRate(f64),
+}
+
+impl BloomConfig {
+    /// Returns `Error::InvalidOptions` unless the bits per key are positive, and the
+    /// false positive rate is between 0 and 1, both exclusive.
+    pub fn validate(&self) -> Result<()> {
+        let valid = match *self {
+            BloomConfig::BitsPerKey(bits_per_key) => bits_per_key.is_finite() && bits_per_key > 0.0,
+            BloomConfig::FalsePositiveRate(rate) => rate > 0.0 && rate < 1.0,
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(Error::InvalidOptions("invalid bloom config"))
+        }
+    }
 }
 
 impl Default for BloomConfig {
     fn default() -> Self {
-        return Self {
-            bitmap_size: 1_000_000,
-            items_count: 1_000_000,
-        };
+        BloomConfig::BitsPerKey(10.0)
     }
 }
 
-/// Options for writing sstables.
+/// The configuration for compressing large values on their own.
+///
+/// This is useful if the table has a mix of small and large values, and the data
+/// as a whole is not compressed, e.g. to read it with `MmapUncompressedSSTableReader`.
 #[derive(Debug, Copy, Clone)]
+pub struct ValueCompression {
+    /// Compression to use for the values.
+    pub compression: Compression,
+    /// Values this long or longer are compressed, smaller ones are stored as is.
+    pub min_length: usize,
+}
+
+impl Default for ValueCompression {
+    fn default() -> Self {
+        Self {
+            compression: Compression::Snappy,
+            min_length: 1024,
+        }
+    }
+}
+
+/// Options for writing sstables.
+#[derive(Debug, Clone)]
 pub struct WriteOptions {
-    /// Compression to use. The default is None.
+    /// Compression to use for the data. The default is None.
     pub compression: Compression,
+    /// Compression to use for the index. If not set, the data compression is used.
+    ///
+    /// An uncompressed index can be used straight from mmap'ed memory, which saves
+    /// both time to open the sstable, and memory.
+    pub index_compression: Option<Compression>,
+    /// Compression to use for the bloom filter. If not set, the data compression is used.
+    pub bloom_compression: Option<Compression>,
+    /// Compression of individual large values. The default is None.
+    pub value_compression: Option<ValueCompression>,
     /// How often to store the records in the index.
     pub flush_every: usize,
+    /// Write bloom filters. Without them readers can't rule out missing keys or prefixes
+    /// without reading the data, but tables are smaller and faster to write.
+    /// The default is true.
+    pub write_bloom: bool,
     /// Options for the bloom filter.
     pub bloom: BloomConfig,
+    /// The format of the bloom filters. The default is FilterType::Bloom.
+    pub filter_type: FilterType,
+    /// Make a bloom filter per chunk instead of one for the whole table.
+    ///
+    /// The filter of a chunk is checked after the index says which chunk may hold the key,
+    /// and it's read through the page cache. So tables open faster, and only the filters
+    /// in use take memory. The default is false.
+    pub bloom_per_chunk: bool,
+    /// Write a range filter, so that readers can tell if a short key range is empty
+    /// without reading the data, see `SSTableReader::may_contain_range`.
+    /// The default is false.
+    pub range_filter: bool,
+    /// Codecs used by Compression::Custom.
+    pub codecs: Codecs,
+    /// If set, the data, the index and the bloom filter are encrypted with this key.
+    pub encryption: Option<Encryption>,
+    /// If set, the prefixes of the keys are put into the bloom filters too, so that
+    /// prefix scans can skip tables and chunks without them. The default is None.
+    pub prefix_extractor: Option<PrefixExtractor>,
+    /// Where the sstable is written. The default is `PosixEnv`, files on local disk.
+    pub env: Arc<dyn Env>,
 }
 
 impl WriteOptions {
@@ -37,22 +117,77 @@ impl WriteOptions {
         self.compression = compression;
         self
     }
+    pub fn index_compression(&mut self, compression: Option<Compression>) -> &mut Self {
+        self.index_compression = compression;
+        self
+    }
+    pub fn bloom_compression(&mut self, compression: Option<Compression>) -> &mut Self {
+        self.bloom_compression = compression;
+        self
+    }
+    pub fn value_compression(&mut self, value_compression: Option<ValueCompression>) -> &mut Self {
+        self.value_compression = value_compression;
+        self
+    }
     pub fn flush_every(&mut self, flush_every: usize) -> &mut Self {
         self.flush_every = flush_every;
         self
     }
+    pub fn write_bloom(&mut self, write_bloom: bool) -> &mut Self {
+        self.write_bloom = write_bloom;
+        self
+    }
     pub fn bloom(&mut self, bloom: BloomConfig) -> &mut Self {
         self.bloom = bloom;
         self
     }
+    pub fn filter_type(&mut self, filter_type: FilterType) -> &mut Self {
+        self.filter_type = filter_type;
+        self
+    }
+    pub fn bloom_per_chunk(&mut self, bloom_per_chunk: bool) -> &mut Self {
+        self.bloom_per_chunk = bloom_per_chunk;
+        self
+    }
+    pub fn range_filter(&mut self, range_filter: bool) -> &mut Self {
+        self.range_filter = range_filter;
+        self
+    }
+    pub fn codecs(&mut self, codecs: Codecs) -> &mut Self {
+        self.codecs = codecs;
+        self
+    }
+    pub fn encryption(&mut self, encryption: Option<Encryption>) -> &mut Self {
+        self.encryption = encryption;
+        self
+    }
+    pub fn prefix_extractor(&mut self, prefix_extractor: Option<PrefixExtractor>) -> &mut Self {
+        self.prefix_extractor = prefix_extractor;
+        self
+    }
+    pub fn env(&mut self, env: Arc<dyn Env>) -> &mut Self {
+        self.env = env;
+        self
+    }
 }
 
 impl Default for WriteOptions {
     fn default() -> Self {
         WriteOptions {
             compression: Compression::None,
+            index_compression: None,
+            bloom_compression: None,
+            value_compression: None,
             flush_every: 4096,
+            write_bloom: true,
             bloom: BloomConfig::default(),
+            filter_type: FilterType::default(),
+            bloom_per_chunk: false,
+            range_filter: false,
+            codecs: Codecs::default(),
+            encryption: None,
+            prefix_extractor: None,
+            env: Arc::new(PosixEnv),
         }
     }
 }
@@ -62,20 +197,25 @@ impl Default for WriteOptions {
 pub enum ReadCache {
     // How many chunks(blocks) to store in LRU.
     Blocks(usize),
+    // How many bytes of chunks to store in LRU. Chunks vary in size with compression
+    // and "flush_every", so this bounds the memory better than Blocks.
+    //
+    // Thread-safe readers split the budget between their buckets.
+    Bytes(usize),
     // Unbounded cache, the default.
     Unbounded,
 }
 
-impl ReadCache {
-    pub fn lru<K, V>(&self) -> LruCache<K, V>
-    where
-        K: std::cmp::Eq + std::hash::Hash,
-    {
-        match self {
-            Self::Blocks(b) => LruCache::new(*b),
-            Self::Unbounded => LruCache::unbounded(),
-        }
-    }
+/// How the caches choose the chunks to evict.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum CachePolicy {
+    /// Evict the least recently used chunks, the default.
+    #[default]
+    Lru,
+    /// W-TinyLFU. New chunks go to a small LRU window, and make it to the main cache only
+    /// if they were used more often recently than the chunks they would evict there.
+    /// A scan through the table doesn't evict the chunks that are used often.
+    TinyLfu,
 }
 
 impl Default for ReadCache {
@@ -84,12 +224,25 @@ impl Default for ReadCache {
     }
 }
 
+/// A range of keys, from the start bound to the end bound.
+///
+/// `(Bound::Unbounded, Bound::Unbounded)` is the whole sstable.
+pub type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
 /// Options for reading sstables.
-#[derive(Copy, Clone, Debug)]
+#[derive(Clone, Debug)]
 pub struct ReadOptions {
     /// The caching strategy to use.
+    ///
+    /// For compressed sstables, this caches the uncompressed chunks.
     pub cache: Option<ReadCache>,
-    /// If mmap can be used for reading the sstable from disk.
+    /// The caching strategy for compressed chunks as they are read from disk, before
+    /// they are uncompressed. Not used for uncompressed sstables.
+    pub raw_cache: Option<ReadCache>,
+    /// How the caches, including "raw_cache", choose the chunks to evict.
+    pub cache_policy: CachePolicy,
+    /// If mmap can be used for reading the sstable from disk. Files that "env" can't map
+    /// are read without it.
     pub use_mmap: bool,
     /// How many buckets to split the caches into for efficient
     /// thread-safe access.
@@ -98,6 +251,42 @@ pub struct ReadOptions {
     // This has a performance penalty for positive lookups,
     // but if you have a lot of maybe-negative, it should make things faster.
     pub use_bloom: bool,
+    /// Codecs used by Compression::Custom. Opening an sstable that uses a codec
+    /// missing here fails with Error::UnknownCodec.
+    pub codecs: Codecs,
+    /// Keys for encrypted sstables. Opening an encrypted sstable without them fails
+    /// with Error::EncryptionKeyRequired, and opening an unencrypted one with them fails
+    /// with Error::NotEncrypted, so that a plaintext file can't pass for an encrypted one.
+    pub keys: Option<Arc<dyn KeyProvider>>,
+    /// A cache shared with other readers. If set, it's used instead of the reader's own
+    /// caches, which are bounded by "cache".
+    pub block_cache: Option<BlockCache>,
+    /// Key ranges whose chunks are read into the caches when the sstable is opened, so
+    /// that the first reads don't go to disk. See `SSTableReader::warm`.
+    pub warm: Vec<KeyRange>,
+    /// Key ranges whose chunks are read into the caches when the sstable is opened, and
+    /// never evicted. See `SSTableReader::pin`.
+    pub pin: Vec<KeyRange>,
+    /// If set, `ConcurrentSSTableReader` keeps the chunks bounded by "cache" in a slot per
+    /// chunk, evicting them with the CLOCK algorithm instead of "cache_policy".
+    /// Cache hits take no locks, which helps with many threads. Not used with "block_cache".
+    pub slot_cache: bool,
+    /// A cache on local disk, where compressed sstables keep their chunks uncompressed,
+    /// to not uncompress them again once they are evicted from the memory caches.
+    /// Encrypted sstables don't use it, so that their chunks are never on disk in plaintext.
+    pub secondary_cache: Option<SecondaryCache>,
+    /// Where the sstable is read from. The default is `PosixEnv`, files on local disk.
+    pub env: Arc<dyn Env>,
+    /// Read the sstable around the OS page cache, with O_DIRECT for `PosixEnv`, so that
+    /// the caches of the readers are the only copy of the chunks in memory. This helps
+    /// with sstables much larger than memory. Reads cover whole 4096 byte pages, and mmap
+    /// is not used. Not used by `MmapUncompressedSSTableReader`. The default is false.
+    pub direct_io: bool,
+    /// How the sstable is going to be read, passed on to the OS when it's opened, for the
+    /// mmap and the file. `Advice::Random` stops readahead for point lookups,
+    /// `Advice::Sequential` reads ahead more for scans, and `Advice::WillNeed` reads the
+    /// whole sstable in the background. The default is `Advice::Normal`.
+    pub access_pattern: Advice,
 }
 
 impl ReadOptions {
@@ -108,6 +297,14 @@ impl ReadOptions {
         self.cache = cache;
         self
     }
+    pub fn raw_cache(&mut self, raw_cache: Option<ReadCache>) -> &mut Self {
+        self.raw_cache = raw_cache;
+        self
+    }
+    pub fn cache_policy(&mut self, cache_policy: CachePolicy) -> &mut Self {
+        self.cache_policy = cache_policy;
+        self
+    }
     pub fn use_mmap(&mut self, use_mmap: bool) -> &mut Self {
         self.use_mmap = use_mmap;
         self
@@ -120,15 +317,67 @@ impl ReadOptions {
         self.thread_buckets = thread_buckets;
         self
     }
+    pub fn codecs(&mut self, codecs: Codecs) -> &mut Self {
+        self.codecs = codecs;
+        self
+    }
+    pub fn keys(&mut self, keys: Option<Arc<dyn KeyProvider>>) -> &mut Self {
+        self.keys = keys;
+        self
+    }
+    pub fn block_cache(&mut self, block_cache: Option<BlockCache>) -> &mut Self {
+        self.block_cache = block_cache;
+        self
+    }
+    pub fn warm(&mut self, warm: Vec<KeyRange>) -> &mut Self {
+        self.warm = warm;
+        self
+    }
+    pub fn pin(&mut self, pin: Vec<KeyRange>) -> &mut Self {
+        self.pin = pin;
+        self
+    }
+    pub fn slot_cache(&mut self, slot_cache: bool) -> &mut Self {
+        self.slot_cache = slot_cache;
+        self
+    }
+    pub fn secondary_cache(&mut self, secondary_cache: Option<SecondaryCache>) -> &mut Self {
+        self.secondary_cache = secondary_cache;
+        self
+    }
+    pub fn env(&mut self, env: Arc<dyn Env>) -> &mut Self {
+        self.env = env;
+        self
+    }
+    pub fn direct_io(&mut self, direct_io: bool) -> &mut Self {
+        self.direct_io = direct_io;
+        self
+    }
+    pub fn access_pattern(&mut self, access_pattern: Advice) -> &mut Self {
+        self.access_pattern = access_pattern;
+        self
+    }
 }
 
 impl Default for ReadOptions {
     fn default() -> Self {
         Self {
             cache: Some(ReadCache::default()),
+            raw_cache: Some(ReadCache::default()),
+            cache_policy: CachePolicy::default(),
             use_mmap: true,
             thread_buckets: Some(num_cpus::get()),
             use_bloom: true,
+            codecs: Codecs::default(),
+            keys: None,
+            block_cache: None,
+            warm: Vec::new(),
+            pin: Vec::new(),
+            slot_cache: false,
+            secondary_cache: None,
+            env: Arc::new(PosixEnv),
+            direct_io: false,
+            access_pattern: Advice::Normal,
         }
     }
 }
@@ -139,6 +388,18 @@ pub struct GetOptions {
     /// Set this if you want to use the bloom filter to speed
     /// up negative lookups at a cost for positive lookup.
     pub use_bloom: bool,
+    /// Unset this to not cache the chunks read, e.g. for scans, so that they don't evict
+    /// the chunks other reads need. Cached chunks are still used.
+    pub fill_cache: bool,
+    /// Set this for scans to tell the OS that the chunks read are not needed anymore
+    /// once the scan moves past them, so that they are dropped from the page cache
+    /// rather than the pages other reads need. Usually set with "fill_cache" unset.
+    pub dont_need: bool,
+    /// How many chunks scans of `ConcurrentSSTableReader` read and uncompress ahead, on
+    /// a pool of background threads, while the current chunk is consumed. This keeps
+    /// scans of compressed sstables from stalling on every chunk. 0, the default, reads
+    /// each chunk when the scan gets to it. Lookups and `SSTableReader` ignore it.
+    pub prefetch: usize,
 }
 
 impl GetOptions {
@@ -149,10 +410,27 @@ impl GetOptions {
         self.use_bloom = use_bloom;
         self
     }
+    pub fn fill_cache(&mut self, fill_cache: bool) -> &mut Self {
+        self.fill_cache = fill_cache;
+        self
+    }
+    pub fn dont_need(&mut self, dont_need: bool) -> &mut Self {
+        self.dont_need = dont_need;
+        self
+    }
+    pub fn prefetch(&mut self, prefetch: usize) -> &mut Self {
+        self.prefetch = prefetch;
+        self
+    }
 }
 
 impl Default for GetOptions {
     fn default() -> Self {
-        Self { use_bloom: true }
+        Self {
+            use_bloom: true,
+            fill_cache: true,
+            dont_need: false,
+            prefetch: 0,
+        }
     }
 }
@@ -1,13 +1,65 @@
 //! Traits for caching the results of uncompression and reading disk pages.
 
 use std::convert::TryFrom;
-use std::io::{Read, Seek, SeekFrom};
+use std::sync::Arc;
 
+use bytes::Bytes;
+
+use super::block_cache::BlockCache;
 use super::compression::Uncompress;
-use super::options::ReadCache;
+use super::encryption::{TableCipher, TAG_LENGTH};
+use super::env::RandomAccessFile;
+use super::options::{CachePolicy, ReadCache};
+use super::secondary_cache::SecondaryCache;
+use super::sized_cache::{CacheSize, SizedCache};
 use super::{error, Result};
 
-use lru::LruCache;
+/// The location of a chunk in the file, and its length once uncompressed.
+///
+/// For uncompressed tables "length" and "uncompressed_length" are the same, unless the
+/// table is encrypted, in which case "length" includes the authentication tag.
+#[derive(Copy, Clone, Debug)]
+pub struct ChunkHandle {
+    pub offset: u64,
+    pub length: u64,
+    pub uncompressed_length: u64,
+}
+
+/// The memory held by a reader in bytes, see `SSTableReader::memory_usage`.
+///
+/// These are the sizes of the buffers held, not counting the overhead of the allocator and
+/// the maps they are in. Chunks in a shared `BlockCache` are not counted, see
+/// `BlockCache::memory_usage`, nor are the parts of mmap'ed files used directly.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct MemoryUsage {
+    /// The index of the chunks.
+    pub index: usize,
+    /// The table-wide bloom and range filters, once they are loaded.
+    pub filters: usize,
+    /// Chunks as read from the file. These are compressed or encrypted chunks, or the
+    /// chunks of uncompressed sstables that are not mmap'ed.
+    pub raw_cache: usize,
+    /// Decrypted chunks that are still compressed, or of uncompressed sstables.
+    pub decrypted_cache: usize,
+    /// Uncompressed chunks of compressed sstables.
+    pub uncompressed_cache: usize,
+    /// Chunks of `ConcurrentSSTableReader` with `ReadOptions::slot_cache`.
+    pub slot_cache: usize,
+    /// Filters of chunks, see `WriteOptions::bloom_per_chunk`.
+    pub filter_cache: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.index
+            + self.filters
+            + self.raw_cache
+            + self.decrypted_cache
+            + self.uncompressed_cache
+            + self.slot_cache
+            + self.filter_cache
+    }
+}
 
 /// PageCache is something that can get byte chunks of a given length, given an offset.
 ///
@@ -18,10 +70,62 @@ use lru::LruCache;
 /// (or mmap buffer), and another to uncompress that and cache the result.
 /// In the latter case, the outer PageCache wraps the inner one.
 ///
+/// If encryption is used, a DecryptingCache sits between the two.
+///
 /// Note, that this cannot be used concurrently, note the &mut self. For concurrent use,
 /// more complicated concurrent cache can be used, from another file.
 pub trait PageCache {
-    fn get_chunk(&mut self, offset: u64, length: u64) -> Result<&[u8]>;
+    fn get_chunk(&mut self, chunk: ChunkHandle) -> Result<&[u8]>;
+
+    /// Get the chunk like `get_chunk`, but don't cache it if it's not cached already.
+    fn get_chunk_no_fill(&mut self, chunk: ChunkHandle) -> Result<&[u8]> {
+        self.get_chunk(chunk)
+    }
+
+    /// Cache the chunk so that it's never evicted. Only the outermost layer that caches
+    /// chunks pins them, the layers it wraps are read as usual.
+    fn pin_chunk(&mut self, chunk: ChunkHandle) -> Result<()> {
+        self.get_chunk(chunk).map(|_| ())
+    }
+
+    /// Get the chunk in a buffer of its own, without caching it in this layer, for layers
+    /// that cache chunks elsewhere, like `SharedCache`. The layers below cache what they
+    /// read for it if "fill_cache" is set.
+    fn take_chunk(&mut self, chunk: ChunkHandle, fill_cache: bool) -> Result<Bytes> {
+        let chunk = if fill_cache {
+            self.get_chunk(chunk)?
+        } else {
+            self.get_chunk_no_fill(chunk)?
+        };
+        Ok(Bytes::copy_from_slice(chunk))
+    }
+
+    /// Add the memory held by this layer and the ones it wraps to "usage".
+    fn memory_usage(&self, _usage: &mut MemoryUsage) {}
+}
+
+/// Get a buffer of exactly "length" bytes to read or uncompress a chunk into.
+///
+/// If the cache is full, the least recently used chunk is evicted right away and its
+/// allocation is reused, so a cache at capacity does not hit the allocator on misses.
+/// The chunks are accounted for by the size of their allocations, not their length.
+fn take_buffer(cache: &mut SizedCache<u64, Vec<u8>>, length: u64) -> Result<Vec<u8>> {
+    let length = usize::try_from(length)?;
+    let mut buf = if cache.is_full(length) {
+        cache.pop_lru().unwrap_or_default()
+    } else {
+        Vec::new()
+    };
+    buf.clear();
+    buf.resize(length, 0);
+    Ok(buf)
+}
+
+/// Get "buf" resized to "length" bytes, to read or uncompress a chunk that won't be cached.
+fn uncached_buffer(buf: &mut Vec<u8>, length: u64) -> Result<&mut [u8]> {
+    buf.clear();
+    buf.resize(usize::try_from(length)?, 0);
+    Ok(buf)
 }
 
 /// This is used to read from the mmap'ed region. It's a mere proxy to the slice.
@@ -39,67 +143,167 @@ impl StaticBufCache {
 }
 
 impl PageCache for StaticBufCache {
-    fn get_chunk(&mut self, offset: u64, length: u64) -> Result<&[u8]> {
+    fn get_chunk(&mut self, chunk: ChunkHandle) -> Result<&[u8]> {
         // if this was mmaped, there will be no truncation.
         #[allow(clippy::cast_possible_truncation)]
         self.buf
-            .get(offset as usize..(offset + length) as usize)
+            .get(chunk.offset as usize..(chunk.offset + chunk.length) as usize)
+            .ok_or(error::INVALID_DATA)
+    }
+
+    fn take_chunk(&mut self, chunk: ChunkHandle, _fill_cache: bool) -> Result<Bytes> {
+        let buf: &'static [u8] = self.buf;
+        #[allow(clippy::cast_possible_truncation)]
+        buf.get(chunk.offset as usize..(chunk.offset + chunk.length) as usize)
+            .map(Bytes::from_static)
             .ok_or(error::INVALID_DATA)
     }
 }
 
-/// This is used to read from a file (or any seek'able reader).
-pub struct ReadPageCache<R> {
-    reader: R,
-    cache: LruCache<u64, Vec<u8>>,
+/// This is used to read from a file.
+pub struct ReadPageCache {
+    file: Arc<dyn RandomAccessFile>,
+    cache: SizedCache<u64, Vec<u8>>,
+    // The chunk read last, if it was not cached.
+    uncached: Vec<u8>,
 }
 
-impl<R> ReadPageCache<R> {
-    pub fn new(reader: R, cache: ReadCache) -> Self {
+impl ReadPageCache {
+    pub fn new(file: Arc<dyn RandomAccessFile>, cache: ReadCache, policy: CachePolicy) -> Self {
         Self {
-            reader,
-            cache: cache.lru(),
+            file,
+            cache: SizedCache::new(cache, policy),
+            uncached: Vec::new(),
         }
     }
 }
 
-impl<R: Read + Seek> PageCache for ReadPageCache<R> {
-    fn get_chunk(&mut self, offset: u64, length: u64) -> Result<&[u8]> {
-        match self.cache.get(&offset) {
+impl PageCache for ReadPageCache {
+    fn get_chunk(&mut self, chunk: ChunkHandle) -> Result<&[u8]> {
+        match self.cache.get(&chunk.offset) {
+            Some(bytes) => Ok(unsafe { &*(bytes as &[u8] as *const [u8]) }),
+            None => {
+                let mut buf = take_buffer(&mut self.cache, chunk.length)?;
+                self.file.read_exact_at(&mut buf, chunk.offset)?;
+                let size = buf.cache_size();
+                self.cache.put(chunk.offset, buf, size);
+                Ok(self.cache.peek(&chunk.offset).unwrap())
+            }
+        }
+    }
+
+    fn get_chunk_no_fill(&mut self, chunk: ChunkHandle) -> Result<&[u8]> {
+        match self.cache.peek(&chunk.offset) {
             Some(bytes) => Ok(unsafe { &*(bytes as &[u8] as *const [u8]) }),
             None => {
-                let mut buf = vec![0; usize::try_from(length)?];
-                // TODO: this can use pread instead of 2 syscalls.
-                self.reader.seek(SeekFrom::Start(offset))?;
-                self.reader.read_exact(&mut buf)?;
-                self.cache.put(offset, buf);
-                Ok(self.cache.get(&offset).unwrap())
+                let buf = uncached_buffer(&mut self.uncached, chunk.length)?;
+                self.file.read_exact_at(buf, chunk.offset)?;
+                Ok(buf)
             }
         }
     }
+
+    fn pin_chunk(&mut self, chunk: ChunkHandle) -> Result<()> {
+        self.get_chunk(chunk)?;
+        self.cache.pin(&chunk.offset);
+        Ok(())
+    }
+
+    fn take_chunk(&mut self, chunk: ChunkHandle, _fill_cache: bool) -> Result<Bytes> {
+        let mut buf = vec![0; usize::try_from(chunk.length)?];
+        self.file.read_exact_at(&mut buf, chunk.offset)?;
+        Ok(Bytes::from(buf))
+    }
+
+    fn memory_usage(&self, usage: &mut MemoryUsage) {
+        usage.raw_cache += self.cache.memory_usage() + self.uncached.cache_size();
+    }
 }
 
 /// A cache that wraps another one, uncompresses the inner cache's results and
 /// store the uncompressed chunks in the LRU cache inside.
+///
+/// With a secondary cache, the chunks are looked up there before they are uncompressed,
+/// and put there once they are.
 pub struct WrappedCache<PC, U> {
     inner: PC,
-    cache: LruCache<u64, Vec<u8>>,
+    cache: SizedCache<u64, Vec<u8>>,
     uncompress: U,
+    // The chunk uncompressed last, if it was not cached.
+    uncached: Vec<u8>,
+    // The secondary cache, and the key of the table in it.
+    secondary: Option<(SecondaryCache, u64)>,
 }
 
 impl<PC, U> WrappedCache<PC, U> {
-    pub fn new(inner: PC, uncompress: U, cache: ReadCache) -> Self {
+    pub fn new(inner: PC, uncompress: U, cache: ReadCache, policy: CachePolicy) -> Self {
         Self {
             inner,
-            cache: cache.lru(),
+            cache: SizedCache::new(cache, policy),
             uncompress,
+            uncached: Vec::new(),
+            secondary: None,
         }
     }
+
+    /// Use "secondary", a secondary cache and the key of the table in it.
+    pub fn with_secondary_cache(mut self, secondary: Option<(SecondaryCache, u64)>) -> Self {
+        self.secondary = secondary;
+        self
+    }
+}
+
+/// Uncompress "chunk" into "buf", unless the secondary cache has it.
+fn uncompress_chunk<PC, U>(
+    inner: &mut PC,
+    uncompress: &U,
+    secondary: Option<&(SecondaryCache, u64)>,
+    chunk: ChunkHandle,
+    fill_cache: bool,
+    buf: &mut [u8],
+) -> Result<()>
+where
+    PC: PageCache,
+    U: Uncompress,
+{
+    // The secondary cache is best effort, its errors are misses and the chunk is read
+    // from the sstable.
+    if let Some((secondary, table_key)) = secondary {
+        if let Ok(true) = secondary.get(*table_key, chunk.offset, buf) {
+            return Ok(());
+        }
+    }
+    let inner_chunk = if fill_cache {
+        inner.get_chunk(chunk)?
+    } else {
+        inner.get_chunk_no_fill(chunk)?
+    };
+    uncompress.uncompress_into(inner_chunk, buf)?;
+    if let (Some((secondary, table_key)), true) = (secondary, fill_cache) {
+        let _ = secondary.put(*table_key, chunk.offset, buf);
+    }
+    Ok(())
 }
 
 impl PageCache for Box<dyn PageCache> {
-    fn get_chunk(&mut self, offset: u64, length: u64) -> Result<&[u8]> {
-        self.as_mut().get_chunk(offset, length)
+    fn get_chunk(&mut self, chunk: ChunkHandle) -> Result<&[u8]> {
+        self.as_mut().get_chunk(chunk)
+    }
+
+    fn get_chunk_no_fill(&mut self, chunk: ChunkHandle) -> Result<&[u8]> {
+        self.as_mut().get_chunk_no_fill(chunk)
+    }
+
+    fn pin_chunk(&mut self, chunk: ChunkHandle) -> Result<()> {
+        self.as_mut().pin_chunk(chunk)
+    }
+
+    fn take_chunk(&mut self, chunk: ChunkHandle, fill_cache: bool) -> Result<Bytes> {
+        self.as_mut().take_chunk(chunk, fill_cache)
+    }
+
+    fn memory_usage(&self, usage: &mut MemoryUsage) {
+        self.as_ref().memory_usage(usage)
     }
 }
 
@@ -108,15 +312,207 @@ where
     U: Uncompress,
     PC: PageCache,
 {
-    fn get_chunk(&mut self, offset: u64, length: u64) -> Result<&[u8]> {
-        match self.cache.get(&offset) {
+    fn get_chunk(&mut self, chunk: ChunkHandle) -> Result<&[u8]> {
+        match self.cache.get(&chunk.offset) {
             Some(bytes) => Ok(unsafe { &*(bytes as &[u8] as *const [u8]) }),
             None => {
-                let inner_chunk = self.inner.get_chunk(offset, length)?;
-                let buf = self.uncompress.uncompress(inner_chunk)?;
-                self.cache.put(offset, buf);
-                Ok(self.cache.get(&offset).unwrap())
+                let mut buf = take_buffer(&mut self.cache, chunk.uncompressed_length)?;
+                uncompress_chunk(
+                    &mut self.inner,
+                    &self.uncompress,
+                    self.secondary.as_ref(),
+                    chunk,
+                    true,
+                    &mut buf,
+                )?;
+                let size = buf.cache_size();
+                self.cache.put(chunk.offset, buf, size);
+                Ok(self.cache.peek(&chunk.offset).unwrap())
             }
         }
     }
+
+    fn get_chunk_no_fill(&mut self, chunk: ChunkHandle) -> Result<&[u8]> {
+        match self.cache.peek(&chunk.offset) {
+            Some(bytes) => Ok(unsafe { &*(bytes as &[u8] as *const [u8]) }),
+            None => {
+                let buf = uncached_buffer(&mut self.uncached, chunk.uncompressed_length)?;
+                uncompress_chunk(
+                    &mut self.inner,
+                    &self.uncompress,
+                    self.secondary.as_ref(),
+                    chunk,
+                    false,
+                    buf,
+                )?;
+                Ok(buf)
+            }
+        }
+    }
+
+    fn pin_chunk(&mut self, chunk: ChunkHandle) -> Result<()> {
+        self.get_chunk(chunk)?;
+        self.cache.pin(&chunk.offset);
+        Ok(())
+    }
+
+    fn take_chunk(&mut self, chunk: ChunkHandle, fill_cache: bool) -> Result<Bytes> {
+        let mut buf = vec![0; usize::try_from(chunk.uncompressed_length)?];
+        uncompress_chunk(
+            &mut self.inner,
+            &self.uncompress,
+            self.secondary.as_ref(),
+            chunk,
+            fill_cache,
+            &mut buf,
+        )?;
+        Ok(Bytes::from(buf))
+    }
+
+    fn memory_usage(&self, usage: &mut MemoryUsage) {
+        usage.uncompressed_cache += self.cache.memory_usage() + self.uncached.cache_size();
+        self.inner.memory_usage(usage);
+    }
+}
+
+/// A cache that wraps another one, and keeps its results in a BlockCache shared with
+/// other readers.
+///
+/// The chunks are taken from the inner cache with `take_chunk`, so they are only cached
+/// in the BlockCache, and not copied there.
+pub struct SharedCache<PC> {
+    inner: PC,
+    cache: BlockCache,
+    table_id: u64,
+    // The chunk returned last, so it can be returned by reference.
+    chunk: Bytes,
+}
+
+impl<PC> SharedCache<PC> {
+    pub fn new(inner: PC, cache: BlockCache) -> Self {
+        Self {
+            inner,
+            table_id: cache.new_table_id(),
+            cache,
+            chunk: Bytes::new(),
+        }
+    }
+}
+
+impl<PC: PageCache> PageCache for SharedCache<PC> {
+    fn get_chunk(&mut self, chunk: ChunkHandle) -> Result<&[u8]> {
+        let inner = &mut self.inner;
+        self.chunk = self.cache.get_or_insert(self.table_id, chunk.offset, || {
+            inner.take_chunk(chunk, true)
+        })?;
+        Ok(&self.chunk)
+    }
+
+    fn get_chunk_no_fill(&mut self, chunk: ChunkHandle) -> Result<&[u8]> {
+        let inner = &mut self.inner;
+        self.chunk = self.cache.get_or_compute(self.table_id, chunk.offset, || {
+            inner.take_chunk(chunk, false)
+        })?;
+        Ok(&self.chunk)
+    }
+
+    fn pin_chunk(&mut self, chunk: ChunkHandle) -> Result<()> {
+        let inner = &mut self.inner;
+        self.cache.pin(self.table_id, chunk.offset, || {
+            inner.take_chunk(chunk, true)
+        })
+    }
+
+    fn memory_usage(&self, usage: &mut MemoryUsage) {
+        self.inner.memory_usage(usage);
+    }
+}
+
+impl<PC> Drop for SharedCache<PC> {
+    fn drop(&mut self) {
+        self.cache.unpin_table(self.table_id);
+    }
+}
+
+/// Returns the length of the chunk once decrypted.
+pub fn decrypted_length(chunk: &ChunkHandle) -> Result<u64> {
+    chunk
+        .length
+        .checked_sub(TAG_LENGTH as u64)
+        .ok_or(error::INVALID_DATA)
+}
+
+/// A cache that wraps another one, decrypts the inner cache's results and
+/// stores the decrypted chunks in the LRU cache inside.
+///
+/// The decrypted chunks may still be compressed.
+pub struct DecryptingCache<PC> {
+    inner: PC,
+    cache: SizedCache<u64, Vec<u8>>,
+    cipher: TableCipher,
+    // The chunk decrypted last, if it was not cached.
+    uncached: Vec<u8>,
+}
+
+impl<PC> DecryptingCache<PC> {
+    pub fn new(inner: PC, cipher: TableCipher, cache: ReadCache, policy: CachePolicy) -> Self {
+        Self {
+            inner,
+            cache: SizedCache::new(cache, policy),
+            cipher,
+            uncached: Vec::new(),
+        }
+    }
+}
+
+impl<PC: PageCache> PageCache for DecryptingCache<PC> {
+    fn get_chunk(&mut self, chunk: ChunkHandle) -> Result<&[u8]> {
+        match self.cache.get(&chunk.offset) {
+            Some(bytes) => Ok(unsafe { &*(bytes as &[u8] as *const [u8]) }),
+            None => {
+                let mut buf = take_buffer(&mut self.cache, decrypted_length(&chunk)?)?;
+                let inner_chunk = self.inner.get_chunk(chunk)?;
+                self.cipher
+                    .decrypt_into(chunk.offset, inner_chunk, &mut buf)?;
+                let size = buf.cache_size();
+                self.cache.put(chunk.offset, buf, size);
+                Ok(self.cache.peek(&chunk.offset).unwrap())
+            }
+        }
+    }
+
+    fn get_chunk_no_fill(&mut self, chunk: ChunkHandle) -> Result<&[u8]> {
+        match self.cache.peek(&chunk.offset) {
+            Some(bytes) => Ok(unsafe { &*(bytes as &[u8] as *const [u8]) }),
+            None => {
+                let buf = uncached_buffer(&mut self.uncached, decrypted_length(&chunk)?)?;
+                let inner_chunk = self.inner.get_chunk_no_fill(chunk)?;
+                self.cipher.decrypt_into(chunk.offset, inner_chunk, buf)?;
+                Ok(buf)
+            }
+        }
+    }
+
+    fn pin_chunk(&mut self, chunk: ChunkHandle) -> Result<()> {
+        self.get_chunk(chunk)?;
+        self.cache.pin(&chunk.offset);
+        Ok(())
+    }
+
+    fn take_chunk(&mut self, chunk: ChunkHandle, fill_cache: bool) -> Result<Bytes> {
+        let mut buf = vec![0; usize::try_from(decrypted_length(&chunk)?)?];
+        let inner_chunk = if fill_cache {
+            self.inner.get_chunk(chunk)?
+        } else {
+            self.inner.get_chunk_no_fill(chunk)?
+        };
+        self.cipher
+            .decrypt_into(chunk.offset, inner_chunk, &mut buf)?;
+        Ok(Bytes::from(buf))
+    }
+
+    fn memory_usage(&self, usage: &mut MemoryUsage) {
+        usage.decrypted_cache += self.cache.memory_usage() + self.uncached.cache_size();
+        self.inner.memory_usage(usage);
+    }
 }
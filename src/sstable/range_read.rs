@@ -0,0 +1,177 @@
+//! Reading sstables from remote storage, e.g. an object store, with range requests.
+
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use bytes::Bytes;
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+
+use super::env::RandomAccessFile;
+
+/// Storage that reads ranges of bytes of an object, like HTTP range requests do.
+///
+/// Every call is assumed to be a round trip, so `RangeReadFile` makes few large calls
+/// rather than many small ones.
+pub trait RangeRead: Send + Sync {
+    /// Read "length" bytes at "offset". The range is within the object.
+    fn read_range(&self, offset: u64, length: u64) -> io::Result<Vec<u8>>;
+    /// The length of the object in bytes.
+    fn size(&self) -> io::Result<u64>;
+}
+
+/// Local files, mostly for tests.
+impl RangeRead for File {
+    fn read_range(&self, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+        let length =
+            usize::try_from(length).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut buf = vec![0; length];
+        self.read_exact_at(&mut buf, offset)?;
+        Ok(buf)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+}
+
+/// How many of the ranges fetched last `RangeReadFile` keeps, so that concurrent scans
+/// don't evict each other's ranges.
+const RECENT_RANGES: usize = 8;
+
+/// A `RandomAccessFile` reading from a `RangeRead`, that only fetches the parts of the
+/// sstable that are used: the header, the index, the filters and the chunks looked up.
+///
+/// Reads at the start of the file, and reads that continue where one of the last ranges
+/// fetched ended, are sequential: they are rounded up to at least "coalesce" bytes, and
+/// the range is kept to serve the reads that follow. So the small reads of parsing the
+/// header, and the adjacent chunks of range scans, are served by few requests. Other reads,
+/// like the chunks of point lookups, fetch just what they ask for.
+///
+/// That's a trade-off: a point lookup doesn't fetch "coalesce" bytes it won't use, but
+/// costs a request per chunk, and the first chunk of a scan is fetched on its own before
+/// the scan reads ahead. Up to `RECENT_RANGES` ranges of "coalesce" bytes are kept in
+/// memory. The chunks are cached by the readers' page caches as for any file.
+///
+/// Open the readers with `SSTableReader::from_file` and `ConcurrentSSTableReader::from_file`.
+///
+/// Example:
+/// ```
+/// use std::sync::Arc;
+/// use sstb::sstable::*;
+///
+/// let filename = "/tmp/some-sstable-read-in-ranges";
+/// let mut writer = SSTableWriterV2::new(filename).unwrap();
+/// writer.set(b"foo", b"bar").unwrap();
+/// writer.finish().unwrap();
+///
+/// let file = RangeReadFile::new(std::fs::File::open(filename).unwrap(), 64 * 1024);
+/// let mut reader = SSTableReader::from_file(Arc::new(file), &ReadOptions::default()).unwrap();
+/// assert_eq!(reader.get(b"foo").unwrap(), Some(b"bar" as &[u8]));
+/// ```
+pub struct RangeReadFile<R> {
+    source: R,
+    coalesce: u64,
+    size: OnceCell<u64>,
+    // The ranges fetched last, oldest first, see `Fetched`.
+    recent: Mutex<VecDeque<Fetched>>,
+    requests: AtomicU64,
+}
+
+/// A range fetched by `RangeReadFile`. Only the contents of sequential reads are kept, the
+/// others just mark where the next sequential read would start.
+struct Fetched {
+    end: u64,
+    data: Bytes,
+}
+
+impl<R: RangeRead> RangeReadFile<R> {
+    pub fn new(source: R, coalesce: usize) -> Self {
+        Self {
+            source,
+            coalesce: coalesce as u64,
+            size: OnceCell::new(),
+            recent: Mutex::new(VecDeque::with_capacity(RECENT_RANGES)),
+            requests: AtomicU64::new(0),
+        }
+    }
+
+    /// How many ranges were fetched from the source.
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    /// Read from the recent ranges, if one has the byte at "offset".
+    fn read_recent(&self, buf: &mut [u8], offset: u64) -> Option<usize> {
+        let recent = self.recent.lock();
+        recent.iter().rev().find_map(|fetched| {
+            let remaining = usize::try_from(fetched.end.checked_sub(offset)?).ok()?;
+            let available = fetched
+                .data
+                .get(fetched.data.len().checked_sub(remaining)?..)?;
+            Some(copy_prefix(available, buf)).filter(|n| *n > 0)
+        })
+    }
+
+    /// Returns true if a read at "offset" continues one of the recent ranges.
+    fn is_sequential(&self, offset: u64) -> bool {
+        offset == 0
+            || self
+                .recent
+                .lock()
+                .iter()
+                .any(|fetched| fetched.end == offset)
+    }
+
+    fn add_recent(&self, fetched: Fetched) {
+        let mut recent = self.recent.lock();
+        if recent.len() == RECENT_RANGES {
+            recent.pop_front();
+        }
+        recent.push_back(fetched);
+    }
+}
+
+/// Copy as much of "data" as fits into "buf", and return how much that was.
+fn copy_prefix(data: &[u8], buf: &mut [u8]) -> usize {
+    let n = buf.len().min(data.len());
+    buf[..n].copy_from_slice(&data[..n]);
+    n
+}
+
+impl<R: RangeRead> RandomAccessFile for RangeReadFile<R> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        if let Some(n) = self.read_recent(buf, offset) {
+            return Ok(n);
+        }
+        let size = self.size()?;
+        if offset >= size || buf.is_empty() {
+            return Ok(0);
+        }
+        // The lock is not held while fetching, so that threads fetch in parallel.
+        let sequential = self.is_sequential(offset);
+        let wanted = buf.len() as u64;
+        let length = if sequential {
+            wanted.max(self.coalesce)
+        } else {
+            wanted
+        };
+        let length = length.min(size - offset);
+        let data = Bytes::from(self.source.read_range(offset, length)?);
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let n = copy_prefix(&data, buf);
+        self.add_recent(Fetched {
+            end: offset + length,
+            data: if sequential { data } else { Bytes::new() },
+        });
+        Ok(n)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.size.get_or_try_init(|| self.source.size()).copied()
+    }
+}
@@ -13,28 +13,37 @@
 //!
 //!   If yes, use `ConcurrentSSTableReader`. Otherwise, use `SSTableReader`
 
-use std::borrow::Borrow;
-use std::collections::BTreeMap;
+use std::borrow::{Borrow, Cow};
+use std::collections::{BTreeMap, VecDeque};
 use std::convert::TryFrom;
-use std::fs::File;
 use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::ops::Bound;
 use std::path::Path;
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::sync::Arc;
 
 use bincode;
-use memmap;
 use num_cpus;
 
 use bloomfilter::Bloom;
 use bytes::Bytes;
+use once_cell::sync::OnceCell;
 
+use super::block_cache::BlockCache;
+use super::compression::Codecs;
+use super::encryption::TableCipher;
+use super::env::{Advice, FileReader, MappedFile, MemFile, RandomAccessFile};
 use super::error::INVALID_DATA;
 use super::ondisk_format::*;
 use super::options::*;
 use super::types::*;
-use super::{compression, concurrent_page_cache, page_cache, posreader, Error, Result};
+use super::{
+    compression, concurrent_page_cache, filter, page_cache, posreader, secondary_cache, slot_cache,
+    thread_pool, Error, Result,
+};
 
 enum MetaData {
-    V3_0(MetaV3_0),
+    V3_2(MetaV3_2),
 }
 
 struct MetaResult {
@@ -56,9 +65,9 @@ fn read_metadata<B: Read + Seek>(mut file: B) -> Result<MetaResult> {
     }
     let version: Version = bincode::deserialize_from(&mut reader)?;
     let meta = match version {
-        VERSION_30 => {
-            let meta: MetaV3_0 = bincode::deserialize_from(&mut reader)?;
-            MetaData::V3_0(meta)
+        VERSION_32 => {
+            let meta: MetaV3_2 = bincode::deserialize_from(&mut reader)?;
+            MetaData::V3_2(meta)
         }
         _ => return Err(Error::UnsupportedVersion(version)),
     };
@@ -71,33 +80,811 @@ fn read_metadata<B: Read + Seek>(mut file: B) -> Result<MetaResult> {
 }
 
 /// Read the bloom filter from a reader.
-fn read_bloom<R: Read>(mut reader: R, config: &BloomV3_0) -> Result<Bloom<[u8]>> {
+fn read_bloom<R: Read>(mut reader: R, meta: &MetaV3_2) -> Result<filter::TableFilter> {
+    let config = &meta.bloom;
     let len_bytes = usize::try_from(config.bitmap_bytes)?;
     // I don't think there's a way not to do this allocation.
     let mut buf = vec![0u8; len_bytes];
     reader.read_exact(&mut buf)?;
-    Ok(Bloom::from_existing(
+    if meta.filter_type != FilterType::Bloom {
+        return Ok(filter::TableFilter::Encoded(
+            meta.filter_type,
+            Cow::Owned(buf),
+        ));
+    }
+    Ok(filter::TableFilter::Bloom(Bloom::from_existing(
         &buf,
         config.bitmap_bytes as u64 * 8,
         config.k_num,
         config.sip_keys,
-    ))
+    )))
+}
+
+/// The location and compression of the index or the bloom filter section.
+struct Section {
+    start: u64,
+    length: u64,
+    uncompressed_length: u64,
+    compression: Compression,
+}
+
+/// Make a reader of a file section, decrypting and uncompressing it if needed.
+///
+/// The section is read into memory with a single read, rather than a read for every
+/// record deserialized from it. Custom codecs can't stream, so such sections are
+/// uncompressed at once, which is why the uncompressed length is needed.
+fn section_reader<'a>(
+    file: &'a dyn RandomAccessFile,
+    section: Section,
+    codecs: &Codecs,
+    cipher: Option<&TableCipher>,
+) -> Result<Box<dyn Read + 'a>> {
+    let mut buf = vec![0; usize::try_from(section.length)?];
+    file.read_exact_at(&mut buf, section.start)?;
+    if let Some(cipher) = cipher {
+        buf = cipher.decrypt_section(section.start, &buf)?;
+    }
+    let reader = std::io::Cursor::new(buf);
+    Ok(match section.compression {
+        Compression::None => Box::new(reader),
+        Compression::Zlib => Box::new(flate2::read::ZlibDecoder::new(reader)),
+        Compression::Snappy => Box::new(snap::Reader::new(reader)),
+        Compression::Custom(id) => {
+            let codec = codecs.get(id)?;
+            let mut uncompressed = vec![0; usize::try_from(section.uncompressed_length)?];
+            if !uncompressed.is_empty() {
+                codec.uncompress_into(reader.get_ref(), &mut uncompressed)?;
+            }
+            Box::new(std::io::Cursor::new(uncompressed))
+        }
+    })
+}
+
+/// Read the index of the sstable.
+///
+/// If the index is neither compressed nor encrypted, and the file is mmap'ed, the index
+/// keys point straight into the mmap'ed buffer, otherwise they are read into memory.
+fn read_index(
+    file: &dyn RandomAccessFile,
+    mmap_buf: Option<&'static [u8]>,
+    meta: &MetaV3_2,
+    index_start: u64,
+    codecs: &Codecs,
+    cipher: Option<&TableCipher>,
+) -> Result<Box<dyn Index + Send + Sync>> {
+    match (mmap_buf, meta.index_compression, cipher) {
+        (Some(mmap), Compression::None, None) => {
+            let index_end = index_start + meta.index_len;
+            Ok(Box::new(MemIndex::from_static_buf(
+                // if it was mmaped, it won't truncate
+                #[allow(clippy::cast_possible_truncation)]
+                &mmap
+                    .get(index_start as usize..index_end as usize)
+                    .ok_or(INVALID_DATA)?,
+                meta.index_len,
+                meta.max_chunk_len,
+            )?))
+        }
+        _ => {
+            let section = Section {
+                start: index_start,
+                length: meta.index_len,
+                uncompressed_length: meta.index_uncompressed_len,
+                compression: meta.index_compression,
+            };
+            let reader = section_reader(file, section, codecs, cipher)?;
+            Ok(Box::new(OwnedIndex::from_reader(
+                reader,
+                meta.max_chunk_len,
+            )?))
+        }
+    }
+}
+
+/// Borrow a section from the mmap'ed file, if it's mmap'ed, and the section doesn't need
+/// to be decrypted or uncompressed.
+fn mmap_section(
+    mmap_buf: Option<&'static [u8]>,
+    start: u64,
+    length: u64,
+    compression: Compression,
+    cipher: Option<&TableCipher>,
+) -> Result<Option<&'static [u8]>> {
+    match (mmap_buf, compression, cipher) {
+        (Some(mmap), Compression::None, None) => Ok(Some(mmap_chunk(
+            mmap,
+            page_cache::ChunkHandle {
+                offset: start,
+                length,
+                uncompressed_length: length,
+            },
+        )?)),
+        _ => Ok(None),
+    }
+}
+
+/// Read the bloom filter section of the sstable, unless filters are per chunk.
+fn read_bloom_section(
+    file: &dyn RandomAccessFile,
+    mmap_buf: Option<&'static [u8]>,
+    meta: &MetaV3_2,
+    bloom_start: u64,
+    codecs: &Codecs,
+    cipher: Option<&TableCipher>,
+) -> Result<Option<filter::TableFilter>> {
+    if meta.bloom.bitmap_bytes == 0 {
+        return Ok(None);
+    }
+    // Filters of other types can be checked right in the mmap'ed file.
+    if meta.filter_type != FilterType::Bloom {
+        let mmap = mmap_section(
+            mmap_buf,
+            bloom_start,
+            meta.bloom_len,
+            meta.bloom_compression,
+            cipher,
+        )?;
+        if let Some(mmap) = mmap {
+            let filter = Cow::Borrowed(mmap);
+            return Ok(Some(filter::TableFilter::Encoded(meta.filter_type, filter)));
+        }
+    }
+    let section = Section {
+        start: bloom_start,
+        length: meta.bloom_len,
+        uncompressed_length: u64::from(meta.bloom.bitmap_bytes),
+        compression: meta.bloom_compression,
+    };
+    let bloom = read_bloom(section_reader(file, section, codecs, cipher)?, meta)?;
+    Ok(Some(bloom))
+}
+
+/// Returns false if the key hashed to "key_hash" is definitely not in the table.
+///
+/// Tables with filters per chunk have no table-wide bloom filter.
+fn bloom_may_contain(bloom: Option<&filter::TableFilter>, key_hash: u64) -> bool {
+    bloom
+        .map(|bloom| bloom.may_contain(key_hash))
+        .unwrap_or(true)
+}
+
+/// Returns false if no key in the table is in the range.
+///
+/// Only tables with a range filter can tell.
+fn range_filter_may_contain(
+    range_filter: Option<&filter::TableFilter>,
+    start: Bound<&[u8]>,
+    end: Bound<&[u8]>,
+) -> bool {
+    range_filter
+        .map(|range_filter| filter::range_may_contain(range_filter, start, end))
+        .unwrap_or(true)
+}
+
+/// Read the range filter section of the sstable, if it has one.
+fn read_range_filter(
+    file: &dyn RandomAccessFile,
+    mmap_buf: Option<&'static [u8]>,
+    meta: &MetaV3_2,
+    range_filter_start: u64,
+    codecs: &Codecs,
+    cipher: Option<&TableCipher>,
+) -> Result<Option<filter::TableFilter>> {
+    if meta.range_filter_len == 0 {
+        return Ok(None);
+    }
+    let mmap = mmap_section(
+        mmap_buf,
+        range_filter_start,
+        meta.range_filter_len,
+        Compression::None,
+        cipher,
+    )?;
+    if let Some(mmap) = mmap {
+        let filter = Cow::Borrowed(mmap);
+        return Ok(Some(filter::TableFilter::Encoded(meta.filter_type, filter)));
+    }
+    let section = Section {
+        start: range_filter_start,
+        length: meta.range_filter_len,
+        uncompressed_length: meta.range_filter_len,
+        compression: Compression::None,
+    };
+    let mut buf = Vec::new();
+    section_reader(file, section, codecs, cipher)?.read_to_end(&mut buf)?;
+    Ok(Some(filter::TableFilter::Encoded(
+        meta.filter_type,
+        Cow::Owned(buf),
+    )))
+}
+
+/// The table-wide filters, read the first time they are needed.
+///
+/// Readers that never check them, e.g. with `ReadOptions::use_bloom` off, or short-lived
+/// ones, don't pay for reading them.
+struct LazyFilters {
+    bloom: OnceCell<Option<filter::TableFilter>>,
+    range_filter: OnceCell<Option<filter::TableFilter>>,
+    file: Arc<dyn RandomAccessFile>,
+    mmap_buf: Option<&'static [u8]>,
+    meta: MetaV3_2,
+    bloom_start: u64,
+    codecs: Codecs,
+    cipher: Option<TableCipher>,
+}
+
+impl LazyFilters {
+    fn new(
+        file: &Arc<dyn RandomAccessFile>,
+        mmap_buf: Option<&'static [u8]>,
+        meta: &MetaV3_2,
+        bloom_start: u64,
+        codecs: &Codecs,
+        cipher: Option<&TableCipher>,
+    ) -> Self {
+        Self {
+            bloom: OnceCell::new(),
+            range_filter: OnceCell::new(),
+            file: file.clone(),
+            mmap_buf,
+            meta: meta.clone(),
+            bloom_start,
+            codecs: codecs.clone(),
+            cipher: cipher.cloned(),
+        }
+    }
+
+    fn bloom(&self) -> Result<Option<&filter::TableFilter>> {
+        let bloom = self.bloom.get_or_try_init(|| {
+            read_bloom_section(
+                self.file.as_ref(),
+                self.mmap_buf,
+                &self.meta,
+                self.bloom_start,
+                &self.codecs,
+                self.cipher.as_ref(),
+            )
+        })?;
+        Ok(bloom.as_ref())
+    }
+
+    fn range_filter(&self) -> Result<Option<&filter::TableFilter>> {
+        let range_filter = self.range_filter.get_or_try_init(|| {
+            read_range_filter(
+                self.file.as_ref(),
+                self.mmap_buf,
+                &self.meta,
+                self.bloom_start + self.meta.bloom_len,
+                &self.codecs,
+                self.cipher.as_ref(),
+            )
+        })?;
+        Ok(range_filter.as_ref())
+    }
+
+    /// The bytes held by the filters loaded so far.
+    fn memory_usage(&self) -> usize {
+        [&self.bloom, &self.range_filter]
+            .iter()
+            .filter_map(|filter| filter.get().and_then(Option::as_ref))
+            .map(filter::TableFilter::memory_usage)
+            .sum()
+    }
+}
+
+/// Passes hints about how the sstable is going to be read on to the OS, for the file and
+/// the mmap. Hints are best effort, so errors are ignored.
+struct Advisor {
+    file: Arc<dyn RandomAccessFile>,
+    mmap: Option<MappedFile>,
+}
+
+impl Advisor {
+    fn advise(&self, offset: u64, length: u64, advice: Advice) {
+        let _ = self.file.advise(offset, length, advice);
+        if let Some(mmap) = self.mmap.as_ref() {
+            let _ = mmap.advise(offset, length, advice);
+        }
+    }
+
+    /// Advise about the whole file, see `ReadOptions::access_pattern`.
+    fn advise_access_pattern(&self, opts: &ReadOptions) -> Result<()> {
+        if opts.access_pattern != Advice::Normal {
+            self.advise(0, self.file.size()?, opts.access_pattern);
+        }
+        Ok(())
+    }
+
+    /// Advise `Advice::WillNeed` for the chunks, so that the OS reads them all in the
+    /// background while the first ones are read.
+    fn will_need(&self, chunks: &[ChunkBounds]) {
+        if let (Some(first), Some(last)) = (chunks.first(), chunks.last()) {
+            let end = last.chunk.offset + last.chunk.length;
+            let offset = first.chunk.offset;
+            self.advise(offset, end - offset, Advice::WillNeed);
+        }
+    }
+}
+
+/// Tells the OS that the chunks a scan read are not needed anymore once it moves past
+/// them, see `GetOptions::dont_need`.
+struct DontNeed<'a> {
+    advisor: &'a Advisor,
+    // The chunk the scan is in.
+    current: Option<page_cache::ChunkHandle>,
+}
+
+impl<'a> DontNeed<'a> {
+    /// Returns None unless "options" ask for it.
+    fn new(advisor: &'a Advisor, options: Option<GetOptions>) -> Option<Self> {
+        if options.map(|o| o.dont_need).unwrap_or(false) {
+            Some(Self {
+                advisor,
+                current: None,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// The scan moved to "chunk".
+    fn moved_to(&mut self, chunk: page_cache::ChunkHandle) {
+        if let Some(previous) = self.current.replace(chunk) {
+            self.advisor
+                .advise(previous.offset, previous.length, Advice::DontNeed);
+        }
+    }
+}
+
+impl Drop for DontNeed<'_> {
+    fn drop(&mut self) {
+        if let Some(current) = self.current.take() {
+            self.advisor
+                .advise(current.offset, current.length, Advice::DontNeed);
+        }
+    }
+}
+
+/// Get "chunk" from "page_cache", caching it if "fill_cache" is set.
+fn read_chunk(
+    page_cache: &SharedPageCache,
+    chunk: page_cache::ChunkHandle,
+    fill_cache: bool,
+) -> Result<Bytes> {
+    if fill_cache {
+        page_cache.get_chunk(chunk)
+    } else {
+        page_cache.get_chunk_no_fill(chunk)
+    }
+}
+
+/// Reads and uncompresses the chunks a scan gets to next on the prefetch threads, while
+/// the scan goes through the current one, see `GetOptions::prefetch`.
+struct Prefetch<'a> {
+    page_cache: SharedPageCache,
+    // The jobs keep the mmap the page cache reads alive.
+    mmap: Option<MappedFile>,
+    fill_cache: bool,
+    // How many chunks are read ahead.
+    depth: usize,
+    // The chunks of the scan that are not requested yet, in scan order.
+    chunks: Box<dyn Iterator<Item = ChunkBounds> + 'a>,
+    // The offsets of the requested chunks, and where they arrive, in scan order.
+    requested: VecDeque<(u64, Receiver<Result<Bytes>>)>,
+}
+
+impl<'a> Prefetch<'a> {
+    /// Returns None unless "options" ask for it.
+    fn new(
+        reader: &'a ConcurrentInnerReader,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        options: Option<GetOptions>,
+    ) -> Option<Self> {
+        let options = options.filter(|o| o.prefetch > 0)?;
+        let data_end = reader.data_start + reader.meta.data_len;
+        Some(Self {
+            page_cache: reader.page_cache.clone(),
+            mmap: reader.advisor.mmap.clone(),
+            fill_cache: options.fill_cache,
+            depth: options.prefetch,
+            chunks: Box::new(chunks_in_range(reader.index.as_ref(), data_end, start, end)),
+            requested: VecDeque::new(),
+        })
+    }
+
+    /// Get "chunk", which the scan moved to, and request the ones after it.
+    fn get_chunk(&mut self, chunk: page_cache::ChunkHandle) -> Result<Bytes> {
+        self.request();
+        while let Some((offset, result)) = self.requested.pop_front() {
+            if offset == chunk.offset {
+                self.request();
+                return result
+                    .recv()
+                    .unwrap_or(Err(Error::ProgrammingError("prefetching a chunk panicked")));
+            }
+        }
+        read_chunk(&self.page_cache, chunk, self.fill_cache)
+    }
+
+    /// Request the next chunks until "depth" of them are requested.
+    fn request(&mut self) {
+        while self.requested.len() < self.depth {
+            let chunk = match self.chunks.next() {
+                Some(bounds) => bounds.chunk,
+                None => return,
+            };
+            let (sender, result) = sync_channel(1);
+            let page_cache = self.page_cache.clone();
+            let mmap = self.mmap.clone();
+            let fill_cache = self.fill_cache;
+            thread_pool::prefetch_pool().execute(move || {
+                let _mmap = mmap;
+                // The scan may be dropped before it gets to the chunk.
+                let _ = sender.send(read_chunk(&page_cache, chunk, fill_cache));
+            });
+            self.requested.push_back((chunk.offset, result));
+        }
+    }
+}
+
+/// Open the sstable file from "env", with direct IO if it's set.
+fn open_file(filename: &Path, opts: &ReadOptions) -> Result<Arc<dyn RandomAccessFile>> {
+    Ok(if opts.direct_io {
+        opts.env.open_direct(filename)?
+    } else {
+        opts.env.open(filename)?
+    })
+}
+
+/// Make the cipher to decrypt the table with, if it's encrypted.
+///
+/// With "keys" set, the table must be encrypted, as the metadata that says it's not is
+/// not authenticated.
+fn table_cipher(meta: &MetaV3_2, opts: &ReadOptions) -> Result<Option<TableCipher>> {
+    match (meta.encryption.as_ref(), opts.keys.as_ref()) {
+        (Some(encryption), keys) => Ok(Some(TableCipher::from_meta(keys, encryption)?)),
+        (None, Some(_)) => Err(Error::NotEncrypted),
+        (None, None) => Ok(None),
+    }
+}
+
+/// The block cache to keep the filters of chunks in, if any. Filters are not compressed,
+/// so they are only read from the mmap'ed file directly if they are not encrypted.
+fn filter_block_cache<'a>(
+    mmap_buf: Option<&'static [u8]>,
+    cipher: Option<&TableCipher>,
+    opts: &'a ReadOptions,
+) -> Option<&'a BlockCache> {
+    let direct = mmap_buf.is_some() && cipher.is_none();
+    opts.block_cache.as_ref().filter(|_| !direct)
+}
+
+/// Make the cache to read the filters of chunks through, if the table has them.
+///
+/// With a shared block cache, the filters are kept there next to the chunks, by the
+/// table and their offset.
+fn filter_cache(
+    file: &Arc<dyn RandomAccessFile>,
+    mmap_buf: Option<&'static [u8]>,
+    meta: &MetaV3_2,
+    cipher: Option<&TableCipher>,
+    opts: &ReadOptions,
+) -> Result<Option<Box<dyn page_cache::PageCache>>> {
+    if meta.filter_len == 0 {
+        return Ok(None);
+    }
+    let block_cache = filter_block_cache(mmap_buf, cipher, opts);
+    // With a shared block cache, the layers below it only keep the filter they return.
+    let cache = match block_cache {
+        Some(_) => ReadCache::Blocks(1),
+        None => opts.cache.clone().unwrap_or_default(),
+    };
+    let pc: Box<dyn page_cache::PageCache> = match mmap_buf {
+        Some(mmap) => Box::new(page_cache::StaticBufCache::new(mmap)),
+        None => Box::new(page_cache::ReadPageCache::new(
+            file.clone(),
+            cache,
+            opts.cache_policy,
+        )),
+    };
+    let pc: Box<dyn page_cache::PageCache> = match cipher {
+        None => pc,
+        Some(cipher) => Box::new(page_cache::DecryptingCache::new(
+            pc,
+            cipher.clone(),
+            cache,
+            opts.cache_policy,
+        )),
+    };
+    Ok(Some(match block_cache {
+        None => pc,
+        Some(block_cache) => Box::new(page_cache::SharedCache::new(pc, block_cache.clone())),
+    }))
+}
+
+/// The secondary cache to keep the uncompressed chunks of the table in, if any, and the
+/// key of the table in it.
+///
+/// Encrypted tables don't use it, as it would keep their chunks in plaintext on disk.
+fn secondary_cache(
+    file: &dyn RandomAccessFile,
+    meta: &MetaV3_2,
+    opts: &ReadOptions,
+) -> Result<Option<(secondary_cache::SecondaryCache, u64)>> {
+    let cache = match (opts.secondary_cache.as_ref(), meta.encryption.as_ref()) {
+        (Some(cache), None) => cache,
+        _ => return Ok(None),
+    };
+    Ok(file.unique_id()?.map(|key| (cache.clone(), key)))
+}
+
+/// Returns true if the chunks are read from the mmap'ed file directly, as they are
+/// neither compressed nor encrypted. There's no need to cache them then.
+fn reads_mmap_directly(mmap_buf: Option<&'static [u8]>, meta: &MetaV3_2) -> bool {
+    mmap_buf.is_some() && meta.compression == Compression::None && meta.encryption.is_none()
+}
+
+/// The block cache to keep the chunks of the table in, if any.
+fn shared_block_cache<'a>(
+    mmap_buf: Option<&'static [u8]>,
+    meta: &MetaV3_2,
+    opts: &'a ReadOptions,
+) -> Option<&'a BlockCache> {
+    let direct = reads_mmap_directly(mmap_buf, meta);
+    opts.block_cache.as_ref().filter(|_| !direct)
 }
 
-/// Find the potential start and end offsets of the key.
+/// Make the cache to read the filters of chunks through, if the table has them, see
+/// `filter_cache`.
+fn concurrent_filter_cache(
+    file: &Arc<dyn RandomAccessFile>,
+    mmap_buf: Option<&'static [u8]>,
+    meta: &MetaV3_2,
+    cipher: Option<&TableCipher>,
+    opts: &ReadOptions,
+    num_cpus: usize,
+) -> Result<Option<Box<dyn concurrent_page_cache::ConcurrentPageCache + Send + Sync>>> {
+    if meta.filter_len == 0 {
+        return Ok(None);
+    }
+    let block_cache = filter_block_cache(mmap_buf, cipher, opts);
+    // With a shared block cache, the layers below it don't cache at all.
+    let cache = match block_cache {
+        Some(_) => None,
+        None => opts.cache,
+    };
+    let pc: Box<dyn concurrent_page_cache::ConcurrentPageCache + Send + Sync> = match mmap_buf {
+        Some(mmap) => Box::new(page_cache::StaticBufCache::new(mmap)),
+        None => Box::new(concurrent_page_cache::FileBackedPageCache::new(
+            file.clone(),
+            cache,
+            opts.cache_policy,
+            num_cpus,
+        )),
+    };
+    let pc: Box<dyn concurrent_page_cache::ConcurrentPageCache + Send + Sync> = match cipher {
+        None => pc,
+        Some(cipher) => Box::new(concurrent_page_cache::DecryptingCache::new(
+            pc,
+            cipher.clone(),
+            cache,
+            opts.cache_policy,
+            num_cpus,
+        )),
+    };
+    Ok(Some(match block_cache {
+        None => pc,
+        Some(block_cache) => Box::new(concurrent_page_cache::SharedCache::new(
+            pc,
+            block_cache.clone(),
+        )),
+    }))
+}
+
+/// Uncompresses the values stored with FLAG_VALUE_COMPRESSED.
+struct ValueUncompress {
+    uncompress: Box<dyn compression::Uncompress + Send + Sync>,
+    // See `MetaV3_2::max_value_len`.
+    max_length: u64,
+}
+
+impl ValueUncompress {
+    /// Returns None if the table has no value compression.
+    fn new(meta: &MetaV3_2, codecs: &Codecs) -> Result<Option<Self>> {
+        Ok(
+            compression::uncompressor(meta.value_compression, codecs)?.map(|uncompress| Self {
+                uncompress,
+                max_length: meta.max_value_len,
+            }),
+        )
+    }
+}
+
+/// Uncompress a value stored with FLAG_VALUE_COMPRESSED into "out".
+fn uncompress_value(
+    value: &[u8],
+    uncompress: Option<&ValueUncompress>,
+    out: &mut Vec<u8>,
+) -> Result<()> {
+    // The value can't be flagged if the table has no value compression.
+    let uncompress = uncompress.ok_or(INVALID_DATA)?;
+    let (uncompressed_length, compressed) = split_compressed_value(value)?;
+    // A corrupt length must not make a huge allocation.
+    if uncompressed_length as u64 > uncompress.max_length {
+        return Err(INVALID_DATA);
+    }
+    out.clear();
+    out.resize(uncompressed_length, 0);
+    uncompress.uncompress.uncompress_into(compressed, out)
+}
+
+/// Returns true if "key" is not before the "start" of a range.
+fn after_start(key: &[u8], start: &Bound<Vec<u8>>) -> bool {
+    match start {
+        Bound::Included(start) => key >= start.as_slice(),
+        Bound::Excluded(start) => key > start.as_slice(),
+        Bound::Unbounded => true,
+    }
+}
+
+/// Returns true if "key" is not past the "end" of a range.
+fn before_end(key: &[u8], end: &Bound<Vec<u8>>) -> bool {
+    match end {
+        Bound::Included(end) => key <= end.as_slice(),
+        Bound::Excluded(end) => key < end.as_slice(),
+        Bound::Unbounded => true,
+    }
+}
+
+fn bound_ref(bound: &Bound<Vec<u8>>) -> Bound<&[u8]> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key),
+        Bound::Excluded(key) => Bound::Excluded(key),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+fn owned_bound(bound: Bound<&[u8]>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.to_owned()),
+        Bound::Excluded(key) => Bound::Excluded(key.to_owned()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+/// The end of the range of keys starting with "prefix", i.e. the first key after them.
+fn prefix_end(prefix: &[u8]) -> Bound<Vec<u8>> {
+    let mut end = prefix.to_owned();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return Bound::Excluded(end);
+        }
+    }
+    Bound::Unbounded
+}
+
+/// Returns false if no key in the table starts with "prefix".
+///
+/// Only tables with a prefix extractor can tell, and only for prefixes the extractor
+/// extracts something from. With filters per chunk, the filters of all the chunks that
+/// may have keys with the prefix are checked with "chunk_filter_may_contain".
+fn may_contain_prefix<F>(
+    prefix_extractor: Option<PrefixExtractor>,
+    bloom: Option<&filter::TableFilter>,
+    chunks: ChunkIter,
+    prefix: &[u8],
+    mut chunk_filter_may_contain: F,
+) -> Result<bool>
+where
+    F: FnMut(page_cache::ChunkHandle, u64) -> Result<bool>,
+{
+    let prefix_hash = match prefix_extractor.and_then(|e| e.extract(prefix)) {
+        Some(extracted) => bloom_prefix_hash(extracted),
+        None => return Ok(true),
+    };
+    if let Some(bloom) = bloom {
+        return Ok(bloom.may_contain(prefix_hash));
+    }
+    let end = prefix_end(prefix);
+    for (first_key, bounds) in chunks {
+        if !before_end(first_key, &end) {
+            break;
+        }
+        let may_contain = match bounds.filter {
+            Some(filter) => chunk_filter_may_contain(filter, prefix_hash)?,
+            None => true,
+        };
+        if may_contain {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// The value of the record, uncompressed if it was compressed on its own.
+fn record_value<'a>(
+    chunk: &'a [u8],
+    location: &ValueLocation,
+    uncompress: Option<&ValueUncompress>,
+) -> Result<Cow<'a, [u8]>> {
+    let value = &chunk[location.start..location.end];
+    if !location.is_compressed() {
+        return Ok(Cow::Borrowed(value));
+    }
+    let mut uncompressed = Vec::new();
+    uncompress_value(value, uncompress, &mut uncompressed)?;
+    Ok(Cow::Owned(uncompressed))
+}
+
+/// What the index stores for every chunk.
+#[derive(Copy, Clone, Debug)]
+struct IndexValue {
+    offset: u64,
+    uncompressed_length: u64,
+    filter_offset: u64,
+    filter_length: u64,
+}
+
+impl IndexValue {
+    /// Make the index value of "kvoffset", checking that its chunk isn't longer than
+    /// "max_chunk_len", as a corrupt length must not make a huge allocation.
+    fn new(kvoffset: &KVOffset, max_chunk_len: u64) -> Result<Self> {
+        if kvoffset.uncompressed_length > max_chunk_len {
+            return Err(INVALID_DATA);
+        }
+        Ok(Self {
+            offset: kvoffset.offset,
+            uncompressed_length: kvoffset.uncompressed_length,
+            filter_offset: kvoffset.filter_offset,
+            filter_length: kvoffset.filter_length,
+        })
+    }
+}
+
+/// The chunk that may contain a key, and its filter if filters are per chunk.
+struct ChunkBounds {
+    chunk: page_cache::ChunkHandle,
+    filter: Option<page_cache::ChunkHandle>,
+}
+
+/// Make the bounds of the chunk described by "value" that ends at "end".
+fn chunk_bounds(value: &IndexValue, end: u64) -> ChunkBounds {
+    let filter = if value.filter_length > 0 {
+        Some(page_cache::ChunkHandle {
+            offset: value.filter_offset,
+            length: value.filter_length,
+            uncompressed_length: value.filter_length,
+        })
+    } else {
+        None
+    };
+    ChunkBounds {
+        chunk: page_cache::ChunkHandle {
+            offset: value.offset,
+            length: end - value.offset,
+            uncompressed_length: value.uncompressed_length,
+        },
+        filter,
+    }
+}
+
+/// Find the chunk that potentially contains the key.
 /// This will be used later to fetch the chunk from the page cache.
-fn find_bounds<K, T>(map: &BTreeMap<K, T>, key: &[u8], end_default: T) -> Option<(T, T)>
+fn find_bounds<K>(
+    map: &BTreeMap<K, IndexValue>,
+    key: &[u8],
+    end_default: u64,
+) -> Option<ChunkBounds>
 where
     K: Borrow<[u8]> + std::cmp::Ord,
-    T: Copy,
 {
-    use std::ops::Bound;
-
     let start = {
         let mut iter_left = map.range::<[u8], _>((Bound::Unbounded, Bound::Included(key)));
         let closest_left = iter_left.next_back();
         match closest_left {
-            Some((_, offset)) => *offset,
+            Some((_, value)) => *value,
             None => return None,
         }
     };
@@ -106,28 +893,80 @@ where
         let mut iter_right = map.range::<[u8], _>((Bound::Excluded(key), Bound::Unbounded));
         let closest_right = iter_right.next();
         match closest_right {
-            Some((_, offset)) => *offset,
+            Some((_, value)) => value.offset,
             None => end_default,
         }
     };
-    Some((start, end))
+    Some(chunk_bounds(&start, end))
+}
+
+/// The chunks in key order, starting with the one that may contain "start",
+/// along with their first keys.
+fn chunks_from<'a, K>(
+    map: &'a BTreeMap<K, IndexValue>,
+    start: Bound<&[u8]>,
+    end_default: u64,
+) -> ChunkIter<'a>
+where
+    K: Borrow<[u8]> + std::cmp::Ord,
+{
+    let first = match start {
+        Bound::Included(key) | Bound::Excluded(key) => map
+            .range::<[u8], _>((Bound::Unbounded, Bound::Included(key)))
+            .next_back()
+            .map(|(first, _)| first.borrow()),
+        Bound::Unbounded => None,
+    };
+    let mut iter = match first {
+        Some(first) => map.range::<[u8], _>((Bound::Included(first), Bound::Unbounded)),
+        None => map.range::<[u8], _>(..),
+    }
+    .peekable();
+    Box::new(std::iter::from_fn(move || {
+        let (key, value) = iter.next()?;
+        let end = iter
+            .peek()
+            .map(|(_, next)| next.offset)
+            .unwrap_or(end_default);
+        Some((key.borrow(), chunk_bounds(value, end)))
+    }))
+}
+
+/// An iterator over the chunks of a table, see `chunks_from`.
+type ChunkIter<'a> = Box<dyn Iterator<Item = (&'a [u8], ChunkBounds)> + 'a>;
+
+/// The chunks that may contain keys in the range.
+fn chunks_in_range<'a>(
+    index: &'a dyn Index,
+    data_end: u64,
+    start: Bound<&[u8]>,
+    end: Bound<&[u8]>,
+) -> impl Iterator<Item = ChunkBounds> + 'a {
+    let end = owned_bound(end);
+    index
+        .chunks_from(start, data_end)
+        .take_while(move |(first_key, _)| before_end(first_key, &end))
+        .map(|(_, bounds)| bounds)
 }
 
-/// An object that can find the potential start and end offsets of the key.
+/// An object that can find the chunk potentially containing the key.
 ///
 /// A trait is used instead of a struct cause we have multiple implementations,
 /// owning and not owning.
 trait Index {
-    fn find_bounds(&self, key: &[u8], end_default: u64) -> Option<(u64, u64)>;
+    fn find_bounds(&self, key: &[u8], end_default: u64) -> Option<ChunkBounds>;
+    fn chunks_from(&self, start: Bound<&[u8]>, end_default: u64) -> ChunkIter<'_>;
+    /// The bytes held by the index, not counting the keys borrowed from the mmap'ed file.
+    fn memory_usage(&self) -> usize;
 }
 
 /// An index that is used with Mmap blocks.
 struct MemIndex {
-    index: BTreeMap<&'static [u8], u64>,
+    index: BTreeMap<&'static [u8], IndexValue>,
 }
 
 impl MemIndex {
-    fn from_static_buf(buf: &'static [u8], expected_len: u64) -> Result<Self> {
+    fn from_static_buf(buf: &'static [u8], expected_len: u64, max_chunk_len: u64) -> Result<Self> {
         // Build the index from mmap here.
         let mut index = BTreeMap::new();
         let mut index_data = &buf[..];
@@ -148,7 +987,7 @@ impl MemIndex {
                 .get(kvoffset_encoded_size..key_end)
                 .ok_or(INVALID_DATA)?;
             let key: &'static [u8] = unsafe { &*(key as *const _) };
-            index.insert(key, kvoffset.offset);
+            index.insert(key, IndexValue::new(&kvoffset, max_chunk_len)?);
             if index_data.len() == key_end {
                 break;
             }
@@ -160,17 +999,23 @@ impl MemIndex {
 }
 
 impl Index for MemIndex {
-    fn find_bounds(&self, key: &[u8], end_default: u64) -> Option<(u64, u64)> {
+    fn find_bounds(&self, key: &[u8], end_default: u64) -> Option<ChunkBounds> {
         find_bounds(&self.index, key, end_default)
     }
+    fn chunks_from(&self, start: Bound<&[u8]>, end_default: u64) -> ChunkIter<'_> {
+        chunks_from(&self.index, start, end_default)
+    }
+    fn memory_usage(&self) -> usize {
+        self.index.len() * std::mem::size_of::<(&[u8], IndexValue)>()
+    }
 }
 
 struct OwnedIndex {
-    index: BTreeMap<Vec<u8>, u64>,
+    index: BTreeMap<Vec<u8>, IndexValue>,
 }
 
 impl OwnedIndex {
-    fn from_reader<R: Read>(mut reader: R) -> Result<Self> {
+    fn from_reader<R: Read>(mut reader: R, max_chunk_len: u64) -> Result<Self> {
         let mut index = BTreeMap::new();
 
         loop {
@@ -181,16 +1026,23 @@ impl OwnedIndex {
             };
             let mut key = vec![0; kvoffset.key_length as usize];
             reader.read_exact(&mut key)?;
-            index.insert(key, kvoffset.offset);
+            index.insert(key, IndexValue::new(&kvoffset, max_chunk_len)?);
         }
         Ok(Self { index })
     }
 }
 
 impl Index for OwnedIndex {
-    fn find_bounds(&self, key: &[u8], end_default: u64) -> Option<(u64, u64)> {
+    fn find_bounds(&self, key: &[u8], end_default: u64) -> Option<ChunkBounds> {
         find_bounds(&self.index, key, end_default)
     }
+    fn chunks_from(&self, start: Bound<&[u8]>, end_default: u64) -> ChunkIter<'_> {
+        chunks_from(&self.index, start, end_default)
+    }
+    fn memory_usage(&self) -> usize {
+        let keys: usize = self.index.keys().map(Vec::capacity).sum();
+        keys + self.index.len() * std::mem::size_of::<(Vec<u8>, IndexValue)>()
+    }
 }
 
 /// The default single-threaded reader for sstables.
@@ -203,37 +1055,37 @@ pub struct SSTableReader {
 
 struct InnerReader {
     index: Box<dyn Index>,
-    // This is just to hold an mmap reference to be dropped in the end.
-    _mmap: Option<memmap::Mmap>,
+    // Also holds the mmap, to be dropped in the end.
+    advisor: Advisor,
     page_cache: Box<dyn page_cache::PageCache>,
-    meta: MetaV3_0,
+    // Set if the table has filters per chunk.
+    filter_cache: Option<Box<dyn page_cache::PageCache>>,
+    meta: MetaV3_2,
     data_start: u64,
     use_bloom_default: bool,
-    bloom: Bloom<[u8]>,
+    filters: LazyFilters,
+    value_uncompress: Option<ValueUncompress>,
+    // Compressed values are uncompressed here, and returned by reference.
+    value_buf: Vec<u8>,
 }
 
 impl InnerReader {
     pub fn new(
-        mut file: File,
+        file: Arc<dyn RandomAccessFile>,
         data_start: u64,
         meta: MetaResult,
         opts: &ReadOptions,
     ) -> Result<Self> {
         #[allow(clippy::infallible_destructuring_match)]
         let meta = match meta.meta {
-            MetaData::V3_0(meta) => meta,
+            MetaData::V3_2(meta) => meta,
         };
 
-        let index_start = data_start + (meta.data_len as u64);
-        let index_end = index_start + meta.index_len;
+        let index_start = data_start + meta.data_len + meta.filter_len;
+        let bloom_start = index_start + meta.index_len;
+        let cipher = table_cipher(&meta, opts)?;
 
-        file.seek(SeekFrom::Start(index_start))?;
-
-        let mmap = if opts.use_mmap {
-            Some(unsafe { memmap::Mmap::map(&file) }?)
-        } else {
-            None
-        };
+        let mmap = if opts.use_mmap { file.map()? } else { None };
         let mmap_buf = mmap.as_ref().map(|mmap| {
             let buf = &mmap as &[u8];
             let buf = buf as *const [u8];
@@ -241,81 +1093,98 @@ impl InnerReader {
             buf
         });
 
-        let (index, bloom): (Box<dyn Index>, Bloom<[u8]>) = match meta.compression {
-            Compression::None => match mmap_buf {
-                Some(mmap) => {
-                    let index = Box::new(MemIndex::from_static_buf(
-                        // if it was mmaped, it won't truncate
-                        #[allow(clippy::cast_possible_truncation)]
-                        &mmap
-                            .get(index_start as usize..index_end as usize)
-                            .ok_or(INVALID_DATA)?,
-                        meta.index_len,
-                    )?);
-                    file.seek(SeekFrom::Start(index_end))?;
-                    let bloom = read_bloom((&mut file).take(meta.bloom_len), &meta.bloom)?;
-                    (index, bloom)
-                }
-                None => {
-                    let index =
-                        Box::new(OwnedIndex::from_reader((&mut file).take(meta.index_len))?);
-                    let bloom = read_bloom((&mut file).take(meta.bloom_len), &meta.bloom)?;
-                    (index, bloom)
-                }
-            },
-            Compression::Zlib => {
-                let index = Box::new(OwnedIndex::from_reader(flate2::read::ZlibDecoder::new(
-                    (&mut file).take(meta.index_len),
-                ))?);
-                let bloom = read_bloom(
-                    flate2::read::ZlibDecoder::new((&mut file).take(meta.bloom_len)),
-                    &meta.bloom,
-                )?;
-                (index, bloom)
-            }
-            Compression::Snappy => {
-                let index = Box::new(OwnedIndex::from_reader(snap::Reader::new(
-                    (&mut file).take(meta.index_len),
-                ))?);
-                let bloom = read_bloom(
-                    snap::Reader::new((&mut file).take(meta.bloom_len)),
-                    &meta.bloom,
-                )?;
-                (index, bloom)
-            }
+        let index = read_index(
+            file.as_ref(),
+            mmap_buf,
+            &meta,
+            index_start,
+            &opts.codecs,
+            cipher.as_ref(),
+        )?;
+        let filters = LazyFilters::new(
+            &file,
+            mmap_buf,
+            &meta,
+            bloom_start,
+            &opts.codecs,
+            cipher.as_ref(),
+        );
+        let filter_cache = filter_cache(&file, mmap_buf, &meta, cipher.as_ref(), opts)?;
+
+        let uncompress = compression::uncompressor(meta.compression, &opts.codecs)?;
+        let secondary = secondary_cache(file.as_ref(), &meta, opts)?;
+        let advisor = Advisor {
+            file: file.clone(),
+            mmap,
+        };
+        advisor.advise_access_pattern(opts)?;
+        let block_cache = shared_block_cache(mmap_buf, &meta, opts);
+        // With a shared block cache, the layers below it only keep the chunk they return.
+        let cache = match block_cache {
+            Some(_) => ReadCache::Blocks(1),
+            None => opts.cache.clone().unwrap_or_default(),
+        };
+        // Compressed chunks are cached in "raw_cache" as read, and in "cache" uncompressed.
+        // The layer reading the file keeps at least the chunk it returns.
+        let raw_cache = match (block_cache, &uncompress) {
+            (None, Some(_)) => opts.raw_cache.unwrap_or(ReadCache::Blocks(1)),
+            _ => cache,
         };
 
         let pc: Box<dyn page_cache::PageCache> = match mmap_buf {
             Some(mmap) => Box::new(page_cache::StaticBufCache::new(mmap)),
             None => Box::new(page_cache::ReadPageCache::new(
                 file,
-                opts.cache.clone().unwrap_or_default(),
+                raw_cache,
+                opts.cache_policy,
             )),
         };
 
-        let uncompressed_cache: Box<dyn page_cache::PageCache> = match meta.compression {
-            Compression::None => pc,
-            Compression::Zlib => {
-                let dec = compression::ZlibUncompress {};
-                let cache = opts.cache.clone().unwrap_or_default();
-                let wrapped = page_cache::WrappedCache::new(pc, dec, cache);
-                Box::new(wrapped)
+        let pc: Box<dyn page_cache::PageCache> = match cipher {
+            None => pc,
+            Some(cipher) => {
+                // If the chunks get uncompressed, there's no point in caching
+                // them in between, the uncompressing layer caches them.
+                let cache = match uncompress {
+                    Some(_) => ReadCache::Blocks(1),
+                    None => cache,
+                };
+                Box::new(page_cache::DecryptingCache::new(
+                    pc,
+                    cipher,
+                    cache,
+                    opts.cache_policy,
+                ))
             }
-            Compression::Snappy => {
-                let dec = compression::SnappyUncompress {};
-                let cache = opts.cache.clone().unwrap_or_default();
-                let wrapped = page_cache::WrappedCache::new(pc, dec, cache);
+        };
+
+        let uncompressed_cache: Box<dyn page_cache::PageCache> = match uncompress {
+            None => pc,
+            Some(dec) => {
+                let wrapped = page_cache::WrappedCache::new(pc, dec, cache, opts.cache_policy)
+                    .with_secondary_cache(secondary);
                 Box::new(wrapped)
             }
         };
 
+        let uncompressed_cache: Box<dyn page_cache::PageCache> = match block_cache {
+            None => uncompressed_cache,
+            Some(block_cache) => Box::new(page_cache::SharedCache::new(
+                uncompressed_cache,
+                block_cache.clone(),
+            )),
+        };
+
         Ok(Self {
-            _mmap: mmap,
+            advisor,
             index,
             page_cache: uncompressed_cache,
+            filter_cache,
             data_start,
+            value_uncompress: ValueUncompress::new(&meta, &opts.codecs)?,
+            value_buf: Vec::new(),
             meta,
-            bloom,
+            filters,
             use_bloom_default: opts.use_bloom,
         })
     }
@@ -328,57 +1197,187 @@ impl InnerReader {
         let use_bloom = options
             .map(|o| o.use_bloom)
             .unwrap_or(self.use_bloom_default);
-        if use_bloom && !self.bloom.check(key) {
+        let key_hash = bloom_key_hash(key);
+        if use_bloom && !bloom_may_contain(self.filters.bloom()?, key_hash) {
             return Ok(None);
         }
-        let index_start = self.data_start + self.meta.data_len as u64;
-        let (offset, right_bound) = match self.index.find_bounds(key, index_start) {
+        let data_end = self.data_start + self.meta.data_len;
+        let bounds = match self.index.find_bounds(key, data_end) {
             Some(v) => v,
             None => return Ok(None),
         };
+        if let (true, Some(filter), Some(filter_cache)) =
+            (use_bloom, bounds.filter, self.filter_cache.as_mut())
+        {
+            if !filter::may_contain(
+                self.meta.filter_type,
+                filter_cache.get_chunk(filter)?,
+                key_hash,
+            ) {
+                return Ok(None);
+            }
+        }
 
-        let chunk = self.page_cache.get_chunk(offset, right_bound - offset)?;
-        Ok(find_value_offset_v2(chunk, key)?.map(|(start, end)| &chunk[start..end]))
+        let fill_cache = options.map(|o| o.fill_cache).unwrap_or(true);
+        let chunk = if fill_cache {
+            self.page_cache.get_chunk(bounds.chunk)?
+        } else {
+            self.page_cache.get_chunk_no_fill(bounds.chunk)?
+        };
+        let location = match find_value_offset_v2(chunk, key)? {
+            Some(location) => location,
+            None => return Ok(None),
+        };
+        let value = &chunk[location.start..location.end];
+        if !location.is_compressed() {
+            return Ok(Some(value));
+        }
+        uncompress_value(value, self.value_uncompress.as_ref(), &mut self.value_buf)?;
+        Ok(Some(&self.value_buf))
     }
 
     fn get(&mut self, key: &[u8]) -> Result<Option<&[u8]>> {
         self.get_with_options(key, None)
     }
+
+    fn may_contain_prefix(&mut self, prefix: &[u8]) -> Result<bool> {
+        let data_end = self.data_start + self.meta.data_len;
+        let filter_type = self.meta.filter_type;
+        let filter_cache = &mut self.filter_cache;
+        may_contain_prefix(
+            self.meta.prefix_extractor,
+            self.filters.bloom()?,
+            self.index.chunks_from(Bound::Included(prefix), data_end),
+            prefix,
+            |filter, prefix_hash| match filter_cache.as_mut() {
+                Some(filter_cache) => Ok(filter::may_contain(
+                    filter_type,
+                    filter_cache.get_chunk(filter)?,
+                    prefix_hash,
+                )),
+                None => Ok(true),
+            },
+        )
+    }
+
+    fn range(
+        &mut self,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        options: Option<GetOptions>,
+    ) -> Result<RangeIter<'_>> {
+        let data_end = self.data_start + self.meta.data_len;
+        let use_bloom = options
+            .map(|o| o.use_bloom)
+            .unwrap_or(self.use_bloom_default);
+        let range_filter = if use_bloom {
+            self.filters.range_filter()?
+        } else {
+            None
+        };
+        Ok(RangeIter {
+            cursor: RangeCursor::new(self.index.as_ref(), data_end, start, end, range_filter),
+            page_cache: &mut self.page_cache,
+            value_uncompress: self.value_uncompress.as_ref(),
+            fill_cache: options.map(|o| o.fill_cache).unwrap_or(true),
+            dont_need: DontNeed::new(&self.advisor, options),
+        })
+    }
+
+    /// Read the filters, and the chunks in the range with their filters into the caches.
+    /// If "pin" is set, the chunks are pinned.
+    fn warm(&mut self, start: Bound<&[u8]>, end: Bound<&[u8]>, pin: bool) -> Result<()> {
+        self.filters.bloom()?;
+        self.filters.range_filter()?;
+        let data_end = self.data_start + self.meta.data_len;
+        let chunks: Vec<_> = chunks_in_range(self.index.as_ref(), data_end, start, end).collect();
+        self.advisor.will_need(&chunks);
+        for bounds in chunks {
+            if pin {
+                self.page_cache.pin_chunk(bounds.chunk)?;
+            } else {
+                self.page_cache.get_chunk(bounds.chunk)?;
+            }
+            if let (Some(filter), Some(filter_cache)) = (bounds.filter, self.filter_cache.as_mut())
+            {
+                if pin {
+                    filter_cache.pin_chunk(filter)?;
+                } else {
+                    filter_cache.get_chunk(filter)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn memory_usage(&self) -> page_cache::MemoryUsage {
+        let mut usage = page_cache::MemoryUsage {
+            index: self.index.memory_usage(),
+            filters: self.filters.memory_usage(),
+            ..page_cache::MemoryUsage::default()
+        };
+        self.page_cache.memory_usage(&mut usage);
+        if let Some(filter_cache) = self.filter_cache.as_ref() {
+            let mut filter_usage = page_cache::MemoryUsage::default();
+            filter_cache.memory_usage(&mut filter_usage);
+            usage.filter_cache = filter_usage.total();
+        }
+        usage
+    }
+
+    /// Warm and pin the ranges of "opts", see `ReadOptions::warm` and `ReadOptions::pin`.
+    fn warm_ranges(&mut self, opts: &ReadOptions) -> Result<()> {
+        for (start, end) in opts.warm.iter() {
+            self.warm(bound_ref(start), bound_ref(end), false)?;
+        }
+        for (start, end) in opts.pin.iter() {
+            self.warm(bound_ref(start), bound_ref(end), true)?;
+        }
+        Ok(())
+    }
+
+    fn prefix(&mut self, prefix: &[u8]) -> Result<RangeIter<'_>> {
+        let skip = self.use_bloom_default && !self.may_contain_prefix(prefix)?;
+        let mut iter = self.range(Bound::Included(prefix.to_owned()), prefix_end(prefix), None)?;
+        iter.cursor.done |= skip;
+        Ok(iter)
+    }
 }
 
+/// The chunks of `ConcurrentSSTableReader`, shared with the prefetch threads.
+type SharedPageCache = Arc<dyn concurrent_page_cache::ConcurrentPageCache + Sync + Send>;
+
 struct ConcurrentInnerReader {
-    index: Box<dyn Index + Sync + Send>,
-    // This is just to hold an mmap reference to be dropped in the end.
-    _mmap: Option<memmap::Mmap>,
-    page_cache: Box<dyn concurrent_page_cache::ConcurrentPageCache + Sync + Send>,
-    meta: MetaV3_0,
+    index: Box<dyn Index + Send + Sync>,
+    // Also holds the mmap, to be dropped in the end.
+    advisor: Advisor,
+    page_cache: SharedPageCache,
+    // Set if the table has filters per chunk.
+    filter_cache: Option<Box<dyn concurrent_page_cache::ConcurrentPageCache + Sync + Send>>,
+    meta: MetaV3_2,
     data_start: u64,
     use_bloom_default: bool,
-    bloom: Bloom<[u8]>,
+    filters: LazyFilters,
+    value_uncompress: Option<ValueUncompress>,
 }
 
 impl ConcurrentInnerReader {
     pub fn new(
-        mut file: File,
+        file: Arc<dyn RandomAccessFile>,
         data_start: u64,
         meta: MetaResult,
         opts: &ReadOptions,
     ) -> Result<Self> {
         #[allow(clippy::infallible_destructuring_match)]
         let meta = match meta.meta {
-            MetaData::V3_0(meta) => meta,
+            MetaData::V3_2(meta) => meta,
         };
 
-        let index_start = data_start + (meta.data_len as u64);
-        let index_end = index_start + meta.index_len;
-
-        file.seek(SeekFrom::Start(index_start))?;
+        let index_start = data_start + meta.data_len + meta.filter_len;
+        let bloom_start = index_start + meta.index_len;
+        let cipher = table_cipher(&meta, opts)?;
 
-        let mmap = if opts.use_mmap {
-            Some(unsafe { memmap::Mmap::map(&file) }?)
-        } else {
-            None
-        };
+        let mmap = if opts.use_mmap { file.map()? } else { None };
         let mmap_buf = mmap.as_ref().map(|mmap| {
             let buf = &mmap as &[u8];
             let buf = buf as *const [u8];
@@ -386,90 +1385,129 @@ impl ConcurrentInnerReader {
             buf
         });
 
-        let (index, bloom): (Box<dyn Index + Send + Sync>, Bloom<[u8]>) = match meta.compression {
-            Compression::None => match mmap_buf {
-                Some(mmap) => {
-                    let index = Box::new(MemIndex::from_static_buf(
-                        // if it was mmaped, it won't truncate
-                        #[allow(clippy::cast_possible_truncation)]
-                        &mmap
-                            .get(index_start as usize..index_end as usize)
-                            .ok_or(INVALID_DATA)?,
-                        meta.index_len,
-                    )?);
-
-                    file.seek(SeekFrom::Start(index_end))?;
-                    let bloom = read_bloom((&mut file).take(meta.bloom_len), &meta.bloom)?;
-                    (index, bloom)
-                }
-                None => {
-                    let index =
-                        Box::new(OwnedIndex::from_reader((&mut file).take(meta.index_len))?);
-                    let bloom = read_bloom((&mut file).take(meta.bloom_len), &meta.bloom)?;
-                    (index, bloom)
-                }
-            },
-            Compression::Zlib => {
-                // does not make sense to use mmap for index as we are not going to access
-                // the pages anyway.
-                let index = Box::new(OwnedIndex::from_reader(flate2::read::ZlibDecoder::new(
-                    (&mut file).take(meta.index_len),
-                ))?);
-                let bloom = read_bloom(
-                    flate2::read::ZlibDecoder::new((&mut file).take(meta.bloom_len)),
-                    &meta.bloom,
-                )?;
-                (index, bloom)
-            }
-            Compression::Snappy => {
-                let index = Box::new(OwnedIndex::from_reader(snap::Reader::new(
-                    (&mut file).take(meta.index_len),
-                ))?);
-                let bloom = read_bloom(
-                    snap::Reader::new((&mut file).take(meta.bloom_len)),
-                    &meta.bloom,
-                )?;
-                (index, bloom)
-            }
-        };
+        let index = read_index(
+            file.as_ref(),
+            mmap_buf,
+            &meta,
+            index_start,
+            &opts.codecs,
+            cipher.as_ref(),
+        )?;
+        let filters = LazyFilters::new(
+            &file,
+            mmap_buf,
+            &meta,
+            bloom_start,
+            &opts.codecs,
+            cipher.as_ref(),
+        );
 
         let num_cpus = opts.thread_buckets.unwrap_or_else(num_cpus::get);
+        let filter_cache =
+            concurrent_filter_cache(&file, mmap_buf, &meta, cipher.as_ref(), opts, num_cpus)?;
+
+        let uncompress = compression::uncompressor(meta.compression, &opts.codecs)?;
+        let secondary = secondary_cache(file.as_ref(), &meta, opts)?;
+        let advisor = Advisor {
+            file: file.clone(),
+            mmap,
+        };
+        advisor.advise_access_pattern(opts)?;
+        let block_cache = shared_block_cache(mmap_buf, &meta, opts);
+        let slot_cache = opts.cache.filter(|_| {
+            opts.slot_cache && block_cache.is_none() && !reads_mmap_directly(mmap_buf, &meta)
+        });
+        // With a shared block cache or a slot cache, the layers below it don't cache at all.
+        let cache = match (block_cache, slot_cache) {
+            (None, None) => opts.cache,
+            _ => None,
+        };
+        // Compressed chunks are cached in "raw_cache" as read, and in "cache" uncompressed.
+        let raw_cache = match (block_cache, &uncompress) {
+            (None, Some(_)) => opts.raw_cache,
+            _ => cache,
+        };
 
         let pc: Box<dyn concurrent_page_cache::ConcurrentPageCache + Send + Sync> = match mmap_buf {
             Some(mmap) => Box::new(page_cache::StaticBufCache::new(mmap)),
             None => Box::new(concurrent_page_cache::FileBackedPageCache::new(
                 file,
-                opts.cache.clone(),
+                raw_cache,
+                opts.cache_policy,
                 num_cpus,
             )),
         };
 
+        let pc: Box<dyn concurrent_page_cache::ConcurrentPageCache + Send + Sync> = match cipher {
+            None => pc,
+            Some(cipher) => {
+                // If the chunks get uncompressed, there's no point in caching
+                // them in between, the uncompressing layer caches them.
+                let cache = match uncompress {
+                    Some(_) => None,
+                    None => cache,
+                };
+                Box::new(concurrent_page_cache::DecryptingCache::new(
+                    pc,
+                    cipher,
+                    cache,
+                    opts.cache_policy,
+                    num_cpus,
+                ))
+            }
+        };
+
         let uncompressed_cache: Box<dyn concurrent_page_cache::ConcurrentPageCache + Send + Sync> =
-            match meta.compression {
-                Compression::None => pc,
-                Compression::Zlib => {
-                    let dec = compression::ZlibUncompress {};
-                    let cache = opts.cache.clone();
-                    let wrapped =
-                        concurrent_page_cache::WrappedCache::new(pc, dec, cache, num_cpus);
+            match uncompress {
+                None => pc,
+                Some(dec) => {
+                    let wrapped = concurrent_page_cache::WrappedCache::new(
+                        pc,
+                        dec,
+                        cache,
+                        opts.cache_policy,
+                        num_cpus,
+                    )
+                    .with_secondary_cache(secondary);
                     Box::new(wrapped)
                 }
-                Compression::Snappy => {
-                    let dec = compression::SnappyUncompress {};
-                    let cache = opts.cache.clone();
-                    let wrapped =
-                        concurrent_page_cache::WrappedCache::new(pc, dec, cache, num_cpus);
-                    Box::new(wrapped)
+            };
+
+        let uncompressed_cache: Box<dyn concurrent_page_cache::ConcurrentPageCache + Send + Sync> =
+            match block_cache {
+                None => uncompressed_cache,
+                Some(block_cache) => Box::new(concurrent_page_cache::SharedCache::new(
+                    uncompressed_cache,
+                    block_cache.clone(),
+                )),
+            };
+
+        let uncompressed_cache: Box<dyn concurrent_page_cache::ConcurrentPageCache + Send + Sync> =
+            match slot_cache {
+                None => uncompressed_cache,
+                Some(slot_cache) => {
+                    let data_end = data_start + meta.data_len;
+                    let offsets = index
+                        .chunks_from(Bound::Unbounded, data_end)
+                        .map(|(_, bounds)| bounds.chunk.offset)
+                        .collect();
+                    Box::new(slot_cache::SlotCache::new(
+                        uncompressed_cache,
+                        offsets,
+                        slot_cache,
+                    ))
                 }
             };
 
         Ok(Self {
-            _mmap: mmap,
+            advisor,
             index,
-            page_cache: uncompressed_cache,
+            page_cache: Arc::from(uncompressed_cache),
+            filter_cache,
             data_start,
+            value_uncompress: ValueUncompress::new(&meta, &opts.codecs)?,
             meta,
-            bloom,
+            filters,
             use_bloom_default: opts.use_bloom,
         })
     }
@@ -482,21 +1520,140 @@ impl ConcurrentInnerReader {
         let use_bloom = options
             .map(|o| o.use_bloom)
             .unwrap_or(self.use_bloom_default);
-        if use_bloom && !self.bloom.check(key) {
+        let key_hash = bloom_key_hash(key);
+        if use_bloom && !bloom_may_contain(self.filters.bloom()?, key_hash) {
             return Ok(None);
         }
-        let index_start = self.data_start + self.meta.data_len as u64;
-        let (offset, right_bound) = match self.index.find_bounds(key, index_start) {
+        let data_end = self.data_start + self.meta.data_len;
+        let bounds = match self.index.find_bounds(key, data_end) {
             Some(v) => v,
             None => return Ok(None),
         };
+        if let (true, Some(filter), Some(filter_cache)) =
+            (use_bloom, bounds.filter, self.filter_cache.as_ref())
+        {
+            if !filter::may_contain(
+                self.meta.filter_type,
+                &filter_cache.get_chunk(filter)?,
+                key_hash,
+            ) {
+                return Ok(None);
+            }
+        }
+
+        let fill_cache = options.map(|o| o.fill_cache).unwrap_or(true);
+        let chunk = read_chunk(&self.page_cache, bounds.chunk, fill_cache)?;
+        match find_value_offset_v2(&chunk, key)? {
+            Some(location) if location.is_compressed() => {
+                let mut buf = Vec::new();
+                uncompress_value(
+                    &chunk[location.start..location.end],
+                    self.value_uncompress.as_ref(),
+                    &mut buf,
+                )?;
+                Ok(Some(Bytes::from(buf)))
+            }
+            Some(location) => Ok(Some(chunk.slice(location.start..location.end))),
+            None => Ok(None),
+        }
+    }
+
+    fn may_contain_prefix(&self, prefix: &[u8]) -> Result<bool> {
+        let data_end = self.data_start + self.meta.data_len;
+        may_contain_prefix(
+            self.meta.prefix_extractor,
+            self.filters.bloom()?,
+            self.index.chunks_from(Bound::Included(prefix), data_end),
+            prefix,
+            |filter, prefix_hash| match self.filter_cache.as_ref() {
+                Some(filter_cache) => Ok(filter::may_contain(
+                    self.meta.filter_type,
+                    &filter_cache.get_chunk(filter)?,
+                    prefix_hash,
+                )),
+                None => Ok(true),
+            },
+        )
+    }
 
-        let chunk: Bytes = self.page_cache.get_chunk(offset, right_bound - offset)?;
-        if let Some((start, end)) = find_value_offset_v2(&chunk, key)? {
-            Ok(Some(chunk.slice(start..end)))
+    fn range(
+        &self,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        options: Option<GetOptions>,
+    ) -> Result<ConcurrentRangeIter<'_>> {
+        let data_end = self.data_start + self.meta.data_len;
+        let use_bloom = options
+            .map(|o| o.use_bloom)
+            .unwrap_or(self.use_bloom_default);
+        let range_filter = if use_bloom {
+            self.filters.range_filter()?
         } else {
-            Ok(None)
+            None
+        };
+        Ok(ConcurrentRangeIter {
+            prefetch: Prefetch::new(self, bound_ref(&start), bound_ref(&end), options),
+            cursor: RangeCursor::new(self.index.as_ref(), data_end, start, end, range_filter),
+            reader: self,
+            fill_cache: options.map(|o| o.fill_cache).unwrap_or(true),
+            dont_need: DontNeed::new(&self.advisor, options),
+        })
+    }
+
+    /// See `InnerReader::warm`.
+    fn warm(&self, start: Bound<&[u8]>, end: Bound<&[u8]>, pin: bool) -> Result<()> {
+        self.filters.bloom()?;
+        self.filters.range_filter()?;
+        let data_end = self.data_start + self.meta.data_len;
+        let chunks: Vec<_> = chunks_in_range(self.index.as_ref(), data_end, start, end).collect();
+        self.advisor.will_need(&chunks);
+        for bounds in chunks {
+            if pin {
+                self.page_cache.pin_chunk(bounds.chunk)?;
+            } else {
+                self.page_cache.get_chunk(bounds.chunk)?;
+            }
+            if let (Some(filter), Some(filter_cache)) = (bounds.filter, self.filter_cache.as_ref())
+            {
+                if pin {
+                    filter_cache.pin_chunk(filter)?;
+                } else {
+                    filter_cache.get_chunk(filter)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn memory_usage(&self) -> page_cache::MemoryUsage {
+        let mut usage = page_cache::MemoryUsage {
+            index: self.index.memory_usage(),
+            filters: self.filters.memory_usage(),
+            ..page_cache::MemoryUsage::default()
+        };
+        self.page_cache.memory_usage(&mut usage);
+        if let Some(filter_cache) = self.filter_cache.as_ref() {
+            let mut filter_usage = page_cache::MemoryUsage::default();
+            filter_cache.memory_usage(&mut filter_usage);
+            usage.filter_cache = filter_usage.total();
+        }
+        usage
+    }
+
+    fn warm_ranges(&self, opts: &ReadOptions) -> Result<()> {
+        for (start, end) in opts.warm.iter() {
+            self.warm(bound_ref(start), bound_ref(end), false)?;
         }
+        for (start, end) in opts.pin.iter() {
+            self.warm(bound_ref(start), bound_ref(end), true)?;
+        }
+        Ok(())
+    }
+
+    fn prefix(&self, prefix: &[u8]) -> Result<ConcurrentRangeIter<'_>> {
+        let mut iter = self.range(Bound::Included(prefix.to_owned()), prefix_end(prefix), None)?;
+        iter.cursor.done |= self.use_bloom_default && !self.may_contain_prefix(prefix)?;
+        Ok(iter)
     }
 }
 
@@ -506,15 +1663,119 @@ impl SSTableReader {
     }
 
     pub fn new_with_options<P: AsRef<Path>>(filename: P, opts: &ReadOptions) -> Result<Self> {
-        let mut file = File::open(filename)?;
-        let meta = read_metadata(&mut file)?;
+        Self::from_file(open_file(filename.as_ref(), opts)?, opts)
+    }
+
+    /// Read the sstable from memory, e.g. as written by `SSTableWriterV2::finish_to_vec`.
+    pub fn from_bytes(data: Bytes) -> Result<Self> {
+        Self::from_bytes_with_options(data, &ReadOptions::default())
+    }
+
+    /// Read the sstable from memory with explicit options, "env" is ignored.
+    ///
+    /// With "use_mmap", chunks that are neither compressed nor encrypted are used from
+    /// "data" without copying.
+    pub fn from_bytes_with_options(data: Bytes, opts: &ReadOptions) -> Result<Self> {
+        Self::from_file(Arc::new(MemFile::new(data)), opts)
+    }
+
+    /// Read the sstable from a file of any storage, e.g. a `RangeReadFile`, "env" is ignored.
+    pub fn from_file(file: Arc<dyn RandomAccessFile>, opts: &ReadOptions) -> Result<Self> {
+        let meta = read_metadata(FileReader::new(file.as_ref(), 0))?;
         let data_start = meta.offset as u64;
-        let inner = InnerReader::new(file, data_start, meta, opts)?;
+        let mut inner = InnerReader::new(file, data_start, meta, opts)?;
+        inner.warm_ranges(opts)?;
         Ok(SSTableReader { inner })
     }
     pub fn get(&mut self, key: &[u8]) -> Result<Option<&[u8]>> {
         self.inner.get(key)
     }
+
+    /// Get a key from the sstable with options.
+    pub fn get_with_options(
+        &mut self,
+        key: &[u8],
+        options: Option<GetOptions>,
+    ) -> Result<Option<&[u8]>> {
+        self.inner.get_with_options(key, options)
+    }
+
+    /// Returns false if no key in the sstable starts with "prefix".
+    ///
+    /// This can only rule prefixes out if the sstable was written with a prefix extractor
+    /// (see `WriteOptions::prefix_extractor`), and the extractor extracts a prefix from
+    /// "prefix" itself. Otherwise it returns true.
+    pub fn may_contain_prefix(&mut self, prefix: &[u8]) -> Result<bool> {
+        self.inner.may_contain_prefix(prefix)
+    }
+
+    /// Returns false if no key in the sstable is in the range.
+    ///
+    /// This can only rule ranges out if the sstable was written with a range filter
+    /// (see `WriteOptions::range_filter`), and works best for short ranges whose ends share
+    /// a prefix. Otherwise it returns true. `range` checks it first, unless
+    /// `ReadOptions::use_bloom` is off.
+    pub fn may_contain_range(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Result<bool> {
+        let range_filter = self.inner.filters.range_filter()?;
+        Ok(range_filter_may_contain(range_filter, start, end))
+    }
+
+    /// Iterate over the records with keys in the range, in key order.
+    ///
+    /// Deleted keys are included with empty values, same as `get` returns them.
+    pub fn range(&mut self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Result<RangeIter<'_>> {
+        self.inner.range(owned_bound(start), owned_bound(end), None)
+    }
+
+    /// Iterate over the records with keys in the range with options.
+    ///
+    /// `GetOptions::use_bloom` turns the range filter on or off, and scans that unset
+    /// `GetOptions::fill_cache` don't evict the chunks cached for other reads. With
+    /// `GetOptions::dont_need` they don't evict the pages of other reads from the OS page
+    /// cache either.
+    pub fn range_with_options(
+        &mut self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        options: Option<GetOptions>,
+    ) -> Result<RangeIter<'_>> {
+        self.inner
+            .range(owned_bound(start), owned_bound(end), options)
+    }
+
+    /// Iterate over the records with keys starting with "prefix", in key order.
+    ///
+    /// If the sstable has a prefix extractor, the bloom filters are checked first,
+    /// unless `ReadOptions::use_bloom` is off, see `may_contain_prefix`.
+    pub fn prefix(&mut self, prefix: &[u8]) -> Result<RangeIter<'_>> {
+        self.inner.prefix(prefix)
+    }
+
+    /// Read the chunks with keys in the range into the caches, so that reads of the range
+    /// don't go to disk, e.g. before taking traffic. The filters are loaded too.
+    ///
+    /// The chunks may be evicted later as usual. Chunks of mmap'ed sstables that are not
+    /// compressed or encrypted are not cached, the OS is told to read them in instead,
+    /// see `Advice::WillNeed`. To warm the sstable as it's opened, see `ReadOptions::warm`.
+    pub fn warm(&mut self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Result<()> {
+        self.inner.warm(start, end, false)
+    }
+
+    /// Read the chunks with keys in the range into the caches like `warm`, and never
+    /// evict them.
+    ///
+    /// Pinned chunks count against the budget of the cache they are in. They are
+    /// released when the reader is dropped. To pin chunks as the sstable is opened, see
+    /// `ReadOptions::pin`.
+    pub fn pin(&mut self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Result<()> {
+        self.inner.warm(start, end, true)
+    }
+
+    /// The memory held by the reader in bytes, by the index, the filters and each layer
+    /// of caches.
+    pub fn memory_usage(&self) -> page_cache::MemoryUsage {
+        self.inner.memory_usage()
+    }
 }
 
 /// A reader that can be used efficiently from multiple threads.
@@ -541,15 +1802,110 @@ impl ConcurrentSSTableReader {
     }
 
     pub fn new_with_options<P: AsRef<Path>>(filename: P, opts: &ReadOptions) -> Result<Self> {
-        let mut file = File::open(filename)?;
-        let meta = read_metadata(&mut file)?;
+        Self::from_file(open_file(filename.as_ref(), opts)?, opts)
+    }
+
+    /// Read the sstable from memory, e.g. as written by `SSTableWriterV2::finish_to_vec`.
+    pub fn from_bytes(data: Bytes) -> Result<Self> {
+        Self::from_bytes_with_options(data, &ReadOptions::default())
+    }
+
+    /// Read the sstable from memory with explicit options, "env" is ignored.
+    ///
+    /// With "use_mmap", chunks that are neither compressed nor encrypted are used from
+    /// "data" without copying.
+    pub fn from_bytes_with_options(data: Bytes, opts: &ReadOptions) -> Result<Self> {
+        Self::from_file(Arc::new(MemFile::new(data)), opts)
+    }
+
+    /// Read the sstable from a file of any storage, e.g. a `RangeReadFile`, "env" is ignored.
+    pub fn from_file(file: Arc<dyn RandomAccessFile>, opts: &ReadOptions) -> Result<Self> {
+        let meta = read_metadata(FileReader::new(file.as_ref(), 0))?;
         let data_start = meta.offset as u64;
         let inner = ConcurrentInnerReader::new(file, data_start, meta, opts)?;
+        inner.warm_ranges(opts)?;
         Ok(Self { inner })
     }
     pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
         self.inner.get(key)
     }
+
+    /// Get a key from the sstable with options.
+    pub fn get_with_options(
+        &self,
+        key: &[u8],
+        options: Option<GetOptions>,
+    ) -> Result<Option<Bytes>> {
+        self.inner.get_with_options(key, options)
+    }
+
+    /// Returns false if no key in the sstable starts with "prefix".
+    ///
+    /// This can only rule prefixes out if the sstable was written with a prefix extractor
+    /// (see `WriteOptions::prefix_extractor`), and the extractor extracts a prefix from
+    /// "prefix" itself. Otherwise it returns true.
+    pub fn may_contain_prefix(&self, prefix: &[u8]) -> Result<bool> {
+        self.inner.may_contain_prefix(prefix)
+    }
+
+    /// Returns false if no key in the sstable is in the range.
+    ///
+    /// This can only rule ranges out if the sstable was written with a range filter
+    /// (see `WriteOptions::range_filter`), and works best for short ranges whose ends share
+    /// a prefix. Otherwise it returns true. `range` checks it first, unless
+    /// `ReadOptions::use_bloom` is off.
+    pub fn may_contain_range(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Result<bool> {
+        let range_filter = self.inner.filters.range_filter()?;
+        Ok(range_filter_may_contain(range_filter, start, end))
+    }
+
+    /// Iterate over the records with keys in the range, in key order.
+    ///
+    /// Deleted keys are included with empty values, same as `get` returns them.
+    pub fn range(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Result<ConcurrentRangeIter<'_>> {
+        self.inner.range(owned_bound(start), owned_bound(end), None)
+    }
+
+    /// Iterate over the records with keys in the range with options.
+    ///
+    /// See `SSTableReader::range_with_options`. With `GetOptions::prefetch`, the next chunks
+    /// are read and uncompressed on background threads while the scan goes through the
+    /// current one.
+    pub fn range_with_options(
+        &self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        options: Option<GetOptions>,
+    ) -> Result<ConcurrentRangeIter<'_>> {
+        self.inner
+            .range(owned_bound(start), owned_bound(end), options)
+    }
+
+    /// Iterate over the records with keys starting with "prefix", in key order.
+    ///
+    /// If the sstable has a prefix extractor, the bloom filters are checked first,
+    /// unless `ReadOptions::use_bloom` is off, see `may_contain_prefix`.
+    pub fn prefix(&self, prefix: &[u8]) -> Result<ConcurrentRangeIter<'_>> {
+        self.inner.prefix(prefix)
+    }
+
+    /// Read the chunks with keys in the range into the caches, see `SSTableReader::warm`.
+    ///
+    /// This can be called from a background thread while the reader is used.
+    pub fn warm(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Result<()> {
+        self.inner.warm(start, end, false)
+    }
+
+    /// Read the chunks with keys in the range into the caches, and never evict them,
+    /// see `SSTableReader::pin`.
+    pub fn pin(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Result<()> {
+        self.inner.warm(start, end, true)
+    }
+
+    /// The memory held by the reader in bytes, see `SSTableReader::memory_usage`.
+    pub fn memory_usage(&self) -> page_cache::MemoryUsage {
+        self.inner.memory_usage()
+    }
 }
 
 /// A multi-threaded reader that only works with fully uncompressed data.
@@ -558,15 +1914,19 @@ impl ConcurrentSSTableReader {
 /// Everything just relies on the OS page cache to work, so if you are ok with storing
 /// uncompressed sstables, this reader the way to go.
 ///
-/// If you try to use it with a compressed sstable it will return `Error::CantUseCompressedFileWithMultiThreadedMmap`
+/// If you try to use it with a compressed sstable it will return `Error::CantUseCompressedFileWithMultiThreadedMmap`,
+/// and `Error::CantUseEncryptedFileWithMultiThreadedMmap` with an encrypted one.
 ///
 /// If you want to use this with multiple threads just put it into an Arc without Mutex'es.
 pub struct MmapUncompressedSSTableReader {
-    index_start: u64,
-    mmap: memmap::Mmap,
-    index: MemIndex,
+    data_end: u64,
+    mmap: MappedFile,
+    index: Box<dyn Index + Send + Sync>,
     use_bloom_default: bool,
-    bloom: Bloom<[u8]>,
+    filters: LazyFilters,
+    value_uncompress: Option<ValueUncompress>,
+    prefix_extractor: Option<PrefixExtractor>,
+    filter_type: FilterType,
 }
 
 impl MmapUncompressedSSTableReader {
@@ -579,28 +1939,54 @@ impl MmapUncompressedSSTableReader {
 
     /// Construct a new mmap reader from a file.
     ///
-    /// All options except "use_bloom" are ignored.
+    /// All options except "use_bloom", "codecs", "env" and "access_pattern" are ignored.
+    ///
+    /// Returns `Error::CantUseCompressedFileWithMultiThreadedMmap` if you try to open a file
+    /// with compressed data with it. The index and the bloom filter may be compressed.
     ///
-    /// Returns `Error::CantUseCompressedFileWithMultiThreadedMmap` if you try to open a compressed file with it.
+    /// Returns `Error::CantUseEncryptedFileWithMultiThreadedMmap` for encrypted files, and
+    /// `Error::CantMapFile` if the file from `ReadOptions::env` can't be mapped.
     pub fn new_with_options<P: AsRef<Path>>(filename: P, opts: &ReadOptions) -> Result<Self> {
-        let mut file = File::open(filename)?;
-        let meta = read_metadata(&mut file)?;
+        Self::from_file(opts.env.open(filename.as_ref())?, opts)
+    }
+
+    /// Read the sstable from memory, e.g. as written by `SSTableWriterV2::finish_to_vec`.
+    pub fn from_bytes(data: Bytes) -> Result<Self> {
+        Self::from_bytes_with_options(data, &ReadOptions::default())
+    }
+
+    /// Read the sstable from memory with explicit options, "env" is ignored.
+    ///
+    /// The values are borrowed from "data" same as from the mmap'ed file.
+    pub fn from_bytes_with_options(data: Bytes, opts: &ReadOptions) -> Result<Self> {
+        Self::from_file(Arc::new(MemFile::new(data)), opts)
+    }
+
+    /// Read the sstable from a copy of "data". Use `from_bytes` to not copy it.
+    pub fn from_slice(data: &[u8]) -> Result<Self> {
+        Self::from_bytes(Bytes::copy_from_slice(data))
+    }
+
+    fn from_file(file: Arc<dyn RandomAccessFile>, opts: &ReadOptions) -> Result<Self> {
+        let meta = read_metadata(FileReader::new(file.as_ref(), 0))?;
         let data_start = meta.offset as u64;
 
         #[allow(clippy::infallible_destructuring_match)]
         let meta = match meta.meta {
-            MetaData::V3_0(meta) => meta,
+            MetaData::V3_2(meta) => meta,
         };
 
         if meta.compression != Compression::None {
             return Err(Error::CantUseCompressedFileWithMultiThreadedMmap);
         }
+        if meta.encryption.is_some() {
+            return Err(Error::CantUseEncryptedFileWithMultiThreadedMmap);
+        }
 
-        let index_start = data_start + (meta.data_len as u64);
-        let index_end = index_start + meta.index_len;
+        let index_start = data_start + meta.data_len + meta.filter_len;
+        let bloom_start = index_start + meta.index_len;
 
-        file.seek(SeekFrom::Start(index_start))?;
-        let mmap = unsafe { memmap::Mmap::map(&file) }?;
+        let mmap = file.map()?.ok_or(Error::CantMapFile)?;
         let mmap_buf = {
             let buf = &mmap as &[u8];
             let buf = buf as *const [u8];
@@ -608,28 +1994,45 @@ impl MmapUncompressedSSTableReader {
             buf
         };
 
-        // if it was mmaped, it won't truncate
-        #[allow(clippy::cast_possible_truncation)]
-        let index = MemIndex::from_static_buf(
-            &mmap_buf
-                .get(index_start as usize..index_end as usize)
-                .ok_or(INVALID_DATA)?,
-            meta.index_len,
+        let index = read_index(
+            file.as_ref(),
+            Some(mmap_buf),
+            &meta,
+            index_start,
+            &opts.codecs,
+            None,
         )?;
-
-        file.seek(SeekFrom::Start(index_end))?;
-        let bloom = read_bloom((&mut file).take(meta.bloom_len), &meta.bloom)?;
+        let filters = LazyFilters::new(
+            &file,
+            Some(mmap_buf),
+            &meta,
+            bloom_start,
+            &opts.codecs,
+            None,
+        );
+        Advisor {
+            file,
+            mmap: Some(mmap.clone()),
+        }
+        .advise_access_pattern(opts)?;
 
         Ok(Self {
             mmap,
             index,
-            index_start,
-            bloom,
+            data_end: data_start + meta.data_len,
+            filters,
             use_bloom_default: opts.use_bloom,
+            value_uncompress: ValueUncompress::new(&meta, &opts.codecs)?,
+            prefix_extractor: meta.prefix_extractor,
+            filter_type: meta.filter_type,
         })
     }
 
-    pub fn get<'a>(&'a self, key: &[u8]) -> Result<Option<&'a [u8]>> {
+    /// Get a key from the sstable.
+    ///
+    /// The value is borrowed from the mmap'ed file, unless it was compressed on its own
+    /// (see `WriteOptions::value_compression`), in which case it's uncompressed into a new buffer.
+    pub fn get<'a>(&'a self, key: &[u8]) -> Result<Option<Cow<'a, [u8]>>> {
         self.get_with_options(key, None)
     }
 
@@ -638,22 +2041,306 @@ impl MmapUncompressedSSTableReader {
         &'a self,
         key: &[u8],
         options: Option<GetOptions>,
-    ) -> Result<Option<&'a [u8]>> {
+    ) -> Result<Option<Cow<'a, [u8]>>> {
         let use_bloom = options
             .map(|o| o.use_bloom)
             .unwrap_or(self.use_bloom_default);
-        if use_bloom && !self.bloom.check(key) {
+        let key_hash = bloom_key_hash(key);
+        if use_bloom && !bloom_may_contain(self.filters.bloom()?, key_hash) {
             return Ok(None);
         }
-        let (offset, right_bound) = match self.index.find_bounds(key, self.index_start) {
+        let bounds = match self.index.find_bounds(key, self.data_end) {
             Some(v) => v,
             None => return Ok(None),
         };
+        if let (true, Some(filter)) = (use_bloom, bounds.filter) {
+            // if it was mmaped, it won't truncate
+            #[allow(clippy::cast_possible_truncation)]
+            let filter = self
+                .mmap
+                .get(filter.offset as usize..(filter.offset + filter.length) as usize)
+                .ok_or(INVALID_DATA)?;
+            if !filter::may_contain(self.filter_type, filter, key_hash) {
+                return Ok(None);
+            }
+        }
+        let handle = bounds.chunk;
 
         // if it was mmaped, it won't truncate
         #[allow(clippy::cast_possible_truncation)]
-        let buf = &self.mmap[offset as usize..right_bound as usize];
+        let buf = &self.mmap[handle.offset as usize..(handle.offset + handle.length) as usize];
+
+        let location = match find_value_offset_v2(buf, key)? {
+            Some(location) => location,
+            None => return Ok(None),
+        };
+        let value = &buf[location.start..location.end];
+        if !location.is_compressed() {
+            return Ok(Some(Cow::Borrowed(value)));
+        }
+        let mut uncompressed = Vec::new();
+        uncompress_value(value, self.value_uncompress.as_ref(), &mut uncompressed)?;
+        Ok(Some(Cow::Owned(uncompressed)))
+    }
+
+    /// Returns false if no key in the sstable starts with "prefix".
+    ///
+    /// See `SSTableReader::may_contain_prefix`.
+    pub fn may_contain_prefix(&self, prefix: &[u8]) -> Result<bool> {
+        may_contain_prefix(
+            self.prefix_extractor,
+            self.filters.bloom()?,
+            self.index
+                .chunks_from(Bound::Included(prefix), self.data_end),
+            prefix,
+            |filter, prefix_hash| {
+                Ok(filter::may_contain(
+                    self.filter_type,
+                    mmap_chunk(&self.mmap, filter)?,
+                    prefix_hash,
+                ))
+            },
+        )
+    }
+
+    /// Iterate over the records with keys in the range, in key order.
+    ///
+    /// Values are borrowed from the mmap'ed file same as in `get`.
+    pub fn range<'a>(
+        &'a self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+    ) -> Result<MmapRangeIter<'a>> {
+        self.range_owned(owned_bound(start), owned_bound(end))
+    }
 
-        Ok(find_value_offset_v2(buf, key)?.map(|(start, end)| &buf[start..end]))
+    fn range_owned(&self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> Result<MmapRangeIter<'_>> {
+        let range_filter = if self.use_bloom_default {
+            self.filters.range_filter()?
+        } else {
+            None
+        };
+        Ok(MmapRangeIter {
+            cursor: RangeCursor::new(self.index.as_ref(), self.data_end, start, end, range_filter),
+            mmap: &self.mmap,
+            value_uncompress: self.value_uncompress.as_ref(),
+        })
+    }
+
+    /// Iterate over the records with keys starting with "prefix", in key order.
+    ///
+    /// See `SSTableReader::prefix`.
+    pub fn prefix<'a>(&'a self, prefix: &[u8]) -> Result<MmapRangeIter<'a>> {
+        let mut iter = self.range_owned(Bound::Included(prefix.to_owned()), prefix_end(prefix))?;
+        iter.cursor.done |= self.use_bloom_default && !self.may_contain_prefix(prefix)?;
+        Ok(iter)
+    }
+
+    /// Returns false if no key in the sstable is in the range.
+    ///
+    /// See `SSTableReader::may_contain_range`.
+    pub fn may_contain_range(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Result<bool> {
+        let range_filter = self.filters.range_filter()?;
+        Ok(range_filter_may_contain(range_filter, start, end))
+    }
+
+    /// The memory held by the reader in bytes. There are no caches, the chunks are read
+    /// from the mmap'ed file.
+    pub fn memory_usage(&self) -> page_cache::MemoryUsage {
+        page_cache::MemoryUsage {
+            index: self.index.memory_usage(),
+            filters: self.filters.memory_usage(),
+            ..page_cache::MemoryUsage::default()
+        }
+    }
+}
+
+/// Get the chunk from the mmap'ed file.
+fn mmap_chunk(mmap: &[u8], chunk: page_cache::ChunkHandle) -> Result<&[u8]> {
+    // if it was mmaped, it won't truncate
+    #[allow(clippy::cast_possible_truncation)]
+    mmap.get(chunk.offset as usize..(chunk.offset + chunk.length) as usize)
+        .ok_or(INVALID_DATA)
+}
+
+/// Walks the records of the chunks in a key range.
+///
+/// The iterators of the readers are built on it, each fetching the chunks its own way.
+struct RangeCursor<'a, C> {
+    chunks: ChunkIter<'a>,
+    chunk: C,
+    // The offset of the next record in the chunk.
+    offset: usize,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    done: bool,
+}
+
+impl<'a, C: AsRef<[u8]> + Default> RangeCursor<'a, C> {
+    /// Make a cursor over the range. If "range_filter" is set, and says the range is
+    /// empty, the cursor is done right away.
+    fn new(
+        index: &'a dyn Index,
+        data_end: u64,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        range_filter: Option<&filter::TableFilter>,
+    ) -> Self {
+        Self {
+            chunks: index.chunks_from(bound_ref(&start), data_end),
+            chunk: C::default(),
+            offset: 0,
+            done: !range_filter_may_contain(range_filter, bound_ref(&start), bound_ref(&end)),
+            start,
+            end,
+        }
+    }
+
+    /// Move to the next record in the range, getting chunks with "get_chunk".
+    ///
+    /// Returns the location of the record in "self.chunk". The cursor stops at errors.
+    fn next_record<F>(&mut self, get_chunk: F) -> Option<Result<RecordLocation>>
+    where
+        F: FnMut(page_cache::ChunkHandle) -> Result<C>,
+    {
+        let result = self.advance(get_chunk).transpose();
+        if let Some(Err(_)) = result {
+            self.done = true;
+        }
+        result
+    }
+
+    fn advance<F>(&mut self, mut get_chunk: F) -> Result<Option<RecordLocation>>
+    where
+        F: FnMut(page_cache::ChunkHandle) -> Result<C>,
+    {
+        while !self.done {
+            if self.offset >= self.chunk.as_ref().len() {
+                match self.chunks.next() {
+                    Some((first_key, bounds)) if before_end(first_key, &self.end) => {
+                        self.chunk = get_chunk(bounds.chunk)?;
+                        self.offset = 0;
+                    }
+                    _ => self.done = true,
+                }
+                continue;
+            }
+            let record = read_record(self.chunk.as_ref(), self.offset)?;
+            self.offset = record.next;
+            let key = &self.chunk.as_ref()[record.key.clone()];
+            if !after_start(key, &self.start) {
+                continue;
+            }
+            if !before_end(key, &self.end) {
+                self.done = true;
+                continue;
+            }
+            return Ok(Some(record));
+        }
+        Ok(None)
+    }
+}
+
+/// An iterator over the records of `SSTableReader` in a key range.
+///
+/// The keys and values are copied, as the chunks they are in may be evicted from the cache
+/// before the next record is read.
+pub struct RangeIter<'a> {
+    cursor: RangeCursor<'a, Vec<u8>>,
+    page_cache: &'a mut Box<dyn page_cache::PageCache>,
+    value_uncompress: Option<&'a ValueUncompress>,
+    fill_cache: bool,
+    dont_need: Option<DontNeed<'a>>,
+}
+
+impl Iterator for RangeIter<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let page_cache = &mut self.page_cache;
+        let fill_cache = self.fill_cache;
+        let dont_need = &mut self.dont_need;
+        let record = match self.cursor.next_record(|chunk| {
+            if let Some(dont_need) = dont_need.as_mut() {
+                dont_need.moved_to(chunk);
+            }
+            let chunk = if fill_cache {
+                page_cache.get_chunk(chunk)?
+            } else {
+                page_cache.get_chunk_no_fill(chunk)?
+            };
+            Ok(chunk.to_vec())
+        })? {
+            Ok(record) => record,
+            Err(e) => return Some(Err(e)),
+        };
+        let chunk = &self.cursor.chunk;
+        Some(
+            record_value(chunk, &record.value, self.value_uncompress)
+                .map(|value| (chunk[record.key].to_vec(), value.into_owned())),
+        )
+    }
+}
+
+/// An iterator over the records of `ConcurrentSSTableReader` in a key range.
+pub struct ConcurrentRangeIter<'a> {
+    cursor: RangeCursor<'a, Bytes>,
+    reader: &'a ConcurrentInnerReader,
+    fill_cache: bool,
+    dont_need: Option<DontNeed<'a>>,
+    prefetch: Option<Prefetch<'a>>,
+}
+
+impl Iterator for ConcurrentRangeIter<'_> {
+    type Item = Result<(Bytes, Bytes)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let reader = self.reader;
+        let fill_cache = self.fill_cache;
+        let dont_need = &mut self.dont_need;
+        let prefetch = &mut self.prefetch;
+        let record = match self.cursor.next_record(|chunk| {
+            if let Some(dont_need) = dont_need.as_mut() {
+                dont_need.moved_to(chunk);
+            }
+            match prefetch.as_mut() {
+                Some(prefetch) => prefetch.get_chunk(chunk),
+                None => read_chunk(&reader.page_cache, chunk, fill_cache),
+            }
+        })? {
+            Ok(record) => record,
+            Err(e) => return Some(Err(e)),
+        };
+        let chunk = &self.cursor.chunk;
+        let value = match record_value(chunk, &record.value, reader.value_uncompress.as_ref()) {
+            Ok(Cow::Borrowed(_)) => chunk.slice(record.value.start..record.value.end),
+            Ok(Cow::Owned(value)) => Bytes::from(value),
+            Err(e) => return Some(Err(e)),
+        };
+        Some(Ok((chunk.slice(record.key), value)))
+    }
+}
+
+/// An iterator over the records of `MmapUncompressedSSTableReader` in a key range.
+pub struct MmapRangeIter<'a> {
+    cursor: RangeCursor<'a, &'a [u8]>,
+    mmap: &'a [u8],
+    value_uncompress: Option<&'a ValueUncompress>,
+}
+
+impl<'a> Iterator for MmapRangeIter<'a> {
+    type Item = Result<(&'a [u8], Cow<'a, [u8]>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mmap = self.mmap;
+        let record = match self.cursor.next_record(|chunk| mmap_chunk(mmap, chunk))? {
+            Ok(record) => record,
+            Err(e) => return Some(Err(e)),
+        };
+        let chunk: &'a [u8] = self.cursor.chunk;
+        Some(
+            record_value(chunk, &record.value, self.value_uncompress)
+                .map(|value| (&chunk[record.key], value)),
+        )
     }
 }
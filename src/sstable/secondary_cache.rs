@@ -0,0 +1,261 @@
+//! A cache of uncompressed chunks in a file on local disk, that survives restarts.
+
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::hash::Hasher;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use siphasher::sip::SipHasher13;
+
+use super::Result;
+
+/// A chunk is identified by its table (see `RandomAccessFile::unique_id`) and its offset in it.
+type ChunkKey = (u64, u64);
+
+/// Where a chunk is in the file, and the checksum of its contents.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+struct Entry {
+    position: u64,
+    length: u64,
+    checksum: u64,
+}
+
+/// The index as it's persisted next to the chunks.
+#[derive(Serialize, Deserialize)]
+struct PersistedIndex {
+    capacity: u64,
+    next_position: u64,
+    entries: Vec<(ChunkKey, Entry)>,
+}
+
+struct Index {
+    entries: HashMap<ChunkKey, Entry>,
+    // The keys of the entries by their position, to find the ones that get overwritten.
+    positions: BTreeMap<u64, ChunkKey>,
+    // Where the next chunk is written.
+    next_position: u64,
+}
+
+impl Index {
+    fn insert(&mut self, key: ChunkKey, entry: Entry) {
+        self.remove(&key);
+        self.positions.insert(entry.position, key);
+        self.entries.insert(key, entry);
+    }
+
+    fn remove(&mut self, key: &ChunkKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.positions.remove(&entry.position);
+        }
+    }
+
+    /// Find room for "length" bytes, evicting the chunks stored there.
+    fn reserve(&mut self, length: u64, capacity: u64) -> u64 {
+        if self.next_position + length > capacity {
+            self.next_position = 0;
+        }
+        let start = self.next_position;
+        let end = start + length;
+        // The entries don't overlap, so the ones ending after "start" are the last ones.
+        let overwritten: Vec<ChunkKey> = self
+            .positions
+            .range(..end)
+            .rev()
+            .take_while(|(position, key)| {
+                let length = self.entries.get(key).map(|e| e.length).unwrap_or(0);
+                **position + length > start
+            })
+            .map(|(_, key)| *key)
+            .collect();
+        for key in overwritten.iter() {
+            self.remove(key);
+        }
+        self.next_position = end;
+        start
+    }
+}
+
+struct Inner {
+    dir: PathBuf,
+    file: File,
+    capacity: u64,
+    index: Mutex<Index>,
+}
+
+impl Inner {
+    fn persist(&self) -> Result<()> {
+        let persisted = {
+            let index = self.index.lock();
+            PersistedIndex {
+                capacity: self.capacity,
+                next_position: index.next_position,
+                entries: index.entries.iter().map(|(k, e)| (*k, *e)).collect(),
+            }
+        };
+        // The chunks must be on disk before the index that points to them.
+        self.file.sync_data()?;
+        let tmp = self.dir.join("index.tmp");
+        let mut file = File::create(&tmp)?;
+        bincode::serialize_into(&mut file, &persisted)?;
+        file.sync_all()?;
+        fs::rename(tmp, self.dir.join("index"))?;
+        Ok(())
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        // There's no one to report the error to. The chunks are checked when they are read,
+        // so a stale index only costs misses.
+        let _ = self.persist();
+    }
+}
+
+/// The checksum of a chunk in the file.
+fn checksum(data: &[u8]) -> u64 {
+    let mut hasher = SipHasher13::new_with_keys(0x7373_7462_7365_636f, 0x6e64_6172_795f_6361);
+    hasher.write(data);
+    hasher.finish()
+}
+
+/// A cache of uncompressed chunks in a file on local disk, consulted before uncompressing
+/// chunks that were evicted from the memory caches, or not cached yet after a restart.
+///
+/// The chunks are written one after another into a file of at most "capacity" bytes,
+/// wrapping around to the start when it's full, so the oldest chunks are evicted first.
+/// Every chunk has a checksum, and a chunk that doesn't match it is a miss. The readers
+/// treat errors reading the cache as misses too, and ignore errors writing it.
+///
+/// The index of the chunks is persisted with `persist`, and when the last handle to the
+/// cache is dropped. Cloning the cache makes another handle to the same cache.
+///
+/// Pass it to the readers with `ReadOptions::secondary_cache`. Only compressed sstables
+/// use it, as uncompressed chunks are read from the sstable itself just as fast, and only
+/// if their file has a `RandomAccessFile::unique_id`. Encrypted sstables don't use it, as
+/// it stores the chunks in plaintext.
+///
+/// Example:
+/// ```
+/// use sstb::sstable::{ReadOptions, SecondaryCache};
+///
+/// let cache = SecondaryCache::open("/tmp/sstb-secondary-cache-example", 64 * 1024 * 1024).unwrap();
+/// let mut options = ReadOptions::default();
+/// options.secondary_cache(Some(cache));
+/// ```
+#[derive(Clone)]
+pub struct SecondaryCache {
+    inner: Arc<Inner>,
+}
+
+impl SecondaryCache {
+    /// Open the cache in "dir", creating it if needed, with the chunks persisted there.
+    ///
+    /// If the cache was persisted with another capacity, it starts empty.
+    pub fn open<P: AsRef<Path>>(dir: P, capacity: usize) -> Result<Self> {
+        let dir = dir.as_ref().to_owned();
+        let capacity = u64::try_from(capacity)?;
+        fs::create_dir_all(&dir)?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            // The chunks of the persisted index are kept.
+            .truncate(false)
+            .open(dir.join("chunks"))?;
+
+        let mut index = Index {
+            entries: HashMap::new(),
+            positions: BTreeMap::new(),
+            next_position: 0,
+        };
+        let persisted = File::open(dir.join("index"))
+            .ok()
+            .and_then(|file| bincode::deserialize_from::<_, PersistedIndex>(file).ok())
+            .filter(|persisted| persisted.capacity == capacity);
+        if let Some(persisted) = persisted {
+            let file_len = file.metadata()?.len();
+            index.next_position = persisted.next_position.min(capacity);
+            for (key, entry) in persisted.entries {
+                if entry.position + entry.length <= file_len {
+                    index.insert(key, entry);
+                }
+            }
+        }
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                dir,
+                file,
+                capacity,
+                index: Mutex::new(index),
+            }),
+        })
+    }
+
+    /// Read the chunk at "offset" of the table into "buf". Returns false if it's not
+    /// cached with the length of "buf".
+    pub fn get(&self, table_key: u64, offset: u64, buf: &mut [u8]) -> Result<bool> {
+        let key = (table_key, offset);
+        let entry = match self.inner.index.lock().entries.get(&key) {
+            Some(entry) => *entry,
+            None => return Ok(false),
+        };
+        if entry.length != u64::try_from(buf.len())? {
+            return Ok(false);
+        }
+        // The lock is not held while reading, the checksum catches chunks that got
+        // overwritten in the meantime.
+        self.inner.file.read_exact_at(buf, entry.position)?;
+        if checksum(buf) != entry.checksum {
+            self.inner.index.lock().remove(&key);
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// Cache the chunk at "offset" of the table.
+    ///
+    /// Chunks larger than the capacity are not cached.
+    pub fn put(&self, table_key: u64, offset: u64, chunk: &[u8]) -> Result<()> {
+        let key = (table_key, offset);
+        let length = u64::try_from(chunk.len())?;
+        if length > self.inner.capacity {
+            return Ok(());
+        }
+        let position = {
+            let mut index = self.inner.index.lock();
+            index.remove(&key);
+            index.reserve(length, self.inner.capacity)
+        };
+        // Another chunk may be written over this one before it's indexed, if the file
+        // wraps around meanwhile. The checksum catches that too.
+        self.inner.file.write_all_at(chunk, position)?;
+        let entry = Entry {
+            position,
+            length,
+            checksum: checksum(chunk),
+        };
+        self.inner.index.lock().insert(key, entry);
+        Ok(())
+    }
+
+    /// Write the index of the cached chunks to disk, so that they can be used after a
+    /// restart.
+    pub fn persist(&self) -> Result<()> {
+        self.inner.persist()
+    }
+}
+
+impl std::fmt::Debug for SecondaryCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SecondaryCache")
+            .field("dir", &self.inner.dir)
+            .field("capacity", &self.inner.capacity)
+            .finish()
+    }
+}
@@ -0,0 +1,493 @@
+//! Caches bounded either by the number of values or by their total size in bytes, with
+//! a choice of eviction policy, see `CachePolicy`.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+use bytes::Bytes;
+use lru::LruCache;
+
+use super::options::{CachePolicy, ReadCache};
+
+/// The number of bytes a cached value accounts for.
+pub trait CacheSize {
+    fn cache_size(&self) -> usize;
+}
+
+impl CacheSize for Vec<u8> {
+    fn cache_size(&self) -> usize {
+        // Buffers get reused, so they may have allocated more than their length.
+        self.capacity()
+    }
+}
+
+impl CacheSize for Bytes {
+    fn cache_size(&self) -> usize {
+        self.len()
+    }
+}
+
+/// The counters of FrequencySketch saturate at this.
+const MAX_FREQUENCY: u8 = 15;
+
+/// Multipliers to get the index of a key in each row of FrequencySketch from its hash.
+const SKETCH_SEEDS: [u64; 4] = [
+    0x9e37_79b9_7f4a_7c15,
+    0xc2b2_ae3d_27d4_eb4f,
+    0x1656_67b1_9e37_79f9,
+    0xff51_afd7_ed55_8ccd,
+];
+
+/// How often the keys were used recently, approximately. This is a count-min sketch
+/// with 4 rows, of 4 counters per value the cache can hold.
+///
+/// All the counters are halved after 10 uses per value the cache can hold, so that keys
+/// that were used often long ago don't stay in the cache forever.
+struct FrequencySketch {
+    counters: Vec<u8>,
+    width: usize,
+    additions: usize,
+    reset_at: usize,
+}
+
+impl FrequencySketch {
+    fn new(values: usize) -> Self {
+        let values = values.clamp(16, 1 << 20);
+        let width = (values * 4).next_power_of_two();
+        Self {
+            counters: vec![0; width * SKETCH_SEEDS.len()],
+            width,
+            additions: 0,
+            reset_at: values * 10,
+        }
+    }
+
+    fn indexes(&self, hash: u64) -> impl Iterator<Item = usize> + '_ {
+        SKETCH_SEEDS.iter().enumerate().map(move |(row, seed)| {
+            // it's ok to truncate the hash.
+            #[allow(clippy::cast_possible_truncation)]
+            let column = (hash.wrapping_mul(*seed) >> 32) as usize & (self.width - 1);
+            row * self.width + column
+        })
+    }
+
+    fn frequency(&self, hash: u64) -> u8 {
+        self.indexes(hash)
+            .map(|i| self.counters[i])
+            .min()
+            .unwrap_or_default()
+    }
+
+    fn increment(&mut self, hash: u64) {
+        let indexes: Vec<usize> = self.indexes(hash).collect();
+        for i in indexes {
+            if self.counters[i] < MAX_FREQUENCY {
+                self.counters[i] += 1;
+            }
+        }
+        self.additions += 1;
+        if self.additions >= self.reset_at {
+            for counter in self.counters.iter_mut() {
+                *counter /= 2;
+            }
+            self.additions /= 2;
+        }
+    }
+}
+
+fn key_hash<K: Hash>(k: &K) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    k.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Values in LRU order, with the total of their charges.
+struct Segment<K: Hash + Eq, V> {
+    lru: LruCache<K, (V, usize)>,
+    charge: usize,
+}
+
+impl<K: Hash + Eq, V> Segment<K, V> {
+    fn new() -> Self {
+        Self {
+            lru: LruCache::unbounded(),
+            charge: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.lru.len()
+    }
+
+    fn contains(&self, k: &K) -> bool {
+        self.lru.contains(k)
+    }
+
+    fn get(&mut self, k: &K) -> Option<&V> {
+        self.lru.get(k).map(|(v, _)| v)
+    }
+
+    fn peek(&self, k: &K) -> Option<&V> {
+        self.lru.peek(k).map(|(v, _)| v)
+    }
+
+    fn peek_lru_key(&self) -> Option<&K> {
+        self.lru.peek_lru().map(|(k, _)| k)
+    }
+
+    /// Push a value that is not in the segment yet.
+    fn push(&mut self, k: K, v: V, charge: usize) {
+        self.lru.put(k, (v, charge));
+        self.charge += charge;
+    }
+
+    fn pop(&mut self, k: &K) -> Option<(V, usize)> {
+        let (v, charge) = self.lru.pop(k)?;
+        self.charge -= charge;
+        Some((v, charge))
+    }
+
+    fn pop_lru(&mut self) -> Option<(K, V, usize)> {
+        let (k, (v, charge)) = self.lru.pop_lru()?;
+        self.charge -= charge;
+        Some((k, v, charge))
+    }
+
+    fn memory_usage(&self) -> usize
+    where
+        V: CacheSize,
+    {
+        self.lru.iter().map(|(_, (v, _))| v.cache_size()).sum()
+    }
+
+    /// Returns false if the value is not in the segment.
+    fn add_charge(&mut self, k: &K, charge: usize) -> bool {
+        match self.lru.peek_mut(k) {
+            Some((_, value_charge)) => {
+                *value_charge += charge;
+                self.charge += charge;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// W-TinyLFU: new values go to a small LRU window, and values evicted from the window
+/// only make it to the main cache if they were used more often recently than the
+/// values they would evict. The main cache keeps the values used more than once in its
+/// protected segment, the others in its probation segment.
+struct TinyLfu<K: Hash + Eq, V> {
+    window: Segment<K, V>,
+    probation: Segment<K, V>,
+    protected: Segment<K, V>,
+    sketch: FrequencySketch,
+}
+
+impl<K: Hash + Eq, V> TinyLfu<K, V> {
+    fn main_charge(&self) -> usize {
+        self.probation.charge + self.protected.charge
+    }
+
+    /// Move a value evicted from the window to the main cache, if it's used more often
+    /// than the values it would evict.
+    fn admit(&mut self, k: K, v: V, charge: usize, main_capacity: usize) {
+        let frequency = self.sketch.frequency(key_hash(&k));
+        while self.main_charge() + charge > main_capacity {
+            let victim = self
+                .probation
+                .peek_lru_key()
+                .or_else(|| self.protected.peek_lru_key());
+            let victim_frequency = match victim {
+                Some(victim) => self.sketch.frequency(key_hash(victim)),
+                // It doesn't fit even into the empty main cache.
+                None => return,
+            };
+            if frequency <= victim_frequency {
+                return;
+            }
+            if self.probation.pop_lru().is_none() {
+                self.protected.pop_lru();
+            }
+        }
+        self.probation.push(k, v, charge);
+    }
+}
+
+enum Policy<K: Hash + Eq, V> {
+    Lru(Segment<K, V>),
+    TinyLfu(Box<TinyLfu<K, V>>),
+}
+
+/// A cache that keeps track of the size of its values, and evicts them as configured by
+/// ReadCache and CachePolicy.
+///
+/// The most recently inserted value is never evicted, so a chunk larger than the whole
+/// budget can still be read.
+///
+/// Pinned values are never evicted either. They count against the capacity, so the
+/// other values get less of it.
+pub struct SizedCache<K: Hash + Eq + Clone, V> {
+    // In blocks or bytes. None if the cache is unbounded.
+    capacity: Option<usize>,
+    count_blocks: bool,
+    policy: Policy<K, V>,
+    pinned: HashMap<K, (V, usize)>,
+    pinned_charge: usize,
+}
+
+impl<K: Hash + Eq + Clone, V> SizedCache<K, V> {
+    pub fn new(cache: ReadCache, policy: CachePolicy) -> Self {
+        let (capacity, count_blocks) = match cache {
+            ReadCache::Blocks(blocks) => (Some(blocks), true),
+            ReadCache::Bytes(bytes) => (Some(bytes), false),
+            ReadCache::Unbounded => (None, true),
+        };
+        let policy = match policy {
+            CachePolicy::Lru => Policy::Lru(Segment::new()),
+            CachePolicy::TinyLfu => {
+                // Assume chunks of about 4KiB to size the sketch for a budget in bytes.
+                let values = match (capacity, count_blocks) {
+                    (Some(blocks), true) => blocks,
+                    (Some(bytes), false) => bytes / 4096,
+                    (None, _) => 0,
+                };
+                Policy::TinyLfu(Box::new(TinyLfu {
+                    window: Segment::new(),
+                    probation: Segment::new(),
+                    protected: Segment::new(),
+                    sketch: FrequencySketch::new(values),
+                }))
+            }
+        };
+        Self {
+            capacity,
+            count_blocks,
+            policy,
+            pinned: HashMap::new(),
+            pinned_charge: 0,
+        }
+    }
+
+    /// The capacity left for the values that are not pinned.
+    fn unpinned_capacity(&self) -> Option<usize> {
+        self.capacity
+            .map(|capacity| capacity.saturating_sub(self.pinned_charge))
+    }
+
+    fn charge(&self, size: usize) -> usize {
+        if self.count_blocks {
+            1
+        } else {
+            size
+        }
+    }
+
+    /// The total size of the values in bytes, even if the cache counts blocks.
+    pub fn memory_usage(&self) -> usize
+    where
+        V: CacheSize,
+    {
+        let cached = match &self.policy {
+            Policy::Lru(lru) => lru.memory_usage(),
+            Policy::TinyLfu(cache) => {
+                cache.window.memory_usage()
+                    + cache.probation.memory_usage()
+                    + cache.protected.memory_usage()
+            }
+        };
+        let pinned: usize = self.pinned.values().map(|(v, _)| v.cache_size()).sum();
+        cached + pinned
+    }
+
+    /// Get the value, and count it as used.
+    pub fn get(&mut self, k: &K) -> Option<&V> {
+        if self.pinned.contains_key(k) {
+            return self.pinned.get(k).map(|(v, _)| v);
+        }
+        let capacity = self.unpinned_capacity();
+        match &mut self.policy {
+            Policy::Lru(lru) => lru.get(k),
+            Policy::TinyLfu(cache) => {
+                cache.sketch.increment(key_hash(k));
+                if cache.window.contains(k) {
+                    return cache.window.get(k);
+                }
+                if cache.protected.contains(k) {
+                    return cache.protected.get(k);
+                }
+                // The value is used the second time, so it's protected now.
+                let (v, charge) = cache.probation.pop(k)?;
+                cache.protected.push(k.clone(), v, charge);
+                if let Some(capacity) = capacity {
+                    let protected_capacity = main_capacity(capacity) * 4 / 5;
+                    while cache.protected.charge > protected_capacity && cache.protected.len() > 1 {
+                        if let Some((k, v, charge)) = cache.protected.pop_lru() {
+                            cache.probation.push(k, v, charge);
+                        }
+                    }
+                }
+                cache.protected.peek(k)
+            }
+        }
+    }
+
+    /// Get the value without counting it as used.
+    pub fn peek(&self, k: &K) -> Option<&V> {
+        if let Some((v, _)) = self.pinned.get(k) {
+            return Some(v);
+        }
+        match &self.policy {
+            Policy::Lru(lru) => lru.peek(k),
+            Policy::TinyLfu(cache) => cache
+                .window
+                .peek(k)
+                .or_else(|| cache.probation.peek(k))
+                .or_else(|| cache.protected.peek(k)),
+        }
+    }
+
+    /// Returns true if caching another value of "size" bytes would evict the least
+    /// recently used value.
+    ///
+    /// This is always false with CachePolicy::TinyLfu, as there it depends on how often
+    /// the values were used.
+    pub fn is_full(&self, size: usize) -> bool {
+        match (&self.policy, self.unpinned_capacity()) {
+            (Policy::Lru(lru), Some(capacity)) => {
+                lru.len() > 0 && lru.charge + self.charge(size) > capacity
+            }
+            _ => false,
+        }
+    }
+
+    pub fn pop_lru(&mut self) -> Option<V> {
+        let popped = match &mut self.policy {
+            Policy::Lru(lru) => lru.pop_lru(),
+            Policy::TinyLfu(cache) => cache
+                .window
+                .pop_lru()
+                .or_else(|| cache.probation.pop_lru())
+                .or_else(|| cache.protected.pop_lru()),
+        };
+        popped.map(|(_, v, _)| v)
+    }
+
+    pub fn put(&mut self, k: K, v: V, size: usize) {
+        let charge = self.charge(size);
+        if let Some(pinned) = self.pinned.get_mut(&k) {
+            self.pinned_charge = self.pinned_charge - pinned.1 + charge;
+            *pinned = (v, charge);
+            self.evict();
+            return;
+        }
+        self.remove(&k);
+        match &mut self.policy {
+            Policy::Lru(lru) => lru.push(k, v, charge),
+            Policy::TinyLfu(cache) => cache.window.push(k, v, charge),
+        }
+        self.evict();
+    }
+
+    /// Remove the value from the policy, returning it with its charge.
+    fn remove(&mut self, k: &K) -> Option<(V, usize)> {
+        match &mut self.policy {
+            Policy::Lru(lru) => lru.pop(k),
+            Policy::TinyLfu(cache) => cache
+                .window
+                .pop(k)
+                .or_else(|| cache.probation.pop(k))
+                .or_else(|| cache.protected.pop(k)),
+        }
+    }
+
+    /// Keep the value under "k" until it's unpinned. Returns false if it's not cached.
+    pub fn pin(&mut self, k: &K) -> bool {
+        if self.pinned.contains_key(k) {
+            return true;
+        }
+        match self.remove(k) {
+            Some((v, charge)) => {
+                self.pinned.insert(k.clone(), (v, charge));
+                self.pinned_charge += charge;
+                self.evict();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drop the pinned values whose keys match "func".
+    pub fn unpin_where<F>(&mut self, func: F)
+    where
+        F: Fn(&K) -> bool,
+    {
+        let pinned_charge = &mut self.pinned_charge;
+        self.pinned.retain(|k, (_, charge)| {
+            if func(k) {
+                *pinned_charge -= *charge;
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    /// Account for "size" more bytes of the value under "k", e.g. once it's computed.
+    pub fn add_size(&mut self, k: &K, size: usize) {
+        if self.count_blocks {
+            return;
+        }
+        if let Some((_, charge)) = self.pinned.get_mut(k) {
+            *charge += size;
+            self.pinned_charge += size;
+            self.evict();
+            return;
+        }
+        match &mut self.policy {
+            Policy::Lru(lru) => {
+                lru.add_charge(k, size);
+            }
+            Policy::TinyLfu(cache) => {
+                if !cache.window.add_charge(k, size) && !cache.probation.add_charge(k, size) {
+                    cache.protected.add_charge(k, size);
+                }
+            }
+        }
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        let capacity = match self.unpinned_capacity() {
+            Some(capacity) => capacity,
+            None => return,
+        };
+        match &mut self.policy {
+            Policy::Lru(lru) => {
+                while lru.charge > capacity && lru.len() > 1 {
+                    lru.pop_lru();
+                }
+            }
+            Policy::TinyLfu(cache) => {
+                let main_capacity = main_capacity(capacity);
+                let window_capacity = capacity - main_capacity;
+                while cache.window.charge > window_capacity && cache.window.len() > 1 {
+                    if let Some((k, v, charge)) = cache.window.pop_lru() {
+                        cache.admit(k, v, charge, main_capacity);
+                    }
+                }
+                while cache.main_charge() > main_capacity {
+                    if cache.probation.pop_lru().is_none() && cache.protected.pop_lru().is_none() {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The capacity of the main cache of TinyLfu, the rest (about 1%) is the window's.
+fn main_capacity(capacity: usize) -> usize {
+    capacity - (capacity / 100).max(1).min(capacity)
+}
@@ -0,0 +1,210 @@
+//! A cache with a slot for every chunk of the table, so that cache hits take no locks.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use bytes::Bytes;
+use crossbeam_epoch::{self as epoch, Atomic, Owned, Shared};
+use parking_lot::Mutex;
+
+use super::concurrent_page_cache::ConcurrentPageCache;
+use super::options::ReadCache;
+use super::page_cache::{ChunkHandle, MemoryUsage};
+use super::sized_cache::CacheSize;
+use super::Result;
+
+struct Slot {
+    value: Atomic<Bytes>,
+    // The charge of the value, 0 if there is none.
+    charge: AtomicUsize,
+    // Set when the value is used, cleared when the clock hand passes by.
+    referenced: AtomicBool,
+    pinned: AtomicBool,
+    // Held while the value is computed or evicted, so that it's computed once.
+    fill: Mutex<()>,
+}
+
+impl Slot {
+    fn new() -> Self {
+        Self {
+            value: Atomic::null(),
+            charge: AtomicUsize::new(0),
+            referenced: AtomicBool::new(false),
+            pinned: AtomicBool::new(false),
+            fill: Mutex::new(()),
+        }
+    }
+
+    fn get(&self) -> Option<Bytes> {
+        let guard = epoch::pin();
+        let value = self.value.load(Ordering::Acquire, &guard);
+        // Evicted values are only destroyed once no thread that loaded them is pinned.
+        let value = unsafe { value.as_ref() }?.clone();
+        self.referenced.store(true, Ordering::Relaxed);
+        Some(value)
+    }
+
+    /// The size of the value, without counting it as used.
+    fn size(&self) -> usize {
+        let guard = epoch::pin();
+        let value = self.value.load(Ordering::Acquire, &guard);
+        unsafe { value.as_ref() }.map(Bytes::len).unwrap_or(0)
+    }
+
+    /// Store the value. Must be called with "fill" held, on an empty slot.
+    fn set(&self, value: Bytes, charge: usize) {
+        self.charge.store(charge, Ordering::Relaxed);
+        self.referenced.store(false, Ordering::Relaxed);
+        self.value.store(Owned::new(value), Ordering::Release);
+    }
+
+    /// Evict the value, and return its charge. Must be called with "fill" held.
+    fn take(&self) -> usize {
+        let guard = epoch::pin();
+        let value = self.value.swap(Shared::null(), Ordering::AcqRel, &guard);
+        if value.is_null() {
+            return 0;
+        }
+        unsafe { guard.defer_destroy(value) };
+        self.charge.swap(0, Ordering::Relaxed)
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        // No other thread can load the value anymore.
+        unsafe {
+            let value = self.value.load(Ordering::Relaxed, epoch::unprotected());
+            if !value.is_null() {
+                drop(value.into_owned());
+            }
+        }
+    }
+}
+
+/// A cache that wraps another one, and keeps its results in a slot per chunk.
+///
+/// The chunks are looked up by offset in the offsets from the index, and loaded from
+/// their slots without locks. A miss locks the slot while the chunk is computed, so
+/// every chunk is computed once. Chunks are evicted with the CLOCK algorithm, giving the
+/// chunks used since the hand passed by last another round.
+pub struct SlotCache<PC> {
+    inner: PC,
+    // The offsets of the chunks in increasing order, and their slots in the same order.
+    offsets: Vec<u64>,
+    slots: Vec<Slot>,
+    // In blocks or bytes. None if the cache is unbounded.
+    capacity: Option<usize>,
+    count_blocks: bool,
+    charge: AtomicUsize,
+    // The position of the clock hand, only moved on misses.
+    hand: Mutex<usize>,
+}
+
+impl<PC> SlotCache<PC> {
+    pub fn new(inner: PC, offsets: Vec<u64>, cache: ReadCache) -> Self {
+        let (capacity, count_blocks) = match cache {
+            ReadCache::Blocks(blocks) => (Some(blocks), true),
+            ReadCache::Bytes(bytes) => (Some(bytes), false),
+            ReadCache::Unbounded => (None, true),
+        };
+        Self {
+            inner,
+            slots: offsets.iter().map(|_| Slot::new()).collect(),
+            offsets,
+            capacity,
+            count_blocks,
+            charge: AtomicUsize::new(0),
+            hand: Mutex::new(0),
+        }
+    }
+
+    fn slot(&self, offset: u64) -> Option<(usize, &Slot)> {
+        let index = self.offsets.binary_search(&offset).ok()?;
+        Some((index, &self.slots[index]))
+    }
+
+    /// Get the chunk from the slot, or compute it with "func" and cache it.
+    fn get_or_fill<F>(&self, index: usize, slot: &Slot, func: F) -> Result<Bytes>
+    where
+        F: FnOnce() -> Result<Bytes>,
+    {
+        if let Some(value) = slot.get() {
+            return Ok(value);
+        }
+        let _fill = slot.fill.lock();
+        // Another thread might have filled it while this one waited.
+        if let Some(value) = slot.get() {
+            return Ok(value);
+        }
+        let value = func()?;
+        let charge = if self.count_blocks {
+            1
+        } else {
+            value.cache_size()
+        };
+        self.charge.fetch_add(charge, Ordering::AcqRel);
+        slot.set(value.clone(), charge);
+        self.evict(index);
+        Ok(value)
+    }
+
+    /// Evict chunks until the cache is within its capacity, except the one at "keep"
+    /// and the pinned ones.
+    fn evict(&self, keep: usize) {
+        let capacity = match self.capacity {
+            Some(capacity) => capacity,
+            None => return,
+        };
+        let mut hand = self.hand.lock();
+        // In 2 rounds the hand clears all the references, and evicts what it can.
+        for _ in 0..self.slots.len() * 2 {
+            if self.charge.load(Ordering::Acquire) <= capacity {
+                break;
+            }
+            let index = *hand;
+            *hand = (index + 1) % self.slots.len();
+            let slot = &self.slots[index];
+            if index == keep || slot.pinned.load(Ordering::Relaxed) {
+                continue;
+            }
+            if slot.referenced.swap(false, Ordering::Relaxed) {
+                continue;
+            }
+            // A slot that is being filled is empty.
+            if let Some(_fill) = slot.fill.try_lock() {
+                let charge = slot.take();
+                self.charge.fetch_sub(charge, Ordering::AcqRel);
+            }
+        }
+    }
+}
+
+impl<PC: ConcurrentPageCache> ConcurrentPageCache for SlotCache<PC> {
+    fn get_chunk(&self, chunk: ChunkHandle) -> Result<Bytes> {
+        match self.slot(chunk.offset) {
+            Some((index, slot)) => self.get_or_fill(index, slot, || self.inner.get_chunk(chunk)),
+            None => self.inner.get_chunk(chunk),
+        }
+    }
+
+    fn get_chunk_no_fill(&self, chunk: ChunkHandle) -> Result<Bytes> {
+        match self.slot(chunk.offset).and_then(|(_, slot)| slot.get()) {
+            Some(value) => Ok(value),
+            None => self.inner.get_chunk_no_fill(chunk),
+        }
+    }
+
+    fn pin_chunk(&self, chunk: ChunkHandle) -> Result<()> {
+        if let Some((_, slot)) = self.slot(chunk.offset) {
+            slot.pinned.store(true, Ordering::Relaxed);
+        }
+        self.get_chunk(chunk).map(|_| ())
+    }
+
+    fn memory_usage(&self, usage: &mut MemoryUsage) {
+        let slots = self.slots.len() * std::mem::size_of::<Slot>()
+            + self.offsets.len() * std::mem::size_of::<u64>();
+        usage.slot_cache += slots + self.slots.iter().map(Slot::size).sum::<usize>();
+        self.inner.memory_usage(usage);
+    }
+}
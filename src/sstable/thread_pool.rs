@@ -0,0 +1,52 @@
+//! The threads that read chunks ahead of scans, see `GetOptions::prefetch`.
+
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Arc;
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Threads running jobs off a shared queue. They live as long as the process.
+pub struct ThreadPool {
+    jobs: Mutex<Sender<Job>>,
+}
+
+impl ThreadPool {
+    pub fn new(threads: usize) -> Self {
+        let (jobs, queue) = channel::<Job>();
+        let queue = Arc::new(Mutex::new(queue));
+        for i in 0..threads {
+            let queue = queue.clone();
+            std::thread::Builder::new()
+                .name(format!("sstb-prefetch-{}", i))
+                .spawn(move || loop {
+                    let job = queue.lock().recv();
+                    match job {
+                        // A job that panics drops its result, the thread keeps going.
+                        Ok(job) => drop(catch_unwind(AssertUnwindSafe(job))),
+                        Err(_) => return,
+                    }
+                })
+                .expect("can't spawn prefetch threads");
+        }
+        Self {
+            jobs: Mutex::new(jobs),
+        }
+    }
+
+    /// Run "job" on one of the threads.
+    pub fn execute<F: FnOnce() + Send + 'static>(&self, job: F) {
+        // The threads never exit while the sender is alive, so the send can't fail.
+        let _ = self.jobs.lock().send(Box::new(job));
+    }
+}
+
+/// The pool shared by all readers, with a thread per CPU. It's started by the first scan
+/// that prefetches.
+pub fn prefetch_pool() -> &'static ThreadPool {
+    static POOL: Lazy<ThreadPool> = Lazy::new(|| ThreadPool::new(num_cpus::get()));
+    &POOL
+}
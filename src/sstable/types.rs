@@ -1,4 +1,4 @@
-pub const VERSION_30: Version = Version { major: 3, minor: 0 };
+pub const VERSION_32: Version = Version { major: 3, minor: 2 };
 
 use serde::{Deserialize, Serialize};
 
@@ -9,12 +9,19 @@ pub struct Version {
     minor: u16,
 }
 
+/// Identifies a custom codec in the sstable metadata, see `Codecs`.
+pub type CodecId = u32;
+
 /// Compression options for sstables.
 #[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
 pub enum Compression {
     None,
     Zlib,
     Snappy,
+    /// A custom codec registered in `Codecs` with this id.
+    ///
+    /// The same codec has to be registered when reading the sstable.
+    Custom(CodecId),
 }
 
 impl Default for Compression {
@@ -22,3 +29,42 @@ impl Default for Compression {
         Self::None
     }
 }
+
+/// Extracts the prefix of a key, that is inserted into the bloom filters next to the key.
+///
+/// This lets prefix scans skip tables, or chunks, that have no keys with the prefix.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum PrefixExtractor {
+    /// The first N bytes of the key. Shorter keys have no prefix.
+    FixedLength(usize),
+    /// The key up to and including the first occurrence of the byte. Keys without it
+    /// have no prefix.
+    Delimiter(u8),
+}
+
+impl PrefixExtractor {
+    /// The prefix of "key", if it has one.
+    pub fn extract<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]> {
+        match *self {
+            Self::FixedLength(length) => key.get(..length),
+            Self::Delimiter(delimiter) => key
+                .iter()
+                .position(|&b| b == delimiter)
+                .map(|pos| &key[..=pos]),
+        }
+    }
+}
+
+/// The format of the bloom filters, see `WriteOptions::filter_type`.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq)]
+pub enum FilterType {
+    /// The standard bloom filter.
+    #[default]
+    Bloom,
+    /// A bloom filter split into cache-line-sized blocks. It's faster to check than
+    /// `Bloom`, but has a bit more false positives for the same size.
+    BlockedBloom,
+    /// An xor filter. It's smaller than a bloom filter with the same false positive rate,
+    /// and about as fast as `BlockedBloom`, but is slower to build.
+    Xor,
+}
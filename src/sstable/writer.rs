@@ -3,7 +3,6 @@
 //! Look at the documentation for available writers for usage examples.
 
 use std::convert::TryFrom;
-use std::fs::File;
 use std::io::BufWriter;
 use std::io::{Seek, SeekFrom, Write};
 use std::path::Path;
@@ -12,12 +11,78 @@ use bincode;
 use bloomfilter::Bloom;
 
 use super::compress_ctx_writer::*;
-use super::compression;
+use super::compression::{self, Codecs};
+use super::encryption::{EncryptingWriter, TableCipher};
+use super::env::{MemFileData, MemWritableFile, WritableFile};
+use super::filter;
 use super::ondisk_format::*;
 use super::options::*;
 use super::poswriter::PosWriter;
 use super::result::Result;
 use super::types::*;
+use super::Error;
+
+type FileWriter = PosWriter<BufWriter<Box<dyn WritableFile>>>;
+
+/// Make a writer that compresses everything written to "writer" with "compression".
+fn compression_writer<W: Write + 'static>(
+    writer: W,
+    compression: Compression,
+    codecs: &Codecs,
+) -> Result<Box<dyn CompressionContextWriter<W>>> {
+    Ok(match compression {
+        Compression::None => Box::new(UncompressedWriter::new(writer)),
+        Compression::Zlib => Box::new(CompressionContextWriterImpl::new(
+            writer,
+            compression::ZlibCompressorFactory::new(None),
+        )),
+        Compression::Snappy => Box::new(CompressionContextWriterImpl::new(
+            writer,
+            compression::SnappyCompressorFactory::new(),
+        )),
+        Compression::Custom(id) => Box::new(CodecWriter::new(writer, codecs.get(id)?)),
+    })
+}
+
+/// Make a writer that compresses, and if "cipher" is set, encrypts every chunk
+/// written to "writer".
+fn chunk_writer(
+    writer: FileWriter,
+    compression: Compression,
+    codecs: &Codecs,
+    cipher: Option<&TableCipher>,
+) -> Result<Box<dyn CompressionContextWriter<FileWriter>>> {
+    let cipher = match cipher {
+        Some(cipher) => cipher.clone(),
+        None => return compression_writer(writer, compression, codecs),
+    };
+    let codecs = codecs.clone();
+    let base_offset = writer.current_offset();
+    Ok(Box::new(EncryptingWriter::new(
+        writer,
+        base_offset,
+        cipher,
+        Box::new(move |buf| compression_writer(buf, compression, &codecs)),
+    )?))
+}
+
+/// Make the bloom filter of the keys with "key_hashes", sized as "config" says.
+fn make_bloom(config: BloomConfig, key_hashes: &[u64]) -> Bloom<u64> {
+    // Empty tables still get a tiny filter, so that there's nothing special to read.
+    let items_count = std::cmp::max(key_hashes.len(), 1);
+    // the size is approximate anyway.
+    #[allow(
+        clippy::cast_precision_loss,
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss
+    )]
+    let bitmap_bytes = (items_count as f64 * filter::bits_per_key(config) / 8.0).ceil() as usize;
+    let mut bloom = Bloom::new(std::cmp::max(bitmap_bytes, 1), items_count);
+    for hash in key_hashes {
+        bloom.set(hash);
+    }
+    bloom
+}
 
 /// Represents an SSTable writer.
 pub trait RawSSTableWriter {
@@ -30,7 +95,7 @@ pub trait RawSSTableWriter {
     fn close(self) -> Result<()>;
 }
 
-/// SSTableWriterV2 writes SSTables to disk.
+/// SSTableWriterV2 writes SSTables to disk, or to the `Env` in `WriteOptions::env`.
 ///
 /// ```
 /// use sstb::sstable::{SSTableWriterV2, RawSSTableWriter};
@@ -42,13 +107,36 @@ pub trait RawSSTableWriter {
 /// writer.finish().unwrap();
 /// ```
 pub struct SSTableWriterV2 {
-    file: PosWriter<Box<dyn CompressionContextWriter<PosWriter<BufWriter<File>>>>>,
-    meta: MetaV3_0,
+    file: PosWriter<Box<dyn CompressionContextWriter<FileWriter>>>,
+    meta: MetaV3_2,
     meta_start: u64,
     data_start: u64,
     flush_every: usize,
-    sparse_index: Vec<(Vec<u8>, u64)>,
-    bloom: Bloom<[u8]>,
+    // The first key of every chunk, and the chunk's offset and uncompressed length.
+    // The length of the last chunk is filled in when it's flushed.
+    sparse_index: Vec<(Vec<u8>, u64, u64)>,
+    // Unset if the table has no bloom filters.
+    write_bloom: bool,
+    bloom: BloomConfig,
+    filter_type: FilterType,
+    // Hashes of all the keys, to build the bloom filter once their number is known.
+    // With filters per chunk, only the hashes of the current chunk.
+    key_hashes: Vec<u64>,
+    // Set if filters are per chunk. They are written out in the end.
+    chunk_filters: Option<Vec<Vec<u8>>>,
+    // Set if there's a range filter, with the prefix hashes of all the keys.
+    range_hashes: Option<Vec<u64>>,
+    last_key: Vec<u8>,
+    value_compression: Option<ValueCompression>,
+    // Reused between calls to "set" to compress values into.
+    value_buf: Vec<u8>,
+    codecs: Codecs,
+    cipher: Option<TableCipher>,
+    prefix_extractor: Option<PrefixExtractor>,
+    // The last prefix put into the bloom filter, as keys sharing it come in a row.
+    last_prefix: Option<Vec<u8>>,
+    // Set if the sstable is written to memory.
+    buffer: Option<MemFileData>,
 }
 
 impl SSTableWriterV2 {
@@ -58,80 +146,249 @@ impl SSTableWriterV2 {
     }
     /// Make a new SSTable writer with explicit options.
     pub fn new_with_options<P: AsRef<Path>>(path: P, options: &WriteOptions) -> Result<Self> {
-        let file = File::create(path)?;
+        // Fail before the file is created.
+        options.bloom.validate()?;
+        let file = options.env.create(path.as_ref())?;
+        Self::with_file(file, options)
+    }
+
+    /// Make a new SSTable writer that writes to memory, "env" in the options is ignored.
+    ///
+    /// Get the sstable with `finish_to_vec`, and read it with e.g. `SSTableReader::from_bytes`.
+    pub fn new_in_memory(options: &WriteOptions) -> Result<Self> {
+        options.bloom.validate()?;
+        let buffer = MemFileData::default();
+        let file = Box::new(MemWritableFile::new(buffer.clone()));
+        let mut writer = Self::with_file(file, options)?;
+        writer.buffer = Some(buffer);
+        Ok(writer)
+    }
+
+    fn with_file(file: Box<dyn WritableFile>, options: &WriteOptions) -> Result<Self> {
         let mut writer = PosWriter::new(BufWriter::new(file), 0);
         writer.write_all(MAGIC)?;
 
-        bincode::serialize_into(&mut writer, &VERSION_30)?;
+        bincode::serialize_into(&mut writer, &VERSION_32)?;
 
         let meta_start = writer.current_offset() as u64;
 
-        let mut meta = MetaV3_0::default();
+        let mut meta = MetaV3_2::default();
         meta.compression = options.compression;
+        meta.index_compression = options.index_compression.unwrap_or(options.compression);
+        meta.bloom_compression = options.bloom_compression.unwrap_or(options.compression);
+        meta.value_compression = options
+            .value_compression
+            .map(|v| v.compression)
+            .unwrap_or(Compression::None);
+        let cipher = match &options.encryption {
+            Some(encryption) => {
+                let (cipher, encryption_meta) = TableCipher::generate(encryption)?;
+                meta.encryption = Some(encryption_meta);
+                Some(cipher)
+            }
+            None => None,
+        };
+
+        meta.prefix_extractor = options.prefix_extractor;
+        meta.filter_type = options.filter_type;
 
         bincode::serialize_into(&mut writer, &meta)?;
 
         let data_start = writer.current_offset() as u64;
 
-        let file: Box<dyn CompressionContextWriter<PosWriter<BufWriter<File>>>> =
-            match options.compression {
-                Compression::None => Box::new(UncompressedWriter::new(writer)),
-                Compression::Zlib => Box::new(CompressionContextWriterImpl::new(
-                    writer,
-                    compression::ZlibCompressorFactory::new(None),
-                )),
-                Compression::Snappy => Box::new(CompressionContextWriterImpl::new(
-                    writer,
-                    compression::SnappyCompressorFactory::new(),
-                )),
-            };
+        let file = chunk_writer(
+            writer,
+            options.compression,
+            &options.codecs,
+            cipher.as_ref(),
+        )?;
 
         Ok(Self {
-            // TODO: this cast is safe, however concerning...
-            // maybe PosWriter should be u64 instead of usize?
-            file: PosWriter::new(file, data_start),
+            // The offset here counts the uncompressed bytes of the current chunk,
+            // it's reset to 0 every time a new chunk starts.
+            file: PosWriter::new(file, 0),
             meta,
             meta_start,
             data_start,
             flush_every: options.flush_every,
             sparse_index: Vec::new(),
-            bloom: Bloom::new(
-                options.bloom.bitmap_size as usize,
-                options.bloom.items_count,
-            ),
+            write_bloom: options.write_bloom,
+            bloom: options.bloom,
+            filter_type: options.filter_type,
+            key_hashes: Vec::new(),
+            chunk_filters: if options.write_bloom && options.bloom_per_chunk {
+                Some(Vec::new())
+            } else {
+                None
+            },
+            range_hashes: if options.range_filter {
+                Some(Vec::new())
+            } else {
+                None
+            },
+            last_key: Vec::new(),
+            value_compression: options.value_compression,
+            value_buf: Vec::new(),
+            codecs: options.codecs.clone(),
+            cipher,
+            prefix_extractor: options.prefix_extractor,
+            last_prefix: None,
+            buffer: None,
         })
     }
+
+    /// Compress the value into "value_buf" if it's configured, and if compression
+    /// makes it any smaller.
+    ///
+    /// Returns true if the value was compressed.
+    fn compress_value(&mut self, value: &[u8]) -> Result<bool> {
+        let config = match self.value_compression {
+            Some(config) if value.len() >= config.min_length => config,
+            _ => return Ok(false),
+        };
+        let uncompressed_length =
+            ValueLength::try_from(value.len()).map_err(|_| Error::ValueTooLong(value.len()))?;
+        self.value_buf.clear();
+        bincode::serialize_into(&mut self.value_buf, &uncompressed_length)?;
+        compression::compress_into(config.compression, &self.codecs, value, &mut self.value_buf)?;
+        let compressed = self.value_buf.len() < value.len();
+        if compressed {
+            self.meta.max_value_len = self.meta.max_value_len.max(u64::from(uncompressed_length));
+        }
+        Ok(compressed)
+    }
+    /// Build the filter of the chunk that's done, if filters are per chunk.
+    fn finish_chunk_filter(&mut self) -> Result<()> {
+        if let Some(filters) = self.chunk_filters.as_mut() {
+            filters.push(filter::build(
+                self.filter_type,
+                self.bloom,
+                &self.key_hashes,
+            )?);
+            self.key_hashes.clear();
+            // The next chunk needs the prefix in its own filter.
+            self.last_prefix = None;
+        }
+        Ok(())
+    }
+    /// Finish the sstable like `finish`, and return it, if the writer was made with
+    /// `new_in_memory`.
+    ///
+    /// Returns `Error::ProgrammingError` if the sstable is written to a file.
+    pub fn finish_to_vec(self) -> Result<Vec<u8>> {
+        let buffer = self.buffer.clone().ok_or(Error::ProgrammingError(
+            "the sstable is not written to memory",
+        ))?;
+        self.finish()?;
+        let mut buffer = buffer.lock();
+        Ok(std::mem::take(&mut *buffer))
+    }
+
     /// Write all the metadata to the sstable, and flush it.
-    pub fn finish(self) -> Result<()> {
+    pub fn finish(mut self) -> Result<()> {
+        if self.meta.items > 0 {
+            self.finish_chunk_filter()?;
+        }
         match self {
             SSTableWriterV2 {
                 file,
                 mut meta,
                 meta_start,
                 data_start,
-                sparse_index,
+                mut sparse_index,
+                write_bloom,
                 bloom,
+                filter_type,
+                key_hashes,
+                chunk_filters,
+                range_hashes,
+                codecs,
+                cipher,
                 ..
             } => {
-                let mut writer = file.into_inner();
-                let index_start = self.data_start + writer.reset_compression_context()? as u64;
-                for (key, offset) in sparse_index.into_iter() {
-                    KVOffset::new(key.len(), offset)?.serialize_into(&mut writer)?;
+                let last_chunk_length = file.current_offset();
+                if let Some(last) = sparse_index.last_mut() {
+                    last.2 = last_chunk_length;
+                }
+                meta.max_chunk_len = meta.max_chunk_len.max(last_chunk_length);
+
+                // Every section has its own compression, so the compressor is replaced
+                // after each one, and the offsets are taken from the file itself.
+                let writer = file.into_inner().into_inner()?;
+                let filter_start = writer.current_offset();
+                // Filters are not compressed, as bitmaps don't compress anyway.
+                // Every filter is a chunk of its own, so they can be read and decrypted
+                // separately.
+                let mut writer = chunk_writer(writer, Compression::None, &codecs, cipher.as_ref())?;
+                let mut filter_handles = Vec::new();
+                let mut filter_offset = 0;
+                for filter in chunk_filters.iter().flatten() {
+                    writer.write_all(filter)?;
+                    let next_offset = writer.reset_compression_context()? as u64;
+                    filter_handles
+                        .push((filter_start + filter_offset, next_offset - filter_offset));
+                    filter_offset = next_offset;
+                }
+
+                let writer = writer.into_inner()?;
+                let index_start = writer.current_offset();
+                let mut writer = PosWriter::new(
+                    chunk_writer(writer, meta.index_compression, &codecs, cipher.as_ref())?,
+                    0,
+                );
+                for (i, (key, offset, uncompressed_length)) in sparse_index.into_iter().enumerate()
+                {
+                    let mut kvoffset = KVOffset::new(key.len(), offset, uncompressed_length)?;
+                    if let Some(&(filter_offset, filter_length)) = filter_handles.get(i) {
+                        kvoffset.filter_offset = filter_offset;
+                        kvoffset.filter_length = filter_length;
+                    }
+                    kvoffset.serialize_into(&mut writer)?;
                     writer.write_all(&key)?;
                 }
-                let bloom_start = self.data_start + writer.reset_compression_context()? as u64;
-                writer.write_all(&bloom.bitmap())?;
-                let end = self.data_start + writer.reset_compression_context()? as u64;
+                meta.index_uncompressed_len = writer.current_offset();
+
+                let writer = writer.into_inner().into_inner()?;
+                let bloom_start = writer.current_offset();
+                let mut writer =
+                    chunk_writer(writer, meta.bloom_compression, &codecs, cipher.as_ref())?;
+                // With filters per chunk, or without filters, the bloom section is left empty.
+                match (&chunk_filters, filter_type) {
+                    _ if !write_bloom => {}
+                    (Some(_), _) => {}
+                    (None, FilterType::Bloom) => {
+                        let bloom = make_bloom(bloom, &key_hashes);
+                        writer.write_all(&bloom.bitmap())?;
+                        meta.bloom.bitmap_bytes = u32::try_from(bloom.number_of_bits() / 8)?;
+                        meta.bloom.k_num = bloom.number_of_hash_functions();
+                        meta.bloom.sip_keys = bloom.sip_keys();
+                    }
+                    (None, filter_type) => {
+                        let filter = filter::build(filter_type, bloom, &key_hashes)?;
+                        writer.write_all(&filter)?;
+                        meta.bloom.bitmap_bytes = u32::try_from(filter.len())?;
+                    }
+                }
+
+                let writer = writer.into_inner()?;
+                let range_filter_start = writer.current_offset();
+                let mut writer = chunk_writer(writer, Compression::None, &codecs, cipher.as_ref())?;
+                if let Some(range_hashes) = range_hashes {
+                    writer.write_all(&filter::build(filter_type, bloom, &range_hashes)?)?;
+                }
+
+                let writer = writer.into_inner()?;
+                let end = writer.current_offset();
                 meta.finished = true;
                 meta.index_len = bloom_start - index_start;
-                meta.data_len = index_start - data_start;
-                meta.bloom_len = end - bloom_start;
-                meta.bloom.bitmap_bytes = u32::try_from(bloom.number_of_bits() / 8)?;
-                meta.bloom.k_num = bloom.number_of_hash_functions();
-                meta.bloom.sip_keys = bloom.sip_keys();
-                let mut writer = writer.into_inner()?.into_inner();
+                meta.data_len = filter_start - data_start;
+                meta.filter_len = index_start - filter_start;
+                meta.bloom_len = range_filter_start - bloom_start;
+                meta.range_filter_len = end - range_filter_start;
+                let mut writer = writer.into_inner();
                 writer.seek(SeekFrom::Start(meta_start as u64))?;
                 bincode::serialize_into(&mut writer, &meta)?;
+                writer.flush()?;
                 Ok(())
             }
         }
@@ -147,18 +404,42 @@ impl RawSSTableWriter for SSTableWriterV2 {
         let approx_msg_len = key.len() + 5 + value.len();
 
         if self.meta.items == 0 {
-            self.sparse_index.push((key.to_owned(), self.data_start));
+            self.sparse_index.push((key.to_owned(), self.data_start, 0));
         } else {
             if self.file.current_offset() + approx_msg_len as u64 >= self.flush_every as u64 {
+                let chunk_length = self.file.current_offset();
+                if let Some(last) = self.sparse_index.last_mut() {
+                    last.2 = chunk_length;
+                }
+                self.meta.max_chunk_len = self.meta.max_chunk_len.max(chunk_length);
                 let total_offset =
                     self.data_start + self.file.get_mut().reset_compression_context()? as u64;
                 self.file.reset_offset(0);
+                self.finish_chunk_filter()?;
                 self.sparse_index
-                    .push((key.to_owned(), total_offset as u64));
+                    .push((key.to_owned(), total_offset as u64, 0));
             }
         }
-        self.bloom.set(key);
-        KVLength::new(key.len(), value.len())?.serialize_into(&mut self.file)?;
+        if self.write_bloom {
+            self.key_hashes.push(bloom_key_hash(key));
+            if let Some(prefix) = self.prefix_extractor.and_then(|e| e.extract(key)) {
+                if self.last_prefix.as_deref() != Some(prefix) {
+                    self.key_hashes.push(bloom_prefix_hash(prefix));
+                    self.last_prefix = Some(prefix.to_owned());
+                }
+            }
+        }
+        if let Some(range_hashes) = self.range_hashes.as_mut() {
+            filter::push_range_filter_items(&self.last_key, key, range_hashes);
+            self.last_key.clear();
+            self.last_key.extend_from_slice(key);
+        }
+        let (value, flags) = if self.compress_value(value)? {
+            (&self.value_buf as &[u8], FLAG_VALUE_COMPRESSED)
+        } else {
+            (value, 0)
+        };
+        KVLength::new(key.len(), value.len(), flags)?.serialize_into(&mut self.file)?;
         self.file.write_all(key)?;
         self.file.write_all(value)?;
         self.meta.items += 1;